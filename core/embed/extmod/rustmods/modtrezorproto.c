@@ -41,10 +41,30 @@ STATIC MP_DEFINE_CONST_FUN_OBJ_1(mod_trezorutils_protobuf_type_for_wire_obj,
 ///     buffer: bytes,
 ///     msg_type: Type[T],
 ///     enable_experimental: bool,
+///     *,
+///     preserve_unknown: bool = False,
+///     strict: bool = False,
+///     zero_copy: bool = False,
+///     limits: bool = False,
 /// ) -> T:
-///     """Decode data in the buffer into the specified message type."""
-STATIC MP_DEFINE_CONST_FUN_OBJ_3(mod_trezorutils_protobuf_decode_obj,
-                                 protobuf_decode);
+///     """Decode data in the buffer into the specified message type.
+///
+///     If `preserve_unknown` is set, fields missing from the message definition
+///     are kept on the message and emitted again by `encode`.
+///
+///     If `strict` is set, only the canonical encoding is accepted. Non-minimal
+///     varints, repeated occurrences of a singular field and trailing garbage
+///     after the last field raise `ValueError`.
+///
+///     If `zero_copy` is set, bytes fields are returned as read-only memoryviews
+///     into `buffer`. The buffer then must be kept alive and unmodified for as
+///     long as the message is in use.
+///
+///     If `limits` is set, the nesting depth, the number of repeated values
+///     and the allocated memory are bounded the same as for messages read
+///     from the wire. Exceeding them raises `ValueError`."""
+STATIC MP_DEFINE_CONST_FUN_OBJ_KW(mod_trezorutils_protobuf_decode_obj, 3,
+                                  protobuf_decode);
 
 /// def encoded_length(msg: MessageType) -> int:
 ///     """Calculate length of encoding of the specified message."""
@@ -57,6 +77,15 @@ STATIC MP_DEFINE_CONST_FUN_OBJ_1(mod_trezorutils_protobuf_encoded_length_obj,
 STATIC MP_DEFINE_CONST_FUN_OBJ_2(mod_trezorutils_protobuf_encode_obj,
                                  protobuf_encode);
 
+#if !PYOPT
+/// def dump(msg: MessageType, *, json: bool = False) -> str:
+///     """Render the message in the protobuf text format, or in JSON if `json`
+///     is set. Enums are shown by name, bytes as hex strings. Only available
+///     in debug builds."""
+STATIC MP_DEFINE_CONST_FUN_OBJ_KW(mod_trezorutils_protobuf_dump_obj, 1,
+                                  protobuf_debug_dump);
+#endif
+
 STATIC const mp_rom_map_elem_t mp_module_trezorproto_globals_table[] = {
     {MP_ROM_QSTR(MP_QSTR___name__), MP_ROM_QSTR(MP_QSTR_trezorproto)},
 
@@ -70,6 +99,9 @@ STATIC const mp_rom_map_elem_t mp_module_trezorproto_globals_table[] = {
      MP_ROM_PTR(&mod_trezorutils_protobuf_encoded_length_obj)},
     {MP_ROM_QSTR(MP_QSTR_encode),
      MP_ROM_PTR(&mod_trezorutils_protobuf_encode_obj)},
+#if !PYOPT
+    {MP_ROM_QSTR(MP_QSTR_dump), MP_ROM_PTR(&mod_trezorutils_protobuf_dump_obj)},
+#endif
 };
 
 STATIC MP_DEFINE_CONST_DICT(mp_module_trezorproto_globals,
@@ -48,6 +48,31 @@ static bool mpz_as_ll_checked(const mpz_t *i, long long *value) {
   return true;
 }
 
+static bool mpz_as_ull_checked(const mpz_t *i, unsigned long long *value) {
+  // Analogue of `mpz_as_uint_checked` from mpz.c
+
+  if (i->neg != 0) {
+    // Negative value.
+    *value = 0;
+    return false;
+  }
+
+  unsigned long long val = 0;
+  mpz_dig_t *d = i->dig + i->len;
+
+  while (d-- > i->dig) {
+    if (val > (~0ULL >> MPZ_DIG_SIZE)) {
+      // will overflow
+      *value = 0;
+      return false;
+    }
+    val = (val << MPZ_DIG_SIZE) | *d;
+  }
+
+  *value = val;
+  return true;
+}
+
 bool trezor_obj_get_ll_checked(mp_obj_t obj, long long *value) {
   if (mp_obj_is_small_int(obj)) {
     // Value is fitting in a small int range. Return it directly.
@@ -66,6 +91,26 @@ bool trezor_obj_get_ll_checked(mp_obj_t obj, long long *value) {
   }
 }
 
+bool trezor_obj_get_ull_checked(mp_obj_t obj, unsigned long long *value) {
+  if (mp_obj_is_small_int(obj)) {
+    // Value is fitting in a small int range. Return it directly, unless it is
+    // negative.
+    mp_int_t val = MP_OBJ_SMALL_INT_VALUE(obj);
+    *value = val < 0 ? 0 : val;
+    return val >= 0;
+
+  } else if (mp_obj_is_type(obj, &mp_type_int)) {
+    // Value is not fitting into small int range, but is an integer.
+    mp_obj_int_t *self = MP_OBJ_TO_PTR(obj);
+    // Try to get the unsigned long long value out of the MPZ struct.
+    return mpz_as_ull_checked(&self->mpz, value);
+  } else {
+    // Value is not integer.
+    *value = 0;
+    return false;
+  }
+}
+
 mp_obj_t trezor_obj_call_protected(void (*func)(void *), void *arg) {
   nlr_buf_t nlr;
   if (nlr_push(&nlr) == 0) {
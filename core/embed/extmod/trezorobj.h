@@ -101,6 +101,8 @@ static inline uint64_t trezor_obj_get_uint64(mp_const_obj_t obj) {
 
 bool trezor_obj_get_ll_checked(mp_obj_t obj, long long *value);
 
+bool trezor_obj_get_ull_checked(mp_obj_t obj, unsigned long long *value);
+
 mp_obj_t trezor_obj_call_protected(void (*func)(void *), void *arg);
 
 mp_obj_t trezor_obj_str_from_rom_text(const char *str);
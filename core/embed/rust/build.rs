@@ -8,6 +8,8 @@ fn main() {
     #[cfg(feature = "micropython")]
     generate_micropython_bindings();
     generate_trezorhal_bindings();
+    #[cfg(feature = "protobuf_types")]
+    generate_protobuf_types();
     #[cfg(feature = "test")]
     link_core_objects();
 }
@@ -140,6 +142,7 @@ fn generate_micropython_bindings() {
         .allowlist_function("mp_obj_new_int_from_ull")
         .allowlist_function("mp_obj_new_int_from_uint")
         .allowlist_function("mp_obj_new_bytes")
+        .allowlist_function("mp_obj_new_memoryview")
         .allowlist_function("mp_obj_new_str")
         .allowlist_function("mp_obj_new_tuple")
         .allowlist_function("mp_obj_get_int_maybe")
@@ -154,6 +157,8 @@ fn generate_micropython_bindings() {
         .allowlist_var("MP_BUFFER_WRITE")
         .allowlist_var("MP_BUFFER_RW")
         .allowlist_var("mp_type_str")
+        // qstr
+        .allowlist_function("qstr_data")
         // dict
         .allowlist_type("mp_obj_dict_t")
         .allowlist_function("mp_obj_new_dict")
@@ -237,6 +242,8 @@ fn generate_trezorhal_bindings() {
         .allowlist_var("secfalse")
         // flash
         .allowlist_function("flash_init")
+        .allowlist_function("flash_power_loss_after")
+        .allowlist_function("flash_power_loss")
         // storage
         .allowlist_var("EXTERNAL_SALT_SIZE")
         .allowlist_var("FLAG_PUBLIC")
@@ -252,12 +259,15 @@ fn generate_trezorhal_bindings() {
         .allowlist_function("storage_get_pin_rem")
         .allowlist_function("storage_change_pin")
         .allowlist_function("storage_ensure_not_wipe_code")
+        .allowlist_function("storage_has_wipe_code")
+        .allowlist_function("storage_change_wipe_code")
         .allowlist_function("storage_has")
         .allowlist_function("storage_get")
         .allowlist_function("storage_set")
         .allowlist_function("storage_delete")
         .allowlist_function("storage_set_counter")
         .allowlist_function("storage_next_counter")
+        .allowlist_function("storage_next_entry")
         // display
         .allowlist_function("display_init")
         .allowlist_function("display_offset")
@@ -297,6 +307,10 @@ fn generate_trezorhal_bindings() {
         .allowlist_function("random_uniform")
         // rgb led
         .allowlist_function("rgb_led_set_color")
+        // touch
+        .allowlist_function("touch_read")
+        // buttons
+        .allowlist_function("button_read")
         // time
         .allowlist_function("hal_delay")
         .allowlist_function("hal_ticks_ms")
@@ -319,6 +333,56 @@ fn generate_trezorhal_bindings() {
         .unwrap();
 }
 
+/// Messages that get a typed Rust struct generated, together with all
+/// messages they contain. See `src/protobuf/types.rs`.
+#[cfg(feature = "protobuf_types")]
+const PROTOBUF_TYPES: &[&str] = &[
+    "ButtonAck",
+    "ButtonRequest",
+    "Failure",
+    "PassphraseAck",
+    "PassphraseRequest",
+    "PinMatrixAck",
+    "PinMatrixRequest",
+    "PublicKey",
+    "Success",
+];
+
+/// Generates Rust structs for the messages in `PROTOBUF_TYPES` from the
+/// protobuf definitions blob.
+#[cfg(feature = "protobuf_types")]
+fn generate_protobuf_types() {
+    let out_path = env::var("OUT_DIR").unwrap();
+    let build_path = if is_firmware() {
+        "../../build/firmware"
+    } else {
+        "../../build/unix"
+    };
+    let read = |path: String| {
+        // Tell cargo to invalidate the built crate whenever the definitions change.
+        println!("cargo:rerun-if-changed={}", path);
+        std::fs::read(&path).unwrap_or_else(|err| panic!("Unable to read {}: {}", path, err))
+    };
+
+    let qstr_defs = read(format!("{}/genhdr/qstrdefs.generated.h", build_path));
+    let defs = protobuf_types::Defs::parse(
+        &String::from_utf8_lossy(&qstr_defs),
+        &read(format!("{}/rust/proto_names.data", build_path)),
+        &read(format!("{}/rust/proto_msgs.data", build_path)),
+        read(format!("{}/rust/proto_enums.data", build_path)),
+    );
+    std::fs::write(
+        PathBuf::from(out_path).join("protobuf_types.rs"),
+        defs.generate(PROTOBUF_TYPES),
+    )
+    .unwrap();
+}
+
+/// Reading of the protobuf definitions blob and generation of the Rust code.
+#[cfg(feature = "protobuf_types")]
+#[path = "codegen/protobuf_types.rs"]
+mod protobuf_types;
+
 fn is_firmware() -> bool {
     let target = env::var("TARGET").unwrap();
     target.starts_with("thumbv7")
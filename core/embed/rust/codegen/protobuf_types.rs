@@ -0,0 +1,647 @@
+//! Generation of typed Rust messages from the protobuf definitions blob, see
+//! `common/protob/pb2py` and `src/protobuf/defs.rs` for the format and
+//! `src/protobuf/types.rs` for the runtime side. Used by `build.rs`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write,
+};
+
+#[allow(dead_code)]
+#[path = "../src/protobuf/layout.rs"]
+mod layout;
+#[path = "qstrs.rs"]
+mod qstrs;
+
+use layout::{
+    FIELD_SIZE, FLAGS_MASK, FLAG_PACKED, FLAG_REPEATED, FLAG_REQUIRED, LOOKUP_SIZE, TYPE_BOOL,
+    TYPE_BYTES, TYPE_ENUM, TYPE_FIXED32, TYPE_FIXED64, TYPE_INT32, TYPE_INT64, TYPE_MAP, TYPE_MASK,
+    TYPE_MSG, TYPE_SFIXED32, TYPE_SFIXED64, TYPE_STRING, TYPE_SVARINT, TYPE_UVARINT,
+};
+use qstrs::parse_qstrs;
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "dyn", "else", "enum", "extern", "false",
+    "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "static", "struct", "trait", "true", "type", "unsafe", "use", "where", "while",
+    "abstract", "become", "box", "do", "final", "macro", "override", "priv", "try", "typeof",
+    "unsized", "virtual", "yield",
+];
+
+struct Field {
+    tag: u8,
+    flags: u8,
+    ftype: u8,
+    enum_or_msg_offset: u16,
+    name: String,
+    /// Index of the oneof group plus one, zero if not a member.
+    oneof: u8,
+}
+
+struct Message {
+    name: String,
+    fields: Vec<Field>,
+    oneofs: Vec<String>,
+    /// Default values rendered as Rust expressions, by field tag.
+    defaults: BTreeMap<u8, String>,
+}
+
+pub struct Defs {
+    messages: BTreeMap<u16, Message>,
+    enums: Vec<u8>,
+}
+
+impl Defs {
+    pub fn parse(qstr_defs: &str, names: &[u8], msgs: &[u8], enums: Vec<u8>) -> Self {
+        let qstrs = parse_qstrs(qstr_defs);
+        let qstr = |id: u16| qstrs[id as usize].to_string();
+        let read_u16 = |buf: &[u8], pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+
+        let mut msg_names = BTreeMap::new();
+        for entry in names.chunks(LOOKUP_SIZE) {
+            msg_names.insert(read_u16(entry, 2), qstr(read_u16(entry, 0)));
+        }
+
+        let mut messages = BTreeMap::new();
+        let mut pos = 0;
+        while pos < msgs.len() {
+            let offset = pos as u16;
+            let fields_count = msgs[pos] as usize;
+            let defaults_size = msgs[pos + 1] as usize;
+            pos += 4;
+            let fields: Vec<Field> = msgs[pos..pos + fields_count * FIELD_SIZE]
+                .chunks(FIELD_SIZE)
+                .map(|field| Field {
+                    tag: field[0],
+                    flags: field[1] & FLAGS_MASK,
+                    ftype: field[1] & TYPE_MASK,
+                    enum_or_msg_offset: read_u16(field, 2),
+                    name: qstr(read_u16(field, 4)),
+                    oneof: field[6],
+                })
+                .collect();
+            pos += fields_count * FIELD_SIZE;
+            let oneofs_count = msgs[pos] as usize;
+            let oneofs = (0..oneofs_count)
+                .map(|i| qstr(read_u16(msgs, pos + 1 + i * 2)))
+                .collect();
+            pos += 1 + oneofs_count * 2;
+            let defaults = parse_defaults(&fields, &msgs[pos..pos + defaults_size]);
+            pos += defaults_size;
+
+            let name = msg_names
+                .remove(&offset)
+                .unwrap_or_else(|| panic!("Message at {} has no name", offset));
+            let message = Message {
+                name,
+                fields,
+                oneofs,
+                defaults,
+            };
+            messages.insert(offset, message);
+        }
+        Self { messages, enums }
+    }
+
+    pub fn generate(&self, selected: &[&str]) -> String {
+        // Collect the selected messages and the messages they contain.
+        let mut pending: Vec<u16> = selected
+            .iter()
+            .map(|name| {
+                self.messages
+                    .iter()
+                    .find(|(_, msg)| msg.name == *name)
+                    .map(|(offset, _)| *offset)
+                    .unwrap_or_else(|| panic!("Unknown message {}", name))
+            })
+            .collect();
+        let mut included = BTreeSet::new();
+        while let Some(offset) = pending.pop() {
+            if included.insert(offset) {
+                let msg = &self.messages[&offset];
+                for field in &msg.fields {
+                    match field.ftype {
+                        TYPE_MSG => pending.push(field.enum_or_msg_offset),
+                        TYPE_MAP => {
+                            panic!("Map field {}.{} not supported", msg.name, field.name)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let mut generator = Generator {
+            defs: self,
+            lifetimes: BTreeMap::new(),
+            enum_names: BTreeMap::new(),
+            out: String::new(),
+        };
+        let mut ordered: Vec<u16> = included.into_iter().collect();
+        ordered.sort_by_key(|offset| &self.messages[offset].name);
+        for offset in &ordered {
+            generator.check_lifetime(*offset, &mut Vec::new());
+        }
+        for offset in &ordered {
+            generator.name_enums(*offset);
+        }
+        generator.write_enums();
+        for offset in &ordered {
+            generator.write_message(*offset);
+        }
+        generator.out
+    }
+}
+
+fn read_uvarint(buf: &[u8], pos: &mut usize) -> u64 {
+    let mut uint = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buf[*pos];
+        *pos += 1;
+        uint |= (byte as u64 & 0x7F) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break uint;
+        }
+    }
+}
+
+fn parse_defaults(fields: &[Field], buf: &[u8]) -> BTreeMap<u8, String> {
+    let mut defaults = BTreeMap::new();
+    let mut pos = 0;
+    while pos < buf.len() {
+        let tag = buf[pos];
+        pos += 1;
+        let field = fields.iter().find(|field| field.tag == tag).unwrap();
+        let mut fixed = |len: usize| {
+            let mut bytes = [0; 8];
+            bytes[..len].copy_from_slice(&buf[pos..pos + len]);
+            pos += len;
+            u64::from_le_bytes(bytes)
+        };
+        let value = match field.ftype {
+            TYPE_FIXED32 | TYPE_SFIXED32 => fixed(4),
+            TYPE_FIXED64 | TYPE_SFIXED64 => fixed(8),
+            TYPE_BYTES | TYPE_STRING => {
+                let len = read_uvarint(buf, &mut pos) as usize;
+                let value = &buf[pos..pos + len];
+                pos += len;
+                let expr = if field.ftype == TYPE_BYTES {
+                    format!("&{:?}", value)
+                } else {
+                    format!("{:?}", String::from_utf8(value.to_vec()).unwrap())
+                };
+                defaults.insert(tag, expr);
+                continue;
+            }
+            _ => read_uvarint(buf, &mut pos),
+        };
+        let expr = match field.ftype {
+            TYPE_SVARINT => format!("{}", ((value >> 1) as i64) ^ (-((value & 1) as i64))),
+            TYPE_BOOL => format!("{}", value != 0),
+            TYPE_SFIXED32 => format!("{}", value as u32 as i32),
+            TYPE_SFIXED64 | TYPE_INT64 => format!("{}", value as i64),
+            TYPE_INT32 => format!("{}", value as i64 as i32),
+            _ => format!("{}", value),
+        };
+        defaults.insert(tag, expr);
+    }
+    defaults
+}
+
+fn camel_case(name: &str) -> String {
+    let mut out = String::new();
+    let mut upper = true;
+    for c in name.chars() {
+        if c == '_' {
+            upper = true;
+        } else if upper {
+            out.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn field_name(name: &str) -> String {
+    if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+struct Generator<'d> {
+    defs: &'d Defs,
+    /// Whether the generated struct borrows from the decoded buffer, by
+    /// message offset.
+    lifetimes: BTreeMap<u16, bool>,
+    enum_names: BTreeMap<u16, String>,
+    out: String,
+}
+
+impl Generator<'_> {
+    fn check_lifetime(&mut self, offset: u16, stack: &mut Vec<u16>) -> bool {
+        if let Some(lifetime) = self.lifetimes.get(&offset) {
+            return *lifetime;
+        }
+        let msg = &self.defs.messages[&offset];
+        if stack.contains(&offset) {
+            panic!("Recursive message {} not supported", msg.name);
+        }
+        stack.push(offset);
+        let mut lifetime = false;
+        for field in &msg.fields {
+            lifetime |= match field.ftype {
+                TYPE_BYTES | TYPE_STRING => true,
+                TYPE_MSG => self.check_lifetime(field.enum_or_msg_offset, stack),
+                _ => false,
+            };
+        }
+        stack.pop();
+        self.lifetimes.insert(offset, lifetime);
+        lifetime
+    }
+
+    fn name_enums(&mut self, offset: u16) {
+        let msg = &self.defs.messages[&offset];
+        for field in msg.fields.iter().filter(|field| field.ftype == TYPE_ENUM) {
+            self.enum_names
+                .entry(field.enum_or_msg_offset)
+                .or_insert_with(|| camel_case(&msg.name) + &camel_case(&field.name));
+        }
+    }
+
+    fn write_enums(&mut self) {
+        let enums = &self.defs.enums;
+        for (offset, name) in &self.enum_names {
+            let offset = *offset as usize;
+            let count = enums[offset] as usize;
+            let values: Vec<String> = (0..count)
+                .map(|i| {
+                    let pos = offset + 1 + i * 2;
+                    u16::from_le_bytes([enums[pos], enums[pos + 1]]).to_string()
+                })
+                .collect();
+            writeln!(
+                self.out,
+                "protobuf_enum!({}, [{}]);",
+                name,
+                values.join(", ")
+            )
+            .unwrap();
+        }
+    }
+
+    fn type_name(&self, offset: u16) -> String {
+        let name = camel_case(&self.defs.messages[&offset].name);
+        if self.lifetimes[&offset] {
+            name + "<'a>"
+        } else {
+            name
+        }
+    }
+
+    /// Rust type of a single value of `field`.
+    fn value_type(&self, field: &Field) -> String {
+        match field.ftype {
+            TYPE_UVARINT | TYPE_FIXED64 => "u64".into(),
+            TYPE_SVARINT | TYPE_SFIXED64 | TYPE_INT64 => "i64".into(),
+            TYPE_BOOL => "bool".into(),
+            TYPE_BYTES => "&'a [u8]".into(),
+            TYPE_STRING => "&'a str".into(),
+            TYPE_ENUM => self.enum_names[&field.enum_or_msg_offset].clone(),
+            TYPE_MSG => self.type_name(field.enum_or_msg_offset),
+            TYPE_FIXED32 => "u32".into(),
+            TYPE_SFIXED32 | TYPE_INT32 => "i32".into(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Value of `field` used by `Default`, if it differs from the
+    /// `Default` of its type.
+    fn default_value(&self, msg: &Message, field: &Field) -> Option<String> {
+        if let Some(value) = msg.defaults.get(&field.tag) {
+            return match field.ftype {
+                TYPE_ENUM => Some(format!(
+                    "{}({})",
+                    self.enum_names[&field.enum_or_msg_offset], value
+                )),
+                // Same as `Default::default()`, leave it for `derive`.
+                _ if ["0", "false", "\"\"", "&[]"].contains(&value.as_str()) => None,
+                _ => Some(value.clone()),
+            };
+        }
+        if field.ftype == TYPE_ENUM && field.flags & FLAG_REQUIRED != 0 {
+            // Enums do not implement `Default`, take the first valid value.
+            let offset = field.enum_or_msg_offset as usize;
+            let enums = &self.defs.enums;
+            let value = u16::from_le_bytes([enums[offset + 1], enums[offset + 2]]);
+            return Some(format!(
+                "{}({})",
+                self.enum_names[&field.enum_or_msg_offset], value
+            ));
+        }
+        None
+    }
+
+    /// Statements writing the value `v` of `field` into `stream`.
+    fn encode_value(&self, field: &Field, stream: &str) -> String {
+        match field.ftype {
+            TYPE_UVARINT => format!("{}.write_uvarint(*v)?;", stream),
+            TYPE_SVARINT => format!("{}.write_uvarint(super::zigzag::to_unsigned(*v))?;", stream),
+            TYPE_BOOL => format!("{}.write_uvarint(*v as u64)?;", stream),
+            TYPE_BYTES => format!("write_buffer({}, v)?;", stream),
+            TYPE_STRING => format!("write_buffer({}, v.as_bytes())?;", stream),
+            TYPE_ENUM => format!("{}.write_uvarint(v.0 as u64)?;", stream),
+            TYPE_MSG => format!("write_message({}, v)?;", stream),
+            TYPE_FIXED32 => format!("{}.write_fixed32(*v)?;", stream),
+            TYPE_FIXED64 => format!("{}.write_fixed64(*v)?;", stream),
+            TYPE_SFIXED32 => format!("{}.write_fixed32(*v as u32)?;", stream),
+            TYPE_SFIXED64 => format!("{}.write_fixed64(*v as u64)?;", stream),
+            TYPE_INT32 => format!("{}.write_uvarint(*v as i64 as u64)?;", stream),
+            TYPE_INT64 => format!("{}.write_uvarint(*v as u64)?;", stream),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Statements writing the key and the value `v` of `field`.
+    fn encode_record(&self, field: &Field) -> String {
+        let prim_type = match field.ftype {
+            TYPE_UVARINT | TYPE_SVARINT | TYPE_BOOL | TYPE_ENUM | TYPE_INT32 | TYPE_INT64 => 0,
+            TYPE_FIXED64 | TYPE_SFIXED64 => 1,
+            TYPE_BYTES | TYPE_STRING | TYPE_MSG => 2,
+            TYPE_FIXED32 | TYPE_SFIXED32 => 5,
+            _ => unreachable!(),
+        };
+        let key = (field.tag as u64) << 3 | prim_type;
+        format!(
+            "stream.write_uvarint({})?; {}",
+            key,
+            self.encode_value(field, "stream")
+        )
+    }
+
+    /// Pattern of the decoded `Value` of `field` and the expression
+    /// converting it, or `None` for sub-messages.
+    fn decode_value(&self, field: &Field) -> Option<(&'static str, String)> {
+        Some(match field.ftype {
+            TYPE_UVARINT | TYPE_FIXED64 => ("UInt", "v".into()),
+            TYPE_SVARINT | TYPE_SFIXED64 | TYPE_INT64 => ("Int", "v".into()),
+            TYPE_BOOL => ("Bool", "v".into()),
+            TYPE_BYTES => ("Bytes", "v".into()),
+            TYPE_STRING => ("String", "v".into()),
+            TYPE_ENUM => (
+                "Enum",
+                format!("{}(v)", self.enum_names[&field.enum_or_msg_offset]),
+            ),
+            TYPE_MSG => return None,
+            TYPE_FIXED32 => ("UInt", "u32::try_from(v)?".into()),
+            TYPE_SFIXED32 | TYPE_INT32 => ("Int", "i32::try_from(v)?".into()),
+            _ => unreachable!(),
+        })
+    }
+
+    /// Statement assigning `value` into the struct member of `field`.
+    fn assign_value(&self, msg: &Message, field: &Field, value: &str) -> String {
+        let name = field_name(&field.name);
+        if field.oneof > 0 {
+            let group = &msg.oneofs[field.oneof as usize - 1];
+            format!(
+                "self.{} = Some({}{}::{}({}))",
+                field_name(group),
+                camel_case(&msg.name),
+                camel_case(group),
+                camel_case(&field.name),
+                value
+            )
+        } else if field.flags & FLAG_REPEATED != 0 {
+            format!("push(&mut self.{}, {})?", name, value)
+        } else if field.flags & FLAG_REQUIRED != 0 || msg.defaults.contains_key(&field.tag) {
+            format!("self.{} = {}", name, value)
+        } else {
+            format!("self.{} = Some({})", name, value)
+        }
+    }
+
+    fn write_message(&mut self, offset: u16) {
+        let msg = &self.defs.messages[&offset];
+        let name = camel_case(&msg.name);
+        let lifetime = if self.lifetimes[&offset] { "<'a>" } else { "" };
+        let mut out = String::new();
+
+        // Oneof groups.
+        for (i, group) in msg.oneofs.iter().enumerate() {
+            let members: Vec<&Field> = msg
+                .fields
+                .iter()
+                .filter(|f| f.oneof as usize == i + 1)
+                .collect();
+            let group_lifetime = members.iter().any(|f| self.value_type(f).contains("'a"));
+            writeln!(out, "#[derive(Clone, Debug, PartialEq)]").unwrap();
+            writeln!(
+                out,
+                "pub enum {}{}{} {{",
+                name,
+                camel_case(group),
+                if group_lifetime { "<'a>" } else { "" }
+            )
+            .unwrap();
+            for field in members {
+                writeln!(
+                    out,
+                    "{}({}),",
+                    camel_case(&field.name),
+                    self.value_type(field)
+                )
+                .unwrap();
+            }
+            writeln!(out, "}}\n").unwrap();
+        }
+
+        // Struct, singular and repeated fields first, then the oneof groups.
+        let mut members = Vec::new();
+        let mut defaults = Vec::new();
+        for field in msg.fields.iter().filter(|f| f.oneof == 0) {
+            let value_type = self.value_type(field);
+            let member_type = if field.flags & FLAG_REPEATED != 0 {
+                format!("Vec<{}, MAX_REPEATED>", value_type)
+            } else if field.flags & FLAG_REQUIRED != 0 || msg.defaults.contains_key(&field.tag) {
+                value_type
+            } else {
+                format!("Option<{}>", value_type)
+            };
+            members.push((field_name(&field.name), member_type));
+            defaults.push(self.default_value(msg, field));
+        }
+        for (i, group) in msg.oneofs.iter().enumerate() {
+            let group_lifetime = msg
+                .fields
+                .iter()
+                .filter(|f| f.oneof as usize == i + 1)
+                .any(|f| self.value_type(f).contains("'a"));
+            let group_type = format!(
+                "{}{}{}",
+                name,
+                camel_case(group),
+                if group_lifetime { "<'a>" } else { "" }
+            );
+            members.push((field_name(group), format!("Option<{}>", group_type)));
+            defaults.push(None);
+        }
+        let derive_default = defaults.iter().all(Option::is_none);
+        if derive_default {
+            writeln!(out, "#[derive(Clone, Debug, Default, PartialEq)]").unwrap();
+        } else {
+            writeln!(out, "#[derive(Clone, Debug, PartialEq)]").unwrap();
+        }
+        writeln!(out, "pub struct {}{} {{", name, lifetime).unwrap();
+        for (member, member_type) in &members {
+            writeln!(out, "pub {}: {},", member, member_type).unwrap();
+        }
+        writeln!(out, "}}\n").unwrap();
+
+        if !derive_default {
+            writeln!(out, "impl{} Default for {}{} {{", lifetime, name, lifetime).unwrap();
+            writeln!(out, "fn default() -> Self {{ Self {{").unwrap();
+            for ((member, _), default) in members.iter().zip(&defaults) {
+                let default = default.as_deref().unwrap_or("Default::default()");
+                writeln!(out, "{}: {},", member, default).unwrap();
+            }
+            writeln!(out, "}} }}\n}}\n").unwrap();
+        }
+
+        // Decoding.
+        writeln!(out, "impl<'a> Message<'a> for {}{} {{", name, lifetime).unwrap();
+        writeln!(out, "const DEF_OFFSET: u16 = {};\n", offset).unwrap();
+
+        let scalars: Vec<String> = msg
+            .fields
+            .iter()
+            .filter_map(|field| {
+                let (pattern, value) = self.decode_value(field)?;
+                let assign = self.assign_value(msg, field, &value);
+                Some(format!(
+                    "({}, Value::{}(v)) => {},",
+                    field.tag, pattern, assign
+                ))
+            })
+            .collect();
+        if scalars.is_empty() {
+            writeln!(
+                out,
+                "fn assign(&mut self, _tag: u8, _value: Value<'a>) -> Result<(), Error> {{ \
+                 Err(Error::TypeError) }}\n"
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                out,
+                "fn assign(&mut self, tag: u8, value: Value<'a>) -> Result<(), Error> {{ \
+                 match (tag, value) {{ {} _ => return Err(Error::TypeError), }} Ok(()) }}\n",
+                scalars.join(" ")
+            )
+            .unwrap();
+        }
+
+        let messages: Vec<String> = msg
+            .fields
+            .iter()
+            .filter(|field| field.ftype == TYPE_MSG)
+            .map(|field| {
+                let assign = self.assign_value(msg, field, "decode_message(stream, msg)?");
+                format!("{} => {},", field.tag, assign)
+            })
+            .collect();
+        if messages.is_empty() {
+            writeln!(
+                out,
+                "fn assign_message(&mut self, _tag: u8, _msg: &MsgDef, \
+                 _stream: &mut InputStream<'a>) -> Result<(), Error> {{ \
+                 Err(Error::TypeError) }}\n"
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                out,
+                "fn assign_message(&mut self, tag: u8, msg: &MsgDef, \
+                 stream: &mut InputStream<'a>) -> Result<(), Error> {{ \
+                 match tag {{ {} _ => return Err(Error::TypeError), }} Ok(()) }}\n",
+                messages.join(" ")
+            )
+            .unwrap();
+        }
+
+        // Encoding, in the order of the tags.
+        let mut records = Vec::new();
+        for field in &msg.fields {
+            let name = field_name(&field.name);
+            let record = if field.oneof > 0 {
+                let group = &msg.oneofs[field.oneof as usize - 1];
+                format!(
+                    "if let Some({}{}::{}(v)) = &self.{} {{ {} }}",
+                    camel_case(&msg.name),
+                    camel_case(group),
+                    camel_case(&field.name),
+                    field_name(group),
+                    self.encode_record(field)
+                )
+            } else if field.flags & FLAG_PACKED != 0 {
+                let key = (field.tag as u64) << 3 | 2;
+                format!(
+                    "if !self.{name}.is_empty() {{ \
+                     let counter = &mut CounterStream {{ len: 0 }}; \
+                     for v in &self.{name} {{ {count} }} \
+                     stream.write_uvarint({key})?; \
+                     stream.write_uvarint(counter.len as u64)?; \
+                     for v in &self.{name} {{ {write} }} }}",
+                    name = name,
+                    key = key,
+                    count = self.encode_value(field, "counter"),
+                    write = self.encode_value(field, "stream"),
+                )
+            } else if field.flags & FLAG_REPEATED != 0 {
+                format!(
+                    "for v in &self.{} {{ {} }}",
+                    name,
+                    self.encode_record(field)
+                )
+            } else if field.flags & FLAG_REQUIRED != 0 || msg.defaults.contains_key(&field.tag) {
+                format!(
+                    "{{ let v = &self.{}; {} }}",
+                    name,
+                    self.encode_record(field)
+                )
+            } else {
+                format!(
+                    "if let Some(v) = &self.{} {{ {} }}",
+                    name,
+                    self.encode_record(field)
+                )
+            };
+            records.push(record);
+        }
+        if records.is_empty() {
+            writeln!(
+                out,
+                "fn encode_fields(&self, _stream: &mut impl OutputStream) -> Result<(), Error> \
+                 {{ Ok(()) }}"
+            )
+            .unwrap();
+        } else {
+            writeln!(
+                out,
+                "fn encode_fields(&self, stream: &mut impl OutputStream) -> Result<(), Error> \
+                 {{\n{}\nOk(()) }}",
+                records.join("\n")
+            )
+            .unwrap();
+        }
+        writeln!(out, "}}\n").unwrap();
+
+        self.out.push_str(&out);
+    }
+}
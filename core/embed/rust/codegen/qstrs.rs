@@ -0,0 +1,20 @@
+//! Parsing of the generated `qstrdefs.generated.h`. Used by
+//! `codegen/protobuf_types.rs` and `embed/rust/protobuf_defs`.
+
+/// Names of the qstrs, indexed by their IDs. Same as `pb2py`, only lines
+/// like `QDEF(MP_QSTR_x, (const byte*)"\x01\x02\x03" "x")` are counted.
+pub fn parse_qstrs(qstr_defs: &str) -> Vec<&str> {
+    qstr_defs
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("QDEF(MP_QSTR")?;
+            let (ident, rest) = rest.split_once(", (const byte*)\"")?;
+            let hash = rest.get(..12)?.as_bytes();
+            let is_hash = (0..3).all(|i| hash[i * 4] == b'\\' && hash[i * 4 + 1] == b'x');
+            if ident.is_empty() || ident.contains(char::is_whitespace) || !is_hash {
+                return None;
+            }
+            rest.get(12..)?.strip_prefix("\" \"")?.strip_suffix("\")")
+        })
+        .collect()
+}
@@ -2,11 +2,16 @@
 
 mp_obj_t protobuf_type_for_name(mp_obj_t name);
 mp_obj_t protobuf_type_for_wire(mp_obj_t wire_id);
-mp_obj_t protobuf_decode(mp_obj_t buf, mp_obj_t def,
-                         mp_obj_t enable_experimental);
+mp_obj_t protobuf_decode(size_t n_args, const mp_obj_t *args,
+                         mp_map_t *kwargs);
 mp_obj_t protobuf_len(mp_obj_t obj);
 mp_obj_t protobuf_encode(mp_obj_t buf, mp_obj_t obj);
 
+#if !PYOPT
+mp_obj_t protobuf_debug_dump(size_t n_args, const mp_obj_t *args,
+                             mp_map_t *kwargs);
+#endif
+
 #ifdef TREZOR_EMULATOR
 mp_obj_t protobuf_debug_msg_type();
 mp_obj_t protobuf_debug_msg_def_type();
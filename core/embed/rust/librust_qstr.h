@@ -8,6 +8,40 @@ static void _librust_qstrs(void) {
   MP_QSTR_is_type_of;
   MP_QSTR_MESSAGE_WIRE_TYPE;
   MP_QSTR_MESSAGE_NAME;
+  MP_QSTR_preserve_unknown;
+  MP_QSTR_strict;
+  MP_QSTR_zero_copy;
+  MP_QSTR_limits;
+#if !PYOPT
+  MP_QSTR_dump;
+  MP_QSTR_json;
+  MP_QSTR_MESSAGE_FIELDS;
+  MP_QSTR_name;
+  MP_QSTR_tag;
+  MP_QSTR_type;
+  MP_QSTR_required;
+  MP_QSTR_repeated;
+  MP_QSTR_experimental;
+  MP_QSTR_packed;
+  MP_QSTR_enum_values;
+  MP_QSTR_msg_type;
+  MP_QSTR_default;
+  MP_QSTR_oneof;
+  MP_QSTR_uvarint;
+  MP_QSTR_svarint;
+  MP_QSTR_bool;
+  MP_QSTR_bytes;
+  MP_QSTR_string;
+  MP_QSTR_enum;
+  MP_QSTR_message;
+  MP_QSTR_fixed32;
+  MP_QSTR_sfixed32;
+  MP_QSTR_fixed64;
+  MP_QSTR_sfixed64;
+  MP_QSTR_int32;
+  MP_QSTR_int64;
+  MP_QSTR_map;
+#endif
 
   // layout
   MP_QSTR___name__;
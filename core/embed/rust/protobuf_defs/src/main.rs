@@ -0,0 +1,79 @@
+//! Check the protobuf definitions blobs and print them as a readable schema.
+//!
+//! Usage: `protobuf-defs [--pyopt] <blob dir> [<qstrdefs.generated.h>]`
+//!
+//! The blob directory is the one `pb2py` writes the `proto_*.data` files into,
+//! e.g. `build/unix/rust`. Pass `--pyopt` for blobs of a `PYOPT=1` build,
+//! which leave out the names of the enum values. Names are resolved through
+//! the generated qstr header, by default the one in `../genhdr` next to the
+//! blobs.
+
+use std::{env, fs, path::Path, process::ExitCode};
+
+#[allow(dead_code)]
+#[path = "../../src/protobuf/layout.rs"]
+mod layout;
+#[path = "../../codegen/qstrs.rs"]
+mod qstrs;
+#[path = "../../src/protobuf/validate.rs"]
+mod validate;
+
+use qstrs::parse_qstrs;
+use validate::{validate, write_schema, Blobs};
+
+fn main() -> ExitCode {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    let enum_names = match args.iter().position(|arg| arg == "--pyopt") {
+        Some(index) => {
+            args.remove(index);
+            false
+        }
+        None => true,
+    };
+    if args.is_empty() || args.len() > 2 {
+        eprintln!("usage: protobuf-defs [--pyopt] <blob dir> [<qstrdefs.generated.h>]");
+        return ExitCode::FAILURE;
+    }
+    let dir = Path::new(&args[0]);
+    let qstr_path = match args.get(1) {
+        Some(path) => Path::new(path).to_path_buf(),
+        None => dir.join("../genhdr/qstrdefs.generated.h"),
+    };
+
+    let read = |name: &str| {
+        let path = dir.join(name);
+        fs::read(&path).unwrap_or_else(|err| {
+            eprintln!("cannot read {}: {}", path.display(), err);
+            std::process::exit(1);
+        })
+    };
+    let enums = read("proto_enums.data");
+    let msgs = read("proto_msgs.data");
+    let names = read("proto_names.data");
+    let wire = read("proto_wire.data");
+    let blobs = Blobs {
+        enums: &enums,
+        msgs: &msgs,
+        names: &names,
+        wire: &wire,
+        enum_names,
+    };
+
+    let qstr_defs = fs::read_to_string(&qstr_path).unwrap_or_else(|_| {
+        eprintln!("{} not found, printing qstr IDs", qstr_path.display());
+        String::new()
+    });
+    let qstrs = parse_qstrs(&qstr_defs);
+
+    if let Err(err) = validate(&blobs) {
+        eprintln!("invalid definitions: {:?}", err);
+        return ExitCode::FAILURE;
+    }
+    let mut schema = String::new();
+    if let Err(err) = write_schema(&blobs, |id| qstrs.get(id as usize).copied(), &mut schema) {
+        eprintln!("cannot print the schema: {:?}", err);
+        return ExitCode::FAILURE;
+    }
+    print!("{}", schema);
+    ExitCode::SUCCESS
+}
@@ -21,10 +21,11 @@ pub enum Error {
     KeyError(Obj),
     #[cfg(feature = "micropython")]
     AttributeError(Qstr),
-    #[cfg(feature = "micropython")]
     ValueError(&'static CStr),
     #[cfg(feature = "micropython")]
     ValueErrorParam(&'static CStr, Obj),
+    #[cfg(feature = "micropython")]
+    ValueErrorParams(&'static CStr, Obj, Obj),
 }
 
 #[cfg(feature = "micropython")]
@@ -61,6 +62,14 @@ impl Error {
                         ffi::mp_obj_new_exception(&ffi::mp_type_ValueError)
                     }
                 }
+                Error::ValueErrorParams(msg, param, other) => {
+                    if let Ok(msg) = msg.try_into() {
+                        let args = [msg, param, other];
+                        ffi::mp_obj_new_exception_args(&ffi::mp_type_ValueError, 3, args.as_ptr())
+                    } else {
+                        ffi::mp_obj_new_exception(&ffi::mp_type_ValueError)
+                    }
+                }
                 Error::AttributeError(attr) => {
                     ffi::mp_obj_new_exception_args(&ffi::mp_type_AttributeError, 1, &attr.into())
                 }
@@ -2,7 +2,7 @@ use core::{convert::TryFrom, ops::Deref, ptr, slice, str};
 
 use crate::{error::Error, micropython::obj::Obj};
 
-use super::ffi;
+use super::{ffi, runtime::catch_exception};
 
 /// Represents an immutable UTF-8 string managed by MicroPython GC.
 /// This either means static data, or a valid GC object.
@@ -183,6 +183,22 @@ pub unsafe fn get_buffer_mut<'a>(obj: Obj) -> Result<&'a mut [u8], Error> {
     }
 }
 
+/// Create a read-only `memoryview` object over `buf`, without copying.
+///
+/// SAFETY:
+/// The memoryview holds a raw pointer to `buf`, which does not keep the memory
+/// alive on its own if it points into the middle of a GC block. The caller is
+/// responsible for ensuring that the memory of `buf` stays allocated and is
+/// not modified for as long as the memoryview is reachable.
+pub unsafe fn new_memoryview(buf: &[u8]) -> Result<Obj, Error> {
+    // SAFETY: Without the RW flag in the typecode, MicroPython refuses writes
+    // through the memoryview.
+    // EXCEPTION: Will raise if allocation fails.
+    catch_exception(|| unsafe {
+        ffi::mp_obj_new_memoryview(b'B', buf.len(), buf.as_ptr() as *mut _)
+    })
+}
+
 #[cfg(feature = "ui_debug")]
 impl crate::trace::Trace for StrBuffer {
     fn trace(&self, t: &mut dyn crate::trace::Tracer) {
@@ -38,6 +38,26 @@ impl<T> Gc<T> {
     }
 }
 
+impl Gc<[u8]> {
+    /// Allocate a zero-filled byte buffer of non-zero `len` on the heap managed
+    /// by the MicroPython garbage collector.
+    pub fn new_bytes(len: usize) -> Result<Self, Error> {
+        // SAFETY:
+        //  - `raw` is guaranteed to stay valid as long as it's reachable from the stack
+        //    or the MicroPython heap.
+        //  - Bytes have no alignment requirements.
+        // EXCEPTION: Returns null instead of raising.
+        unsafe {
+            let raw = ffi::gc_alloc(len, 0).cast::<u8>();
+            if raw.is_null() {
+                return Err(Error::AllocationFailed);
+            }
+            ptr::write_bytes(raw, 0, len);
+            Ok(Self::from_raw(ptr::slice_from_raw_parts_mut(raw, len)))
+        }
+    }
+}
+
 impl<T: ?Sized> Gc<T> {
     /// Construct a `Gc` from a raw pointer.
     ///
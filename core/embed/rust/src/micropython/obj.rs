@@ -364,10 +364,20 @@ impl TryFrom<Obj> for u64 {
     type Error = Error;
 
     fn try_from(obj: Obj) -> Result<Self, Self::Error> {
-        // TODO: Support full range.
-        let val = i64::try_from(obj)?;
-        let this = Self::try_from(val)?;
-        Ok(this)
+        let mut ull: cty::c_ulonglong = 0;
+
+        // SAFETY:
+        //  - `ull` is a mutable variable of the right type.
+        //  - `obj` can be anything uPy understands.
+        // EXCEPTION: Does not raise.
+        if unsafe { ffi::trezor_obj_get_ull_checked(obj, &mut ull) } {
+            Ok(ull)
+        } else {
+            // Negative values are out of range, everything else is a type error.
+            let val = i64::try_from(obj)?;
+            let this = Self::try_from(val)?;
+            Ok(this)
+        }
     }
 }
 
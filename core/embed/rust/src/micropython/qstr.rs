@@ -2,9 +2,12 @@
 #![allow(non_upper_case_globals)]
 #![allow(dead_code)]
 
-use core::convert::TryFrom;
+use core::{convert::TryFrom, slice, str};
 
-use crate::{error::Error, micropython::obj::Obj};
+use crate::{
+    error::Error,
+    micropython::{ffi, obj::Obj},
+};
 
 impl Qstr {
     pub const fn to_obj(self) -> Obj {
@@ -30,6 +33,18 @@ impl Qstr {
         // TODO: Change the internal representation of Qstr to u16.
         Self(val as _)
     }
+
+    pub fn as_str(self) -> &'static str {
+        let mut len = 0;
+        // SAFETY:
+        //  - Qstr data is never freed, so the returned slice lives forever.
+        //  - Qstrs are only ever created from valid UTF-8 strings.
+        // EXCEPTION: Does not raise.
+        unsafe {
+            let ptr = ffi::qstr_data(self.0 as _, &mut len);
+            str::from_utf8_unchecked(slice::from_raw_parts(ptr, len))
+        }
+    }
 }
 
 impl From<u16> for Qstr {
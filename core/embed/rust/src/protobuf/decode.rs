@@ -1,18 +1,30 @@
 use core::{
+    cell::{Cell, Ref, RefCell},
     convert::{TryFrom, TryInto},
-    str,
+    mem,
 };
 
 use crate::{
     error::Error,
-    micropython::{buffer, gc::Gc, list::List, map::Map, obj::Obj, qstr::Qstr, util},
+    micropython::{
+        buffer,
+        dict::Dict,
+        gc::Gc,
+        list::List,
+        map::{Map, MapElem},
+        obj::Obj,
+        qstr::Qstr,
+        util,
+    },
 };
 
 use super::{
-    defs::{self, FieldDef, FieldType, MsgDef},
+    defs::{FieldDef, FieldType, MsgDef, MAP_KEY_TAG, MAP_VALUE_TAG},
     error,
-    obj::{MsgDefObj, MsgObj},
-    zigzag,
+    obj::{set_oneof_members, MsgDefObj, MsgObj},
+    path::{FieldPath, PathError, PathSegment},
+    stream::InputStream,
+    visit::{self, Value, Visitor},
 };
 
 #[no_mangle]
@@ -38,10 +50,18 @@ pub extern "C" fn protobuf_type_for_wire(wire_id: Obj) -> Obj {
 }
 
 #[no_mangle]
-pub extern "C" fn protobuf_decode(buf: Obj, msg_def: Obj, enable_experimental: Obj) -> Obj {
-    let block = || {
-        let def = Gc::<MsgDefObj>::try_from(msg_def)?;
-        let enable_experimental = bool::try_from(enable_experimental)?;
+pub extern "C" fn protobuf_decode(n_args: usize, args: *const Obj, kwargs: *mut Map) -> Obj {
+    let block = |args: &[Obj], kwargs: &Map| {
+        if args.len() != 3 {
+            return Err(Error::TypeError);
+        }
+        let buf = args[0];
+        let def = Gc::<MsgDefObj>::try_from(args[1])?;
+        let enable_experimental = bool::try_from(args[2])?;
+        let preserve_unknown = kwargs.get_or(Qstr::MP_QSTR_preserve_unknown, false)?;
+        let strict = kwargs.get_or(Qstr::MP_QSTR_strict, false)?;
+        let zero_copy = kwargs.get_or(Qstr::MP_QSTR_zero_copy, false)?;
+        let limits = kwargs.get_or(Qstr::MP_QSTR_limits, false)?;
 
         if !enable_experimental && def.msg().is_experimental {
             // Refuse to decode message defs marked as experimental if not
@@ -53,24 +73,99 @@ pub extern "C" fn protobuf_decode(buf: Obj, msg_def: Obj, enable_experimental: O
 
         // SAFETY:
         // We assume that for the lifetime of `buf`, no MicroPython code can run that
-        // would mutate the buffer, nor pass it to another Rust function.
+        // would mutate the buffer, nor pass it to another Rust function. In the
+        // zero-copy mode, the caller is responsible for keeping `buf` alive and
+        // unmodified while the decoded message is in use, see `MsgObj`.
         let buf = unsafe { buffer::get_buffer(buf) }?;
         let stream = &mut InputStream::new(buf);
-        let decoder = Decoder {
-            enable_experimental,
-        };
+        let mut decoder = Decoder::new(enable_experimental);
+        decoder.preserve_unknown = preserve_unknown;
+        decoder.strict = strict;
+        decoder.zero_copy = zero_copy;
+        if limits {
+            decoder.limits = DecodeLimits::WIRE;
+        }
 
-        let obj = decoder.message_from_stream(stream, def.msg())?;
+        let obj = decoder
+            .message_from_stream(stream, def.msg())
+            .map_err(|err| decoder.error_with_path(err))?;
         Ok(obj)
     };
-    unsafe { util::try_or_raise(block) }
+    unsafe { util::try_with_args_and_kwargs(n_args, args, kwargs, block) }
+}
+
+/// Resource limits enforced while decoding a single message. Unlimited by
+/// default, messages read from the wire opt in to `DecodeLimits::WIRE`.
+#[derive(Clone, Copy)]
+pub struct DecodeLimits {
+    /// Maximum nesting depth of messages, the top-level message included.
+    pub max_depth: usize,
+    /// Maximum number of values of a single repeated field.
+    pub max_repeated: usize,
+    /// Maximum number of bytes allocated on the MicroPython heap. The amount is
+    /// approximated from the sizes of the created messages, lists and buffers.
+    pub max_alloc: usize,
+}
+
+impl DecodeLimits {
+    /// Limits of messages received from the host.
+    pub const WIRE: Self = Self {
+        max_depth: 16,
+        max_repeated: 1024,
+        max_alloc: 32 * 1024,
+    };
+
+    pub const fn unlimited() -> Self {
+        Self {
+            max_depth: usize::MAX,
+            max_repeated: usize::MAX,
+            max_alloc: usize::MAX,
+        }
+    }
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self::unlimited()
+    }
 }
 
 pub struct Decoder {
     pub enable_experimental: bool,
+    /// Keep the raw encoding of fields missing from the message definition on
+    /// the decoded `MsgObj`, so that re-encoding does not lose them.
+    pub preserve_unknown: bool,
+    /// Only accept the canonical encoding, i.e. refuse non-minimal varints,
+    /// singular fields occurring more than once and incomplete trailing keys.
+    pub strict: bool,
+    /// Return bytes fields as read-only memoryviews into the input buffer
+    /// instead of copying them. See `MsgObj` for the lifetime rules.
+    pub zero_copy: bool,
+    pub limits: DecodeLimits,
+    depth: Cell<usize>,
+    allocated: Cell<usize>,
+    path: RefCell<FieldPath>,
 }
 
 impl Decoder {
+    pub fn new(enable_experimental: bool) -> Self {
+        Self {
+            enable_experimental,
+            preserve_unknown: false,
+            strict: false,
+            zero_copy: false,
+            limits: DecodeLimits::default(),
+            depth: Cell::new(0),
+            allocated: Cell::new(0),
+            path: RefCell::new(FieldPath::new()),
+        }
+    }
+
+    pub fn with_limits(mut self, limits: DecodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
     /// Create a new message instance and decode `stream` into it, handling the
     /// default and required fields correctly.
     pub fn message_from_stream(
@@ -78,93 +173,290 @@ impl Decoder {
         stream: &mut InputStream,
         msg: &MsgDef,
     ) -> Result<Obj, Error> {
-        let mut obj = self.empty_message(msg)?;
+        let mut obj = self.begin_message(msg)?;
         // SAFETY: We assume that `obj` is not aliased here.
-        let map = unsafe { Gc::as_mut(&mut obj) }.map_mut();
-        self.decode_fields_into(stream, msg, map)?;
+        let this = unsafe { Gc::as_mut(&mut obj) };
+        self.decode_fields_into(stream, msg, this)?;
+        self.end_message(msg, this)?;
+        Ok(obj.into())
+    }
+
+    /// Enter a new nesting level and allocate an empty message instance for
+    /// it. Has to be paired with `end_message` once all fields are decoded.
+    pub fn begin_message(&self, msg: &MsgDef) -> Result<Gc<MsgObj>, Error> {
+        // Depth is only restored on success, a failed decoding cannot be resumed.
+        let depth = self.depth.get() + 1;
+        if depth > self.limits.max_depth {
+            return Err(error::max_depth_exceeded());
+        }
+        self.depth.set(depth);
+        if depth == 1 {
+            self.enter(PathSegment::Message(msg.offset));
+        }
+        self.empty_message(msg)
+    }
+
+    /// Fill in the default and missing fields of a decoded message and leave
+    /// its nesting level.
+    pub fn end_message(&self, msg: &MsgDef, obj: &mut MsgObj) -> Result<(), Error> {
+        let map = obj.map_mut();
         self.decode_defaults_into(msg, map)?;
         self.assign_required_into(msg, map)?;
-        Ok(obj.into())
+
+        let depth = self.depth.get();
+        if depth == 1 {
+            self.leave();
+        }
+        self.depth.set(depth - 1);
+        Ok(())
+    }
+
+    /// Path of the value that was being decoded when the last error occurred.
+    /// Empty if the decoding succeeded.
+    pub fn path(&self) -> Ref<FieldPath> {
+        self.path.borrow()
+    }
+
+    /// Attach the current field path to `err`, so that it is clear which part
+    /// of a nested message was at fault.
+    pub fn error_with_path(&self, err: Error) -> PathError {
+        PathError {
+            error: err,
+            path: self.path().clone(),
+        }
+    }
+
+    fn enter(&self, segment: PathSegment) {
+        self.path.borrow_mut().push(segment);
+    }
+
+    pub fn leave(&self) {
+        self.path.borrow_mut().pop();
     }
 
     /// Create a new message instance and fill it from `values`, handling the
     /// default and required fields correctly.
     pub fn message_from_values(&self, values: &Map, msg: &MsgDef) -> Result<Obj, Error> {
+        // Errors are reported with the same paths as when decoding, see
+        // `error_with_path`.
+        self.enter(PathSegment::Message(msg.offset));
         let mut obj = self.empty_message(msg)?;
         // SAFETY: We assume that `obj` is not aliased here.
         let map = unsafe { Gc::as_mut(&mut obj) }.map_mut();
         for elem in values.elems() {
             map.set(elem.key, elem.value)?;
         }
+        // Same as on the wire, only one member of a oneof can be set.
+        for (group, name) in msg.oneofs.iter().enumerate() {
+            if set_oneof_members(msg, map, group as u8).nth(1).is_some() {
+                return Err(error::multiple_oneof_members(name.into()));
+            }
+        }
         self.decode_defaults_into(msg, map)?;
         self.assign_required_into(msg, map)?;
+        self.leave();
         Ok(obj.into())
     }
 
     /// Allocate the backing message object with enough pre-allocated space for
     /// all fields.
     pub fn empty_message(&self, msg: &MsgDef) -> Result<Gc<MsgObj>, Error> {
+        let size = mem::size_of::<MsgObj>() + msg.fields.len() * mem::size_of::<MapElem>();
+        self.charge_alloc(size)?;
         MsgObj::alloc_with_capacity(msg.fields.len(), msg)
     }
 
+    /// Account for `size` bytes about to be allocated, failing if the
+    /// allocation budget would be exceeded.
+    pub fn charge_alloc(&self, size: usize) -> Result<(), Error> {
+        let allocated = self.allocated.get().saturating_add(size);
+        if allocated > self.limits.max_alloc {
+            return Err(error::max_alloc_exceeded());
+        }
+        self.allocated.set(allocated);
+        Ok(())
+    }
+
     /// Decode message fields one-by-one from the input stream, assigning them
-    /// into `map`.
-    fn decode_fields_into(
+    /// into the map of `obj`.
+    pub fn decode_fields_into(
         &self,
         stream: &mut InputStream,
         msg: &MsgDef,
+        obj: &mut MsgObj,
+    ) -> Result<(), Error> {
+        if self.strict {
+            stream.set_canonical(true);
+        }
+        visit::visit_fields(
+            stream,
+            msg,
+            &mut MsgBuilder {
+                decoder: self,
+                msg,
+                obj,
+            },
+        )
+    }
+
+    /// Extend the field path with the next value of `field` in `obj`. Has to
+    /// be paired with `leave` once the value is assigned.
+    pub fn enter_field(&self, field: &FieldDef, obj: &MsgObj) -> Result<(), Error> {
+        let field_name = Qstr::from(field.name);
+        if field.is_repeated() {
+            let index = match obj.map().get(field_name) {
+                Ok(list) => Gc::<List>::try_from(list)?.len(),
+                Err(_) => 0,
+            };
+            self.enter(PathSegment::Element(field_name, index));
+        } else {
+            self.enter(PathSegment::Field(field_name));
+        }
+        Ok(())
+    }
+
+    /// Assign a decoded value of `field` of `msg` into `obj`.
+    pub fn assign_field_into(
+        &self,
+        msg: &MsgDef,
+        field: &FieldDef,
+        value: Obj,
+        obj: &mut MsgObj,
+    ) -> Result<(), Error> {
+        if let FieldType::Map(entry) = field.get_type() {
+            self.insert_map_entry_into(field, &entry, value, obj.map_mut())
+        } else if field.is_repeated() {
+            self.append_repeated_into(field, value, obj.map_mut())
+        } else {
+            // Singular field, assign the value directly. The last occurrence wins,
+            // unless we are in the strict mode.
+            let field_name = Qstr::from(field.name);
+            if self.strict && obj.map().contains_key(field_name) {
+                return Err(error::duplicate_field(field_name));
+            }
+            if let Some(group) = field.oneof() {
+                self.clear_oneof_into(msg, field, group, obj)?;
+            }
+            obj.map_mut().set(field_name, value)
+        }
+    }
+
+    /// Remove the other members of the oneof `group` of `field` from `obj`, so
+    /// that the last member on the wire wins. In the strict mode, setting
+    /// several members is refused instead.
+    fn clear_oneof_into(
+        &self,
+        msg: &MsgDef,
+        field: &FieldDef,
+        group: u8,
+        obj: &mut MsgObj,
+    ) -> Result<(), Error> {
+        for member in msg.oneof_members(group) {
+            let member_name = Qstr::from(member.name);
+            if member.tag == field.tag || !obj.map().contains_key(member_name) {
+                continue;
+            }
+            if self.strict {
+                let oneof_name = Qstr::from(unwrap!(msg.oneofs.get(group as usize)));
+                return Err(error::multiple_oneof_members(oneof_name));
+            }
+            obj.map_mut().delete(member_name);
+        }
+        Ok(())
+    }
+
+    /// Append `value` to the list of values of a repeated field.
+    fn append_repeated_into(
+        &self,
+        field: &FieldDef,
+        value: Obj,
         map: &mut Map,
     ) -> Result<(), Error> {
-        // Loop, trying to read the field key that contains the tag and primitive value
-        // type. If we fail to read the key, we are at the end of the stream.
-        while let Ok(field_key) = stream.read_uvarint() {
-            let field_tag = u8::try_from(field_key >> 3)?;
-            let prim_type = u8::try_from(field_key & 7)?;
-
-            match msg.field(field_tag) {
-                Some(field) => {
-                    let field_value = self.decode_field(stream, field)?;
-                    let field_name = Qstr::from(field.name);
-                    if field.is_repeated() {
-                        // Repeated field, values are stored in a list. First, look up the list
-                        // object. If it exists, append to it. If it doesn't, create a new list with
-                        // this field's value and assign it.
-                        if let Ok(obj) = map.get(field_name) {
-                            let mut list = Gc::<List>::try_from(obj)?;
-                            // SAFETY: We assume that `list` is not aliased here. This holds for
-                            // uses in `message_from_stream` and `message_from_values`, because we
-                            // start with an empty `Map` and fill with unique lists.
-                            unsafe { Gc::as_mut(&mut list) }.append(field_value)?;
-                        } else {
-                            let list = List::alloc(&[field_value])?;
-                            map.set(field_name, list)?;
-                        }
-                    } else {
-                        // Singular field, assign the value directly.
-                        map.set(field_name, field_value)?;
-                    }
-                }
-                None => {
-                    // Unknown field, skip it.
-                    match prim_type {
-                        defs::PRIMITIVE_TYPE_VARINT => {
-                            stream.read_uvarint()?;
-                        }
-                        defs::PRIMITIVE_TYPE_LENGTH_DELIMITED => {
-                            let num = stream.read_uvarint()?;
-                            let len = num.try_into()?;
-                            stream.read(len)?;
-                        }
-                        _ => {
-                            return Err(error::unknown_field_type());
-                        }
-                    }
-                }
+        // Repeated field, values are stored in a list. First, look up the list
+        // object. If it exists, append to it. If it doesn't, create a new list with
+        // this field's value and assign it.
+        let field_name = Qstr::from(field.name);
+        if let Ok(obj) = map.get(field_name) {
+            let mut list = Gc::<List>::try_from(obj)?;
+            // SAFETY: We assume that `list` is not aliased here. This holds for
+            // uses in `message_from_stream` and `message_from_values`, because we
+            // start with an empty `Map` and fill with unique lists.
+            let list = unsafe { Gc::as_mut(&mut list) };
+            if list.len() >= self.limits.max_repeated {
+                return Err(error::max_repeated_exceeded(field_name));
             }
+            self.charge_alloc(mem::size_of::<Obj>())?;
+            list.append(value)?;
+        } else {
+            self.charge_alloc(mem::size_of::<List>() + mem::size_of::<Obj>())?;
+            let list = List::alloc(&[value])?;
+            map.set(field_name, list)?;
         }
         Ok(())
     }
 
+    /// Insert the key and value of a decoded map entry message into the dict
+    /// of a map field. A missing key means the default of the key type, same
+    /// as for any other field, and a missing value is stored as `None`.
+    fn insert_map_entry_into(
+        &self,
+        field: &FieldDef,
+        entry: &MsgDef,
+        value: Obj,
+        map: &mut Map,
+    ) -> Result<(), Error> {
+        let field_name = Qstr::from(field.name);
+        let entry_obj = Gc::<MsgObj>::try_from(value)?;
+        let entry_field = |tag: u8| {
+            entry
+                .field(tag)
+                .map(|field| Qstr::from(field.name))
+                .ok_or_else(|| Error::KeyError(tag.into()))
+        };
+        let mut key = entry_obj.map().get(entry_field(MAP_KEY_TAG)?)?;
+        let value = entry_obj.map().get(entry_field(MAP_VALUE_TAG)?)?;
+        if key == Obj::const_none() {
+            let key_field = entry
+                .field(MAP_KEY_TAG)
+                .ok_or_else(|| Error::KeyError(MAP_KEY_TAG.into()))?;
+            key = match key_field.get_type() {
+                FieldType::Bool => false.into(),
+                FieldType::String => Qstr::MP_QSTR_.into(),
+                FieldType::Bytes | FieldType::Enum(_) | FieldType::Msg(_) | FieldType::Map(_) => {
+                    return Err(error::invalid_map_key(field_name))
+                }
+                _ => 0_u8.into(),
+            };
+        }
+
+        // Look up the dict of the field, or create an empty one.
+        let dict = match map.get(field_name) {
+            Ok(dict) => dict,
+            Err(_) => {
+                self.charge_alloc(mem::size_of::<Dict>())?;
+                let dict: Obj = Dict::alloc_with_capacity(1)?.into();
+                map.set(field_name, dict)?;
+                dict
+            }
+        };
+        let mut dict = Gc::<Dict>::try_from(dict)?;
+        // SAFETY: We assume that `dict` is not aliased here, same as the lists
+        // of repeated fields.
+        let dict = unsafe { Gc::as_mut(&mut dict) }.map_mut();
+        if dict.contains_key(key) {
+            // The last occurrence of a key wins, unless we are in the strict mode.
+            if self.strict {
+                return Err(error::duplicate_field(field_name));
+            }
+        } else {
+            if dict.len() >= self.limits.max_repeated {
+                return Err(error::max_repeated_exceeded(field_name));
+            }
+            self.charge_alloc(mem::size_of::<MapElem>())?;
+        }
+        dict.set(key, value)
+    }
+
     /// Fill in the default values by decoding them from the defaults stream.
     /// Only singular fields are allowed to have a default value, this is
     /// enforced in the blob compilation.
@@ -183,24 +475,42 @@ impl Decoder {
             let field_name = Qstr::from(field.name);
             if map.contains_key(field_name) {
                 // Field already has a value assigned, skip it.
-                match field.get_type().primitive_type() {
-                    defs::PRIMITIVE_TYPE_VARINT => {
-                        stream.read_uvarint()?;
-                    }
-                    defs::PRIMITIVE_TYPE_LENGTH_DELIMITED => {
-                        let num = stream.read_uvarint()?;
-                        let len = num.try_into()?;
-                        stream.read(len)?;
-                    }
-                    _ => {
-                        return Err(error::unknown_field_type());
-                    }
-                }
+                stream.skip_value(field.get_type().primitive_type())?;
             } else {
                 // Decode the value and assign it.
-                let field_value = self.decode_field(stream, field)?;
+                self.enter(PathSegment::Field(field_name));
+                self.check_experimental(field)?;
+                let field_value = self.value_to_obj(visit::read_value(stream, field)?)?;
                 map.set(field_name, field_value)?;
+                self.leave();
+            }
+        }
+        Ok(())
+    }
+
+    /// Decode the default value of `field` from the defaults stream of `msg`,
+    /// or return `None` if the field has no default.
+    pub fn default_value(&self, msg: &MsgDef, field: &FieldDef) -> Result<Option<Obj>, Error> {
+        let stream = &mut InputStream::new(msg.defaults);
+        while let Ok(field_tag) = stream.read_byte() {
+            let default_field = msg
+                .field(field_tag)
+                .ok_or_else(|| Error::KeyError(field_tag.into()))?;
+            if field_tag == field.tag {
+                self.check_experimental(field)?;
+                let value = visit::read_value(stream, field)?;
+                return self.value_to_obj(value).map(Some);
             }
+            stream.skip_value(default_field.get_type().primitive_type())?;
+        }
+        Ok(None)
+    }
+
+    /// Refuse values of experimental fields, including their defaults, unless
+    /// experimental features are enabled.
+    pub fn check_experimental(&self, field: &FieldDef) -> Result<(), Error> {
+        if field.is_experimental() && !self.enable_experimental {
+            return Err(error::experimental_not_enabled());
         }
         Ok(())
     }
@@ -216,10 +526,16 @@ impl Decoder {
             }
             if field.is_required() {
                 // Required field is missing, abort.
+                self.enter(PathSegment::Field(field_name));
                 return Err(error::missing_required_field(field_name));
             }
-            if field.is_repeated() {
+            if let FieldType::Map(_) = field.get_type() {
+                // Map field, set to a new empty dict.
+                self.charge_alloc(mem::size_of::<Dict>())?;
+                map.set(field_name, Dict::alloc_with_capacity(0)?)?;
+            } else if field.is_repeated() {
                 // Optional repeated field, set to a new empty list.
+                self.charge_alloc(mem::size_of::<List>())?;
                 map.set(field_name, List::alloc(&[])?)?;
             } else {
                 // Optional singular field, set to None.
@@ -229,100 +545,269 @@ impl Decoder {
         Ok(())
     }
 
-    /// Decode one field value from the input stream.
-    fn decode_field(&self, stream: &mut InputStream, field: &FieldDef) -> Result<Obj, Error> {
-        if field.is_experimental() && !self.enable_experimental {
-            return Err(error::experimental_not_enabled());
-        }
-        let num = stream.read_uvarint()?;
-        match field.get_type() {
-            FieldType::UVarInt => Ok(num.try_into()?),
-            FieldType::SVarInt => {
-                let signed_int = zigzag::to_signed(num);
-                Ok(signed_int.try_into()?)
-            }
-            FieldType::Bool => {
-                let boolean = num != 0;
-                Ok(boolean.into())
-            }
-            FieldType::Bytes => {
-                let buf_len = num.try_into()?;
-                let buf = stream.read(buf_len)?;
-                buf.try_into()
-            }
-            FieldType::String => {
-                let buf_len = num.try_into()?;
-                let buf = stream.read(buf_len)?;
-                let unicode =
-                    str::from_utf8(buf).map_err(|_| error::invalid_value(field.name.into()))?;
-                unicode.try_into()
-            }
-            FieldType::Enum(enum_type) => {
-                let enum_val = num.try_into()?;
-                if enum_type.values.contains(&enum_val) {
-                    Ok(enum_val.into())
+    /// Convert a decoded scalar value into an object, copying bytes and strings
+    /// onto the MicroPython heap unless in the zero-copy mode.
+    fn value_to_obj(&self, value: Value) -> Result<Obj, Error> {
+        match value {
+            Value::UInt(num) => Ok(num.try_into()?),
+            Value::Int(num) => Ok(num.try_into()?),
+            Value::Bool(boolean) => Ok(boolean.into()),
+            Value::Bytes(buf) => {
+                if self.zero_copy {
+                    // Only the view object is allocated, not charging for it.
+                    // SAFETY: It is up to the user of the zero-copy mode to keep the
+                    // input buffer alive and unmodified.
+                    unsafe { buffer::new_memoryview(buf) }
                 } else {
-                    Err(error::invalid_value(field.name.into()))
+                    self.charge_alloc(buf.len())?;
+                    buf.try_into()
                 }
             }
-            FieldType::Msg(msg_type) => {
-                let msg_len = num.try_into()?;
-                let sub_stream = &mut stream.read_stream(msg_len)?;
-                self.message_from_stream(sub_stream, &msg_type)
+            Value::String(unicode) => {
+                self.charge_alloc(unicode.len())?;
+                unicode.try_into()
             }
+            Value::Enum(enum_val) => Ok(enum_val.into()),
         }
     }
 }
 
-pub struct InputStream<'a> {
-    buf: &'a [u8],
-    pos: usize,
+/// Visitor assigning the decoded fields into the message instance `obj`.
+struct MsgBuilder<'d> {
+    decoder: &'d Decoder,
+    msg: &'d MsgDef,
+    obj: &'d mut MsgObj,
 }
 
-impl<'a> InputStream<'a> {
-    pub fn new(buf: &'a [u8]) -> Self {
-        Self { buf, pos: 0 }
-    }
-
-    pub fn read_stream(&mut self, len: usize) -> Result<Self, Error> {
-        let buf = self
-            .buf
-            .get(self.pos..self.pos + len)
-            .ok_or_else(error::end_of_buffer)?;
-        self.pos += len;
-        Ok(Self::new(buf))
-    }
-
-    pub fn read(&mut self, len: usize) -> Result<&[u8], Error> {
-        let buf = self
-            .buf
-            .get(self.pos..self.pos + len)
-            .ok_or_else(error::end_of_buffer)?;
-        self.pos += len;
-        Ok(buf)
-    }
-
-    pub fn read_byte(&mut self) -> Result<u8, Error> {
-        let val = self
-            .buf
-            .get(self.pos)
-            .copied()
-            .ok_or_else(error::end_of_buffer)?;
-        self.pos += 1;
-        Ok(val)
-    }
-
-    pub fn read_uvarint(&mut self) -> Result<u64, Error> {
-        let mut uint = 0;
-        let mut shift = 0;
-        loop {
-            let byte = self.read_byte()?;
-            uint += (byte as u64 & 0x7F) << shift;
-            shift += 7;
-            if byte & 0x80 == 0 {
-                break;
-            }
+impl<'a> Visitor<'a> for MsgBuilder<'_> {
+    fn enter_field(&mut self, field: &FieldDef) -> Result<(), Error> {
+        self.decoder.enter_field(field, self.obj)?;
+        self.decoder.check_experimental(field)
+    }
+
+    fn leave_field(&mut self, _field: &FieldDef) {
+        self.decoder.leave();
+    }
+
+    fn visit_value(&mut self, field: &FieldDef, value: Value<'a>) -> Result<(), Error> {
+        let value = self.decoder.value_to_obj(value)?;
+        self.decoder
+            .assign_field_into(self.msg, field, value, self.obj)
+    }
+
+    fn visit_message(
+        &mut self,
+        field: &FieldDef,
+        msg: &MsgDef,
+        stream: &mut InputStream<'a>,
+    ) -> Result<(), Error> {
+        // Map entries are decoded as messages and only then inserted into the
+        // dict, see `insert_map_entry_into`.
+        let value = self.decoder.message_from_stream(stream, msg)?;
+        self.decoder
+            .assign_field_into(self.msg, field, value, self.obj)
+    }
+
+    fn visit_unknown(&mut self, raw: &'a [u8]) -> Result<(), Error> {
+        // If requested, keep the raw encoding of unknown fields around.
+        if self.decoder.preserve_unknown {
+            self.decoder
+                .charge_alloc(raw.len() + mem::size_of::<Obj>())?;
+            self.obj.append_unknown_field(raw)?;
         }
-        Ok(uint)
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::micropython::{map::Map, testutil::mpy_init};
+
+    use super::{
+        super::{
+            defs::PackedU16Slice,
+            testutil::{get_address, map_entry, GET_ADDRESS, MAP_FIELD},
+        },
+        *,
+    };
+
+    // Message with a oneof `type`, of an `int` and a `str` member.
+    static ONEOF_FIELDS: [FieldDef; 3] = [
+        FieldDef::scalar(1, 0, 0)
+            .named(Qstr::MP_QSTR_int.to_u16())
+            .in_oneof(0),
+        FieldDef::scalar(2, 0, 4)
+            .named(Qstr::MP_QSTR_str.to_u16())
+            .in_oneof(0),
+        FieldDef::scalar(3, 0, 2).named(Qstr::MP_QSTR_bool.to_u16()),
+    ];
+    static ONEOFS: [[u8; 2]; 1] = [Qstr::MP_QSTR_type.to_u16().to_le_bytes()];
+
+    fn oneof_msg() -> MsgDef {
+        MsgDef {
+            fields: &ONEOF_FIELDS,
+            oneofs: PackedU16Slice::new(&ONEOFS),
+            defaults: &[],
+            is_experimental: false,
+            wire_id: None,
+            offset: 0,
+        }
+    }
+
+    fn decode_oneof(decoder: &Decoder, buf: &[u8]) -> Result<Gc<MsgObj>, Error> {
+        let msg = oneof_msg();
+        let mut obj = decoder.empty_message(&msg)?;
+        // SAFETY: The message was just allocated and is not aliased.
+        let this = unsafe { Gc::as_mut(&mut obj) };
+        decoder.decode_fields_into(&mut InputStream::new(buf), &msg, this)?;
+        Ok(obj)
+    }
+
+    fn which_oneof(obj: &MsgObj) -> Option<Qstr> {
+        set_oneof_members(&oneof_msg(), obj.map(), 0).next()
+    }
+
+    #[test]
+    fn oneof_last_member_wins() {
+        unsafe { mpy_init() };
+
+        let decoder = Decoder::new(false);
+        let obj = decode_oneof(&decoder, b"\x08\x05\x12\x02hi\x18\x01").unwrap();
+        assert_eq!(which_oneof(&obj), Some(Qstr::MP_QSTR_str));
+        assert!(!obj.map().contains_key(Qstr::MP_QSTR_int));
+        assert!(obj.map().contains_key(Qstr::MP_QSTR_bool));
+
+        let obj = decode_oneof(&decoder, b"\x12\x02hi\x08\x05").unwrap();
+        assert_eq!(which_oneof(&obj), Some(Qstr::MP_QSTR_int));
+        assert!(!obj.map().contains_key(Qstr::MP_QSTR_str));
+
+        let obj = decode_oneof(&decoder, b"\x18\x01").unwrap();
+        assert_eq!(which_oneof(&obj), None);
+
+        // Strict mode refuses several members.
+        let mut decoder = Decoder::new(false);
+        decoder.strict = true;
+        assert!(decode_oneof(&decoder, b"\x08\x05").is_ok());
+        assert!(decode_oneof(&decoder, b"\x08\x05\x12\x02hi").is_err());
+    }
+
+    #[test]
+    fn oneof_from_values() {
+        unsafe { mpy_init() };
+
+        let decoder = Decoder::new(false);
+        let msg = oneof_msg();
+        let mut values = Map::with_capacity(2).unwrap();
+        values.set(Qstr::MP_QSTR_int, 5_u8).unwrap();
+        values.set(Qstr::MP_QSTR_str, Obj::const_none()).unwrap();
+        let obj = decoder.message_from_values(&values, &msg).unwrap();
+        let obj = Gc::<MsgObj>::try_from(obj).unwrap();
+        assert_eq!(which_oneof(&obj), Some(Qstr::MP_QSTR_int));
+
+        values
+            .set(Qstr::MP_QSTR_str, Obj::try_from("hi").unwrap())
+            .unwrap();
+        assert!(decoder.message_from_values(&values, &msg).is_err());
+    }
+
+    #[test]
+    fn map_entries() {
+        unsafe { mpy_init() };
+
+        let entry = map_entry();
+        let insert = |decoder: &Decoder, buf: &[u8], map: &mut Map| {
+            let mut obj = decoder.empty_message(&entry)?;
+            // SAFETY: The entry was just allocated and is not aliased.
+            let this = unsafe { Gc::as_mut(&mut obj) };
+            decoder.decode_fields_into(&mut InputStream::new(buf), &entry, this)?;
+            decoder.insert_map_entry_into(&MAP_FIELD, &entry, obj.into(), map)
+        };
+        let bytes_at = |map: &Map, key: u8| {
+            let dict = Gc::<Dict>::try_from(map.get(Qstr::MP_QSTR_dict).unwrap()).unwrap();
+            let value = dict.map().get(key).unwrap();
+            // SAFETY: The value is a bytes object that is not mutated.
+            unsafe { buffer::get_buffer(value) }.unwrap().to_vec()
+        };
+
+        // The last occurrence of a key wins.
+        let decoder = Decoder::new(false);
+        let mut map = Map::with_capacity(1).unwrap();
+        insert(&decoder, b"\x08\x01\x12\x02ab", &mut map).unwrap();
+        insert(&decoder, b"\x08\x02\x12\x02cd", &mut map).unwrap();
+        insert(&decoder, b"\x08\x01\x12\x02ef", &mut map).unwrap();
+        let dict = Gc::<Dict>::try_from(map.get(Qstr::MP_QSTR_dict).unwrap()).unwrap();
+        assert_eq!(dict.map().len(), 2);
+        assert_eq!(bytes_at(&map, 1), b"ef");
+        assert_eq!(bytes_at(&map, 2), b"cd");
+
+        // Entries without a key use the default key.
+        insert(&decoder, b"\x12\x02gh", &mut map).unwrap();
+        assert_eq!(bytes_at(&map, 0), b"gh");
+
+        // Strict mode refuses duplicate keys.
+        let mut decoder = Decoder::new(false);
+        decoder.strict = true;
+        let mut map = Map::with_capacity(1).unwrap();
+        insert(&decoder, b"\x08\x01\x12\x02ab", &mut map).unwrap();
+        assert!(insert(&decoder, b"\x08\x01\x12\x02cd", &mut map).is_err());
+    }
+
+    #[test]
+    fn experimental_defaults() {
+        unsafe { mpy_init() };
+
+        // Experimental optional field `int` with the default value 5.
+        static FIELDS: [FieldDef; 1] =
+            [FieldDef::scalar(1, 0x20, 0).named(Qstr::MP_QSTR_int.to_u16())];
+        let msg = MsgDef {
+            fields: &FIELDS,
+            oneofs: PackedU16Slice::new(&[]),
+            defaults: b"\x01\x05",
+            is_experimental: false,
+            wire_id: None,
+            offset: 0,
+        };
+        let decode = |decoder: &Decoder| {
+            let obj = decoder.message_from_stream(&mut InputStream::new(b""), &msg)?;
+            Gc::<MsgObj>::try_from(obj)
+        };
+
+        let obj = decode(&Decoder::new(true)).unwrap();
+        let value = obj.map().get(Qstr::MP_QSTR_int).unwrap();
+        assert_eq!(u8::try_from(value).unwrap(), 5);
+        let decoder = Decoder::new(false);
+        assert!(decode(&decoder).is_err());
+        assert!(!decoder.path().is_empty());
+
+        let decoder = Decoder::new(false);
+        assert!(decoder
+            .message_from_values(&Map::with_capacity(0).unwrap(), &msg)
+            .is_err());
+        assert!(!decoder.path().is_empty());
+    }
+
+    #[test]
+    fn depth_limit() {
+        unsafe { mpy_init() };
+
+        // `GetAddress` > `MultisigRedeemScriptType` > `HDNodePathType` >
+        // `HDNodeType`, i.e. four levels.
+        let data = get_address(1);
+        let msg = MsgDef::for_wire_id(GET_ADDRESS).unwrap();
+        let decode = |max_depth| {
+            let limits = DecodeLimits {
+                max_depth,
+                ..DecodeLimits::unlimited()
+            };
+            Decoder::new(false)
+                .with_limits(limits)
+                .message_from_stream(&mut InputStream::new(&data), &msg)
+        };
+
+        assert!(decode(4).is_ok());
+        assert!(matches!(
+            decode(3),
+            Err(Error::ValueError(msg)) if msg.to_bytes() == b"Maximum nesting depth exceeded."
+        ));
     }
 }
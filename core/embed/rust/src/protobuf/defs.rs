@@ -1,7 +1,16 @@
 use core::{mem, slice};
 
+use super::layout::{
+    FIELD_SIZE, FLAGS_MASK, FLAG_EXPERIMENTAL, FLAG_PACKED, FLAG_REPEATED, FLAG_REQUIRED,
+    LOOKUP_SIZE, MSG_EXPERIMENTAL, NO_WIRE_ID, TYPE_BOOL, TYPE_BYTES, TYPE_ENUM, TYPE_FIXED32,
+    TYPE_FIXED64, TYPE_INT32, TYPE_INT64, TYPE_MAP, TYPE_MASK, TYPE_MSG, TYPE_SFIXED32,
+    TYPE_SFIXED64, TYPE_STRING, TYPE_SVARINT, TYPE_UVARINT,
+};
+
 pub struct MsgDef {
     pub fields: &'static [FieldDef],
+    /// Names of the oneof groups, indexed by the group ID of the member fields.
+    pub oneofs: PackedU16Slice,
     pub defaults: &'static [u8],
     pub is_experimental: bool,
     pub wire_id: Option<u16>,
@@ -28,6 +37,20 @@ impl MsgDef {
     pub fn field(&self, tag: u8) -> Option<&FieldDef> {
         self.fields.iter().find(|field| field.tag == tag)
     }
+
+    pub fn oneof_by_name(&self, name: u16) -> Option<u8> {
+        self.oneofs
+            .iter()
+            .position(|oneof| oneof == name)
+            .map(|group| group as u8)
+    }
+
+    /// Fields belonging to the oneof `group`.
+    pub fn oneof_members(&self, group: u8) -> impl Iterator<Item = &FieldDef> {
+        self.fields
+            .iter()
+            .filter(move |field| field.oneof() == Some(group))
+    }
 }
 
 #[repr(C, packed)]
@@ -36,40 +59,87 @@ pub struct FieldDef {
     flags_and_type: u8,
     enum_or_msg_offset: u16,
     pub name: u16,
+    /// Zero if the field is not a member of a oneof group, otherwise the group
+    /// ID plus one.
+    oneof: u8,
 }
 
+// `FieldDef` is read right out of the blobs, see `layout.rs`.
+const _: () = assert!(mem::size_of::<FieldDef>() == FIELD_SIZE);
+
 impl FieldDef {
     pub fn get_type(&self) -> FieldType {
         match self.ftype() {
-            0 => FieldType::UVarInt,
-            1 => FieldType::SVarInt,
-            2 => FieldType::Bool,
-            3 => FieldType::Bytes,
-            4 => FieldType::String,
-            5 => FieldType::Enum(unsafe { get_enum(self.enum_or_msg_offset) }),
-            6 => FieldType::Msg(unsafe { get_msg(self.enum_or_msg_offset) }),
+            TYPE_UVARINT => FieldType::UVarInt,
+            TYPE_SVARINT => FieldType::SVarInt,
+            TYPE_BOOL => FieldType::Bool,
+            TYPE_BYTES => FieldType::Bytes,
+            TYPE_STRING => FieldType::String,
+            TYPE_ENUM => FieldType::Enum(unsafe { get_enum(self.enum_or_msg_offset) }),
+            TYPE_MSG => FieldType::Msg(unsafe { get_msg(self.enum_or_msg_offset) }),
+            TYPE_FIXED32 => FieldType::Fixed32,
+            TYPE_SFIXED32 => FieldType::SFixed32,
+            TYPE_FIXED64 => FieldType::Fixed64,
+            TYPE_SFIXED64 => FieldType::SFixed64,
+            TYPE_INT32 => FieldType::Int32,
+            TYPE_INT64 => FieldType::Int64,
+            TYPE_MAP => FieldType::Map(unsafe { get_msg(self.enum_or_msg_offset) }),
             _ => unreachable!(),
         }
     }
 
     pub fn is_required(&self) -> bool {
-        self.flags() & 0b_1000_0000 != 0
+        self.flags() & FLAG_REQUIRED != 0
     }
 
     pub fn is_repeated(&self) -> bool {
-        self.flags() & 0b_0100_0000 != 0
+        self.flags() & FLAG_REPEATED != 0
     }
 
     pub fn is_experimental(&self) -> bool {
-        self.flags() & 0b_0010_0000 != 0
+        self.flags() & FLAG_EXPERIMENTAL != 0
+    }
+
+    pub fn is_packed(&self) -> bool {
+        self.flags() & FLAG_PACKED != 0
+    }
+
+    /// ID of the oneof group this field is a member of.
+    pub fn oneof(&self) -> Option<u8> {
+        self.oneof.checked_sub(1)
     }
 
     fn flags(&self) -> u8 {
-        self.flags_and_type & 0xF0
+        self.flags_and_type & FLAGS_MASK
     }
 
     fn ftype(&self) -> u8 {
-        self.flags_and_type & 0x0F
+        self.flags_and_type & TYPE_MASK
+    }
+}
+
+#[cfg(test)]
+impl FieldDef {
+    /// Definition of a field with a scalar, non-enum type, for tests that do
+    /// not depend on the compiled definitions.
+    pub const fn scalar(tag: u8, flags: u8, ftype: u8) -> Self {
+        Self {
+            tag,
+            flags_and_type: flags | ftype,
+            enum_or_msg_offset: 0,
+            name: 0,
+            oneof: 0,
+        }
+    }
+
+    pub const fn named(mut self, name: u16) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub const fn in_oneof(mut self, group: u8) -> Self {
+        self.oneof = group + 1;
+        self
     }
 }
 
@@ -81,26 +151,98 @@ pub enum FieldType {
     String,
     Enum(EnumDef),
     Msg(MsgDef),
+    Fixed32,
+    SFixed32,
+    Fixed64,
+    SFixed64,
+    /// Signed integers without the zigzag encoding, negative values take the
+    /// 10-byte form of the sign-extended two's complement.
+    Int32,
+    Int64,
+    /// Map field, stored as a dict. On the wire, it is a repeated sub-message
+    /// with the key and value fields described by the entry definition.
+    Map(MsgDef),
 }
 
 pub const PRIMITIVE_TYPE_VARINT: u8 = 0;
+pub const PRIMITIVE_TYPE_FIXED64: u8 = 1;
 pub const PRIMITIVE_TYPE_LENGTH_DELIMITED: u8 = 2;
+pub const PRIMITIVE_TYPE_FIXED32: u8 = 5;
+
+/// Field tags of the key and value in a map entry message.
+pub const MAP_KEY_TAG: u8 = 1;
+pub const MAP_VALUE_TAG: u8 = 2;
 
 impl FieldType {
     pub fn primitive_type(&self) -> u8 {
         match self {
-            FieldType::UVarInt | FieldType::SVarInt | FieldType::Bool | FieldType::Enum(_) => {
-                PRIMITIVE_TYPE_VARINT
-            }
-            FieldType::Bytes | FieldType::String | FieldType::Msg(_) => {
+            FieldType::UVarInt
+            | FieldType::SVarInt
+            | FieldType::Bool
+            | FieldType::Enum(_)
+            | FieldType::Int32
+            | FieldType::Int64 => PRIMITIVE_TYPE_VARINT,
+            FieldType::Bytes | FieldType::String | FieldType::Msg(_) | FieldType::Map(_) => {
                 PRIMITIVE_TYPE_LENGTH_DELIMITED
             }
+            FieldType::Fixed32 | FieldType::SFixed32 => PRIMITIVE_TYPE_FIXED32,
+            FieldType::Fixed64 | FieldType::SFixed64 => PRIMITIVE_TYPE_FIXED64,
         }
     }
+
+    /// Scalar numeric types can be transmitted in the packed encoding when
+    /// repeated.
+    pub fn is_packable(&self) -> bool {
+        self.primitive_type() != PRIMITIVE_TYPE_LENGTH_DELIMITED
+    }
 }
 
 pub struct EnumDef {
-    pub values: &'static [u16],
+    pub values: PackedU16Slice,
+    /// Qstr names of the values, in the same order. Only debug builds carry
+    /// them.
+    #[cfg(feature = "protobuf_debug")]
+    pub names: PackedU16Slice,
+}
+
+#[cfg(feature = "protobuf_debug")]
+impl EnumDef {
+    /// Qstr name of `value`, or `None` if it is not a value of this enum.
+    pub fn name_of(&self, value: u16) -> Option<u16> {
+        let index = self.values.iter().position(|v| v == value)?;
+        self.names.get(index)
+    }
+}
+
+/// Little-endian `u16` values inside the definitions blob. The blob does not
+/// guarantee any alignment, so the values are read byte by byte.
+#[derive(Clone, Copy)]
+pub struct PackedU16Slice(&'static [[u8; 2]]);
+
+impl PackedU16Slice {
+    pub const fn new(bytes: &'static [[u8; 2]]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<u16> {
+        self.0.get(index).map(|bytes| u16::from_le_bytes(*bytes))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> {
+        self.0.iter().map(|bytes| u16::from_le_bytes(*bytes))
+    }
+
+    pub fn contains(&self, value: u16) -> bool {
+        self.iter().any(|v| v == value)
+    }
 }
 
 #[repr(C, packed)]
@@ -109,6 +251,8 @@ struct NameDef {
     msg_offset: u16,
 }
 
+const _: () = assert!(mem::size_of::<NameDef>() == LOOKUP_SIZE);
+
 #[cfg(target_arch = "arm")]
 macro_rules! proto_def_path {
     ($filename:expr) => {
@@ -187,6 +331,8 @@ pub unsafe fn get_msg(msg_offset: u16) -> MsgDef {
     //     defaults_size: u8,
     //     flags_and_wire_id: u16,
     //     fields: [Field],
+    //     oneofs_count: u8,
+    //     oneofs: [u16],
     //     defaults: [u8],
     // }
 
@@ -201,18 +347,22 @@ pub unsafe fn get_msg(msg_offset: u16) -> MsgDef {
         let flags_and_wire_id_hi = ptr.offset(3).read();
         let flags_and_wire_id = u16::from_le_bytes([flags_and_wire_id_lo, flags_and_wire_id_hi]);
 
-        let is_experimental = flags_and_wire_id & 0x8000 != 0;
-        let wire_id = match flags_and_wire_id & 0x7FFF {
-            0x7FFF => None,
+        let is_experimental = flags_and_wire_id & MSG_EXPERIMENTAL != 0;
+        let wire_id = match flags_and_wire_id & NO_WIRE_ID {
+            NO_WIRE_ID => None,
             some_wire_id => Some(some_wire_id),
         };
 
         let fields_size = fields_count * mem::size_of::<FieldDef>();
         let fields_ptr = ptr.offset(4);
-        let defaults_ptr = ptr.offset(4).add(fields_size);
+        let oneofs_count_ptr = fields_ptr.add(fields_size);
+        let oneofs_count = oneofs_count_ptr.read() as usize;
+        let oneofs_ptr = oneofs_count_ptr.offset(1);
+        let defaults_ptr = oneofs_ptr.add(oneofs_count * mem::size_of::<u16>());
 
         MsgDef {
             fields: slice::from_raw_parts(fields_ptr.cast(), fields_count),
+            oneofs: PackedU16Slice(slice::from_raw_parts(oneofs_ptr.cast(), oneofs_count)),
             defaults: slice::from_raw_parts(defaults_ptr.cast(), defaults_size),
             is_experimental,
             wire_id,
@@ -225,7 +375,8 @@ unsafe fn get_enum(enum_offset: u16) -> EnumDef {
     // #[repr(C, packed)]
     // struct EnumDef {
     //     count: u8,
-    //     vals: [u16],
+    //     vals: [u16; count],
+    //     names: [u16; count],  // only with `protobuf_debug`
     // }
 
     // SAFETY: `enum_offset` has to point to a beginning of a valid enum
@@ -234,9 +385,33 @@ unsafe fn get_enum(enum_offset: u16) -> EnumDef {
         let ptr = ENUM_DEFS.as_ptr().add(enum_offset as usize);
         let count = ptr.offset(0).read() as usize;
         let vals = ptr.offset(1);
+        #[cfg(feature = "protobuf_debug")]
+        let names = vals.add(count * 2);
 
         EnumDef {
-            values: slice::from_raw_parts(vals.cast(), count),
+            values: PackedU16Slice(slice::from_raw_parts(vals.cast(), count)),
+            #[cfg(feature = "protobuf_debug")]
+            names: PackedU16Slice(slice::from_raw_parts(names.cast(), count)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::validate::{validate, Blobs},
+        *,
+    };
+
+    #[test]
+    fn compiled_defs_are_valid() {
+        let blobs = Blobs {
+            enums: ENUM_DEFS,
+            msgs: MSG_DEFS,
+            names: NAME_DEFS,
+            enum_names: cfg!(feature = "protobuf_debug"),
+            wire: WIRE_DEFS,
+        };
+        assert_eq!(validate(&blobs), Ok(()));
+    }
+}
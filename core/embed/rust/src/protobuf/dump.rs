@@ -0,0 +1,557 @@
+//! Human-readable rendering of decoded messages, for debug logs.
+//!
+//! The text format follows the protobuf text format, i.e. one `name: value`
+//! line per value and sub-messages in indented `name { ... }` blocks. The JSON
+//! format is a pretty-printed object keyed by the field names, with repeated
+//! fields as arrays and map fields as objects. In both formats, unset fields
+//! and repeated or map fields without values are left out, enums are shown by
+//! the name of the value and bytes as quoted hex strings. Preserved unknown
+//! fields are not shown.
+
+use core::{
+    convert::{TryFrom, TryInto},
+    str,
+};
+
+use crate::{
+    error::Error,
+    micropython::{
+        buffer,
+        dict::Dict,
+        gc::Gc,
+        iter::{Iter, IterBuf},
+        list::List,
+        map::Map,
+        obj::Obj,
+        qstr::Qstr,
+        util,
+    },
+};
+
+use super::{
+    defs::{FieldDef, FieldType, MsgDef, MAP_KEY_TAG, MAP_VALUE_TAG},
+    error,
+    obj::MsgObj,
+    stream::{BufferStream, CounterStream, OutputStream},
+};
+
+#[no_mangle]
+pub extern "C" fn protobuf_debug_dump(n_args: usize, args: *const Obj, kwargs: *mut Map) -> Obj {
+    let block = |args: &[Obj], kwargs: &Map| {
+        if args.len() != 1 {
+            return Err(Error::TypeError);
+        }
+        let obj = Gc::<MsgObj>::try_from(args[0])?;
+        let format = if kwargs.get_or(Qstr::MP_QSTR_json, false)? {
+            Format::Json
+        } else {
+            Format::Text
+        };
+        dump_to_str(&obj, format)
+    };
+    unsafe { util::try_with_args_and_kwargs(n_args, args, kwargs, block) }
+}
+
+/// Render `obj` into a new string object.
+fn dump_to_str(obj: &MsgObj, format: Format) -> Result<Obj, Error> {
+    // Measure the output first, so that it can be rendered in one buffer.
+    let counter = &mut CounterStream { len: 0 };
+    dump(counter, obj, format)?;
+    if counter.len == 0 {
+        // Nothing to allocate, e.g. a message without any fields in the text
+        // format.
+        return Ok(Qstr::MP_QSTR_.into());
+    }
+    let mut buf = Gc::<[u8]>::new_bytes(counter.len)?;
+    // SAFETY: The buffer was just allocated and is not aliased.
+    let buf = unsafe { Gc::as_mut(&mut buf) };
+    dump(&mut BufferStream::new(buf), obj, format)?;
+
+    let text = str::from_utf8(buf).map_err(|_| error::invalid_message())?;
+    text.try_into()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+/// Render `obj` into `stream` in the given format.
+pub fn dump(stream: &mut impl OutputStream, obj: &MsgObj, format: Format) -> Result<(), Error> {
+    let mut dumper = Dumper {
+        stream,
+        format,
+        depth: 0,
+    };
+    match format {
+        Format::Text => dumper.text_message(&obj.def(), obj),
+        Format::Json => dumper.json_message(&obj.def(), obj),
+    }
+}
+
+struct Dumper<'a, S> {
+    stream: &'a mut S,
+    format: Format,
+    /// Nesting level of the value being written.
+    depth: usize,
+}
+
+impl<'a, S: OutputStream> Dumper<'a, S> {
+    fn text_message(&mut self, msg: &MsgDef, obj: &MsgObj) -> Result<(), Error> {
+        for field in msg.fields {
+            let value = match field_value(obj, field) {
+                Some(value) => value,
+                None => continue,
+            };
+            match field.get_type() {
+                FieldType::Map(entry) => self.text_map(field, &entry, value)?,
+                _ if field.is_repeated() => {
+                    let mut iter_buf = IterBuf::new();
+                    for item in Iter::try_from_obj_with_buf(value, &mut iter_buf)? {
+                        self.text_field(field, item)?;
+                    }
+                }
+                _ => self.text_field(field, value)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the entries of map `field` as blocks of the key and the value.
+    fn text_map(&mut self, field: &FieldDef, entry: &MsgDef, value: Obj) -> Result<(), Error> {
+        let (key_field, value_field) = map_entry_fields(entry)?;
+        let dict = Gc::<Dict>::try_from(value)?;
+        let mut iter_buf = IterBuf::new();
+        for key in Iter::try_from_obj_with_buf(value, &mut iter_buf)? {
+            self.indent()?;
+            self.write_name(field)?;
+            self.stream.write(b" {\n")?;
+            self.depth += 1;
+            self.text_field(key_field, key)?;
+            let value = dict.map().get(key)?;
+            if value != Obj::const_none() {
+                self.text_field(value_field, value)?;
+            }
+            self.depth -= 1;
+            self.indent()?;
+            self.stream.write(b"}\n")?;
+        }
+        Ok(())
+    }
+
+    /// Write a single value of `field` on its own line, or as a block in case
+    /// of a sub-message.
+    fn text_field(&mut self, field: &FieldDef, value: Obj) -> Result<(), Error> {
+        self.indent()?;
+        self.write_name(field)?;
+        if let FieldType::Msg(msg) = field.get_type() {
+            let obj = Gc::<MsgObj>::try_from(value)?;
+            self.stream.write(b" {\n")?;
+            self.depth += 1;
+            self.text_message(&msg, &obj)?;
+            self.depth -= 1;
+            self.indent()?;
+            self.stream.write(b"}\n")
+        } else {
+            self.stream.write(b": ")?;
+            self.write_scalar(field, value)?;
+            self.stream.write_byte(b'\n')
+        }
+    }
+
+    fn json_message(&mut self, msg: &MsgDef, obj: &MsgObj) -> Result<(), Error> {
+        self.stream.write_byte(b'{')?;
+        let mut first = true;
+        for field in msg.fields {
+            let value = match field_value(obj, field) {
+                Some(value) => value,
+                None => continue,
+            };
+            self.json_separator(&mut first)?;
+            self.stream.write_byte(b'"')?;
+            self.write_name(field)?;
+            self.stream.write(b"\": ")?;
+            self.depth += 1;
+            match field.get_type() {
+                FieldType::Map(entry) => self.json_map(&entry, value)?,
+                _ if field.is_repeated() => {
+                    let mut iter_buf = IterBuf::new();
+                    let mut first_item = true;
+                    self.stream.write_byte(b'[')?;
+                    for item in Iter::try_from_obj_with_buf(value, &mut iter_buf)? {
+                        self.json_separator(&mut first_item)?;
+                        self.depth += 1;
+                        self.json_value(field, item)?;
+                        self.depth -= 1;
+                    }
+                    self.json_close(first_item, b']')?;
+                }
+                _ => self.json_value(field, value)?,
+            }
+            self.depth -= 1;
+        }
+        self.json_close(first, b'}')
+    }
+
+    /// Write the entries of a map as an object, with the keys converted to
+    /// strings.
+    fn json_map(&mut self, entry: &MsgDef, value: Obj) -> Result<(), Error> {
+        let (key_field, value_field) = map_entry_fields(entry)?;
+        let dict = Gc::<Dict>::try_from(value)?;
+        let mut iter_buf = IterBuf::new();
+        let mut first_entry = true;
+        self.stream.write_byte(b'{')?;
+        for key in Iter::try_from_obj_with_buf(value, &mut iter_buf)? {
+            self.json_separator(&mut first_entry)?;
+            // Object keys have to be strings.
+            if matches!(key_field.get_type(), FieldType::String) {
+                self.write_scalar(key_field, key)?;
+            } else {
+                self.stream.write_byte(b'"')?;
+                self.write_scalar(key_field, key)?;
+                self.stream.write_byte(b'"')?;
+            }
+            self.stream.write(b": ")?;
+            self.depth += 1;
+            self.json_value(value_field, dict.map().get(key)?)?;
+            self.depth -= 1;
+        }
+        self.json_close(first_entry, b'}')
+    }
+
+    fn json_value(&mut self, field: &FieldDef, value: Obj) -> Result<(), Error> {
+        match field.get_type() {
+            _ if value == Obj::const_none() => self.stream.write(b"null"),
+            FieldType::Msg(msg) => {
+                let obj = Gc::<MsgObj>::try_from(value)?;
+                self.json_message(&msg, &obj)
+            }
+            _ => self.write_scalar(field, value),
+        }
+    }
+
+    /// Start the next member of an array or an object on a new line.
+    fn json_separator(&mut self, first: &mut bool) -> Result<(), Error> {
+        if !*first {
+            self.stream.write_byte(b',')?;
+        }
+        *first = false;
+        self.stream.write_byte(b'\n')?;
+        self.depth += 1;
+        let result = self.indent();
+        self.depth -= 1;
+        result
+    }
+
+    /// Close an array or an object, empty ones stay on a single line.
+    fn json_close(&mut self, empty: bool, bracket: u8) -> Result<(), Error> {
+        if !empty {
+            self.stream.write_byte(b'\n')?;
+            self.indent()?;
+        }
+        self.stream.write_byte(bracket)
+    }
+
+    fn write_scalar(&mut self, field: &FieldDef, value: Obj) -> Result<(), Error> {
+        match field.get_type() {
+            FieldType::UVarInt | FieldType::Fixed32 | FieldType::Fixed64 => {
+                write_uint(self.stream, u64::try_from(value)?)
+            }
+            FieldType::SVarInt
+            | FieldType::SFixed32
+            | FieldType::SFixed64
+            | FieldType::Int32
+            | FieldType::Int64 => write_int(self.stream, i64::try_from(value)?),
+            FieldType::Bool => {
+                let text: &[u8] = if bool::try_from(value)? {
+                    b"true"
+                } else {
+                    b"false"
+                };
+                self.stream.write(text)
+            }
+            FieldType::Bytes => {
+                self.stream.write_byte(b'"')?;
+                for_each_buffer(value, |buf| write_hex(self.stream, buf))?;
+                self.stream.write_byte(b'"')
+            }
+            FieldType::String => {
+                self.stream.write_byte(b'"')?;
+                let format = self.format;
+                for_each_buffer(value, |buf| write_escaped(self.stream, buf, format))?;
+                self.stream.write_byte(b'"')
+            }
+            FieldType::Enum(enum_type) => {
+                let enum_val = u16::try_from(value)?;
+                match enum_type.name_of(enum_val) {
+                    Some(name) if self.format == Format::Json => {
+                        self.stream.write_byte(b'"')?;
+                        self.stream.write(Qstr::from(name).as_str().as_bytes())?;
+                        self.stream.write_byte(b'"')
+                    }
+                    Some(name) => self.stream.write(Qstr::from(name).as_str().as_bytes()),
+                    // Not a valid value, e.g. assigned from Python.
+                    None => write_uint(self.stream, enum_val.into()),
+                }
+            }
+            FieldType::Msg(_) | FieldType::Map(_) => Err(Error::TypeError),
+        }
+    }
+
+    fn write_name(&mut self, field: &FieldDef) -> Result<(), Error> {
+        self.stream
+            .write(Qstr::from(field.name).as_str().as_bytes())
+    }
+
+    fn indent(&mut self) -> Result<(), Error> {
+        for _ in 0..self.depth {
+            self.stream.write(b"  ")?;
+        }
+        Ok(())
+    }
+}
+
+/// Value of `field` in `obj`, or `None` if the field is not set. Repeated and
+/// map fields without any values count as not set.
+fn field_value(obj: &MsgObj, field: &FieldDef) -> Option<Obj> {
+    obj.map()
+        .get(Qstr::from(field.name))
+        .ok()
+        .filter(|&value| value != Obj::const_none() && !is_empty(field, value))
+}
+
+fn is_empty(field: &FieldDef, value: Obj) -> bool {
+    match field.get_type() {
+        FieldType::Map(_) => {
+            Gc::<Dict>::try_from(value).map_or(false, |dict| dict.map().len() == 0)
+        }
+        _ if field.is_repeated() => {
+            Gc::<List>::try_from(value).map_or(false, |list| list.len() == 0)
+        }
+        _ => false,
+    }
+}
+
+fn map_entry_fields(entry: &MsgDef) -> Result<(&FieldDef, &FieldDef), Error> {
+    let key_field = entry
+        .field(MAP_KEY_TAG)
+        .ok_or_else(|| Error::KeyError(MAP_KEY_TAG.into()))?;
+    let value_field = entry
+        .field(MAP_VALUE_TAG)
+        .ok_or_else(|| Error::KeyError(MAP_VALUE_TAG.into()))?;
+    Ok((key_field, value_field))
+}
+
+/// Pass the contents of a bytes or string value to `func`. Same as in the
+/// encoder, the value can also be a list of buffers to be concatenated.
+fn for_each_buffer(
+    value: Obj,
+    mut func: impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    if Gc::<List>::try_from(value).is_ok() {
+        let mut iter_buf = IterBuf::new();
+        for value in Iter::try_from_obj_with_buf(value, &mut iter_buf)? {
+            // SAFETY: buffer is dropped immediately.
+            func(unsafe { buffer::get_buffer(value) }?)?;
+        }
+        Ok(())
+    } else {
+        // SAFETY: buffer is dropped immediately.
+        func(unsafe { buffer::get_buffer(value) }?)
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn write_uint(stream: &mut impl OutputStream, mut num: u64) -> Result<(), Error> {
+    let mut digits = [0; 20];
+    let mut pos = digits.len();
+    loop {
+        pos -= 1;
+        digits[pos] = b'0' + (num % 10) as u8;
+        num /= 10;
+        if num == 0 {
+            break;
+        }
+    }
+    stream.write(&digits[pos..])
+}
+
+fn write_int(stream: &mut impl OutputStream, num: i64) -> Result<(), Error> {
+    if num < 0 {
+        stream.write_byte(b'-')?;
+    }
+    write_uint(stream, num.unsigned_abs())
+}
+
+fn write_hex(stream: &mut impl OutputStream, buf: &[u8]) -> Result<(), Error> {
+    for byte in buf {
+        stream.write_byte(HEX_DIGITS[(byte >> 4) as usize])?;
+        stream.write_byte(HEX_DIGITS[(byte & 0xF) as usize])?;
+    }
+    Ok(())
+}
+
+/// Write the contents of a string literal. Only quotes, backslashes and
+/// control characters are escaped, the rest of the UTF-8 is kept as is.
+fn write_escaped(stream: &mut impl OutputStream, buf: &[u8], format: Format) -> Result<(), Error> {
+    for &byte in buf {
+        match byte {
+            b'"' => stream.write(b"\\\"")?,
+            b'\\' => stream.write(b"\\\\")?,
+            b'\n' => stream.write(b"\\n")?,
+            b'\r' => stream.write(b"\\r")?,
+            b'\t' => stream.write(b"\\t")?,
+            0..=0x1F => {
+                match format {
+                    Format::Text => stream.write(b"\\x")?,
+                    Format::Json => stream.write(b"\\u00")?,
+                }
+                write_hex(stream, &[byte])?;
+            }
+            _ => stream.write_byte(byte)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::micropython::{buffer::StrBuffer, testutil::mpy_init};
+
+    use super::{super::testutil::*, *};
+
+    fn render(func: impl FnOnce(&mut BufferStream) -> Result<(), Error>) -> String {
+        let mut buf = [0; 1024];
+        let stream = &mut BufferStream::new(&mut buf);
+        func(stream).unwrap();
+        let len = stream.len();
+        String::from_utf8(buf[..len].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(render(|s| write_uint(s, 0)), "0");
+        assert_eq!(render(|s| write_uint(s, u64::MAX)), "18446744073709551615");
+        assert_eq!(render(|s| write_int(s, -42)), "-42");
+        assert_eq!(render(|s| write_int(s, i64::MIN)), "-9223372036854775808");
+        assert_eq!(render(|s| write_hex(s, b"\x00\xab\x0f")), "00ab0f");
+    }
+
+    #[test]
+    fn escaping() {
+        let text = "a\"b\\c\nd\x01é";
+        assert_eq!(
+            render(|s| write_escaped(s, text.as_bytes(), Format::Text)),
+            "a\\\"b\\\\c\\nd\\x01é"
+        );
+        assert_eq!(
+            render(|s| write_escaped(s, text.as_bytes(), Format::Json)),
+            "a\\\"b\\\\c\\nd\\u0001é"
+        );
+    }
+
+    #[test]
+    fn messages() {
+        unsafe { mpy_init() };
+
+        // Sub-messages, repeated fields, defaults and an enum.
+        let obj = decode(GET_ADDRESS, &get_address(1));
+        assert_eq!(
+            render(|s| dump(s, &obj, Format::Text)),
+            "address_n: 44\n\
+             coin_name: \"Bitcoin\"\n\
+             multisig {\n\
+             \x20 pubkeys {\n\
+             \x20   node {\n\
+             \x20     depth: 1\n\
+             \x20     fingerprint: 0\n\
+             \x20     child_num: 0\n\
+             \x20     chain_code: \"aaaaaaaa\"\n\
+             \x20     public_key: \"bbbbbbbb\"\n\
+             \x20   }\n\
+             \x20   address_n: 0\n\
+             \x20 }\n\
+             \x20 m: 2\n\
+             }\n\
+             script_type: SPENDADDRESS\n"
+        );
+        assert_eq!(
+            render(|s| dump(s, &obj, Format::Json)),
+            "{\n\
+             \x20 \"address_n\": [\n\
+             \x20   44\n\
+             \x20 ],\n\
+             \x20 \"coin_name\": \"Bitcoin\",\n\
+             \x20 \"multisig\": {\n\
+             \x20   \"pubkeys\": [\n\
+             \x20     {\n\
+             \x20       \"node\": {\n\
+             \x20         \"depth\": 1,\n\
+             \x20         \"fingerprint\": 0,\n\
+             \x20         \"child_num\": 0,\n\
+             \x20         \"chain_code\": \"aaaaaaaa\",\n\
+             \x20         \"public_key\": \"bbbbbbbb\"\n\
+             \x20       },\n\
+             \x20       \"address_n\": [\n\
+             \x20         0\n\
+             \x20       ]\n\
+             \x20     }\n\
+             \x20   ],\n\
+             \x20   \"m\": 2\n\
+             \x20 },\n\
+             \x20 \"script_type\": \"SPENDADDRESS\"\n\
+             }"
+        );
+    }
+
+    #[test]
+    fn empty_message() {
+        unsafe { mpy_init() };
+
+        let obj = decode(INITIALIZE, b"");
+        let dumped = |format| StrBuffer::try_from(dump_to_str(&obj, format).unwrap()).unwrap();
+        assert_eq!(&*dumped(Format::Text), "");
+        assert_eq!(&*dumped(Format::Json), "{}");
+    }
+
+    #[test]
+    fn maps() {
+        unsafe { mpy_init() };
+
+        // No real message has a map field, the entries are rendered directly.
+        let dict_with = |key: u8, value: Obj| {
+            let mut dict = Dict::alloc_with_capacity(1).unwrap();
+            // SAFETY: The dict was just allocated and is not aliased.
+            let map = unsafe { Gc::as_mut(&mut dict) }.map_mut();
+            map.set(key, value).unwrap();
+            Obj::from(dict)
+        };
+        let render_map = |format: Format, dict: Obj| {
+            render(|stream| {
+                let mut dumper = Dumper {
+                    stream,
+                    format,
+                    depth: 0,
+                };
+                match format {
+                    Format::Text => dumper.text_map(&MAP_FIELD, &map_entry(), dict),
+                    Format::Json => dumper.json_map(&map_entry(), dict),
+                }
+            })
+        };
+
+        let dict = dict_with(1, Obj::try_from(&b"ab"[..]).unwrap());
+        assert_eq!(
+            render_map(Format::Text, dict),
+            "dict {\n  key: 1\n  bytes: \"6162\"\n}\n"
+        );
+        assert_eq!(render_map(Format::Json, dict), "{\n  \"1\": \"6162\"\n}");
+
+        // Missing values are left out of the text format.
+        let dict = dict_with(2, Obj::const_none());
+        assert_eq!(render_map(Format::Text, dict), "dict {\n  key: 2\n}\n");
+        assert_eq!(render_map(Format::Json, dict), "{\n  \"2\": null\n}");
+    }
+}
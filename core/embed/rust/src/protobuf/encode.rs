@@ -1,9 +1,15 @@
-use core::convert::{TryFrom, TryInto};
+use core::{
+    cell::{Cell, RefCell},
+    convert::{TryFrom, TryInto},
+};
+
+use heapless::Vec;
 
 use crate::{
     error::Error,
     micropython::{
-        buffer,
+        buffer::{self, StrBuffer},
+        dict::Dict,
         gc::Gc,
         iter::{Iter, IterBuf},
         list::List,
@@ -14,9 +20,12 @@ use crate::{
 };
 
 use super::{
-    defs::{FieldDef, FieldType, MsgDef},
+    defs::{
+        FieldDef, FieldType, MsgDef, MAP_KEY_TAG, MAP_VALUE_TAG, PRIMITIVE_TYPE_LENGTH_DELIMITED,
+    },
     error,
     obj::MsgObj,
+    stream::{BufferStream, ChunkStream, CounterStream, OutputStream},
     zigzag,
 };
 
@@ -24,9 +33,8 @@ use super::{
 pub extern "C" fn protobuf_len(obj: Obj) -> Obj {
     let block = || {
         let obj = Gc::<MsgObj>::try_from(obj)?;
-        let stream = &mut CounterStream { len: 0 };
-        Encoder.encode_message(stream, &obj.def(), &obj)?;
-        stream.len.try_into()
+        let len = Encoder::memoized(&obj.def(), &obj)?.encoded_len();
+        len.try_into()
     };
     unsafe { util::try_or_raise(block) }
 }
@@ -44,16 +52,83 @@ pub extern "C" fn protobuf_encode(buf: Obj, obj: Obj) -> Obj {
         let buf = unsafe { buffer::get_buffer_mut(buf)? };
         let stream = &mut BufferStream::new(buf);
 
-        Encoder.encode_message(stream, &obj.def(), &obj)?;
+        Encoder::memoized(&obj.def(), &obj)?.encode_message(stream, &obj.def(), &obj)?;
 
         stream.len().try_into()
     };
     unsafe { util::try_or_raise(block) }
 }
 
-pub struct Encoder;
+/// Maximum number of sub-message lengths remembered by a memoized encoder.
+/// Lengths of any further sub-messages are computed on the fly.
+const MAX_MEMOIZED_LENGTHS: usize = 32;
+
+/// Source of sub-message lengths, needed for their length prefix.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LengthMode {
+    /// Count every sub-message by encoding it into `CounterStream`. Each
+    /// sub-message is walked once more per nesting level.
+    Count,
+    /// Measure the message in one pass, remembering the sub-message lengths
+    /// in the order they are encoded.
+    Record,
+    /// Take the sub-message lengths remembered in the `Record` pass.
+    Replay,
+}
+
+pub struct Encoder {
+    mode: Cell<LengthMode>,
+    lengths: RefCell<Vec<usize, MAX_MEMOIZED_LENGTHS>>,
+    cursor: Cell<usize>,
+    encoded_len: usize,
+}
 
 impl Encoder {
+    pub fn new() -> Self {
+        Self {
+            mode: Cell::new(LengthMode::Count),
+            lengths: RefCell::new(Vec::new()),
+            cursor: Cell::new(0),
+            encoded_len: 0,
+        }
+    }
+
+    /// Create an encoder for `obj` with the lengths of all its sub-messages
+    /// computed upfront in a single pass, so that the message can be encoded
+    /// without walking each sub-message twice per nesting level.
+    pub fn memoized(msg: &MsgDef, obj: &MsgObj) -> Result<Self, Error> {
+        let mut this = Self::new();
+        this.mode.set(LengthMode::Record);
+        this.encoded_len = this.message_len(msg, obj)?;
+        this.mode.set(LengthMode::Replay);
+        Ok(this)
+    }
+
+    /// Length of the whole message this encoder was memoized for.
+    pub fn encoded_len(&self) -> usize {
+        self.encoded_len
+    }
+
+    /// Calculate the message size by encoding it through `CounterStream`.
+    pub fn message_len(&self, msg: &MsgDef, obj: &MsgObj) -> Result<usize, Error> {
+        let counter = &mut CounterStream { len: 0 };
+        self.encode_message(counter, msg, obj)?;
+        Ok(counter.len)
+    }
+
+    /// Length of the next sub-message to be encoded.
+    fn sub_message_len(&self, msg: &MsgDef, obj: &MsgObj) -> Result<usize, Error> {
+        if self.mode.get() == LengthMode::Replay {
+            let cursor = self.cursor.get();
+            self.cursor.set(cursor + 1);
+            if let Some(len) = self.lengths.borrow().get(cursor) {
+                return Ok(*len);
+            }
+            // Not remembered, count it without touching the memoized lengths.
+            return Encoder::new().message_len(msg, obj);
+        }
+        self.message_len(msg, obj)
+    }
     pub fn encode_message(
         &self,
         stream: &mut impl OutputStream,
@@ -72,14 +147,40 @@ impl Encoder {
                 continue;
             }
 
-            let field_key = {
-                let prim_type = field.get_type().primitive_type();
-                let prim_type = prim_type as u64;
-                let field_tag = field.tag as u64;
-                field_tag << 3 | prim_type
-            };
+            let field_key = record_key(field);
 
-            if field.is_repeated() {
+            if let FieldType::Map(entry) = field.get_type() {
+                // Map field, every entry of the dict is a separate sub-message.
+                let dict = Gc::<Dict>::try_from(field_value)?;
+                let mut iter_buf = IterBuf::new();
+                let iter = Iter::try_from_obj_with_buf(field_value, &mut iter_buf)?;
+                for key in iter {
+                    let value = dict.map().get(key)?;
+                    stream.write_uvarint(field_key)?;
+                    self.encode_map_entry(stream, field, &entry, key, value)?;
+                }
+            } else if field.is_repeated() && field.is_packed() {
+                // Packed repeated field, all values are concatenated in a single
+                // length-delimited record. Calculate its size first, skip it
+                // completely if there are no values.
+                let mut iter_buf = IterBuf::new();
+                let counter = &mut CounterStream { len: 0 };
+                let iter = Iter::try_from_obj_with_buf(field_value, &mut iter_buf)?;
+                for iter_value in iter {
+                    self.encode_field(counter, field, iter_value)?;
+                }
+                if counter.len == 0 {
+                    continue;
+                }
+
+                let packed_key = (field.tag as u64) << 3 | PRIMITIVE_TYPE_LENGTH_DELIMITED as u64;
+                stream.write_uvarint(packed_key)?;
+                stream.write_uvarint(counter.len as u64)?;
+                let iter = Iter::try_from_obj_with_buf(field_value, &mut iter_buf)?;
+                for iter_value in iter {
+                    self.encode_field(stream, field, iter_value)?;
+                }
+            } else if field.is_repeated() {
                 let mut iter_buf = IterBuf::new();
                 let iter = Iter::try_from_obj_with_buf(field_value, &mut iter_buf)?;
                 for iter_value in iter {
@@ -92,6 +193,71 @@ impl Encoder {
             }
         }
 
+        // Re-emit the preserved unknown fields verbatim, in their original order.
+        let unknown_fields = obj.unknown_fields();
+        if unknown_fields != Obj::const_none() {
+            let mut iter_buf = IterBuf::new();
+            let iter = Iter::try_from_obj_with_buf(unknown_fields, &mut iter_buf)?;
+            for raw in iter {
+                // SAFETY: buffer is dropped immediately.
+                let raw = unsafe { buffer::get_buffer(raw) }?;
+                stream.write(raw)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encode a single map entry as a length-delimited sub-message, checking
+    /// that the type of `key` matches the entry definition.
+    fn encode_map_entry(
+        &self,
+        stream: &mut impl OutputStream,
+        field: &FieldDef,
+        entry: &MsgDef,
+        key: Obj,
+        value: Obj,
+    ) -> Result<(), Error> {
+        let key_field = entry
+            .field(MAP_KEY_TAG)
+            .ok_or_else(|| Error::KeyError(MAP_KEY_TAG.into()))?;
+        let value_field = entry
+            .field(MAP_VALUE_TAG)
+            .ok_or_else(|| Error::KeyError(MAP_VALUE_TAG.into()))?;
+        let key_matches = match key_field.get_type() {
+            FieldType::String => StrBuffer::try_from(key).is_ok(),
+            FieldType::Bool => key == Obj::const_true() || key == Obj::const_false(),
+            FieldType::UVarInt | FieldType::Fixed32 | FieldType::Fixed64 => {
+                u64::try_from(key).is_ok()
+            }
+            _ => i64::try_from(key).is_ok(),
+        };
+        if !key_matches {
+            return Err(error::invalid_map_key(field.name.into()));
+        }
+
+        // The entry length is counted by a separate encoder, so that the memoized
+        // lengths of sub-messages in values stay in the order they are encoded.
+        let counter = &mut CounterStream { len: 0 };
+        Encoder::new().encode_map_entry_fields(counter, key_field, key, value_field, value)?;
+        stream.write_uvarint(counter.len as u64)?;
+        self.encode_map_entry_fields(stream, key_field, key, value_field, value)
+    }
+
+    fn encode_map_entry_fields(
+        &self,
+        stream: &mut impl OutputStream,
+        key_field: &FieldDef,
+        key: Obj,
+        value_field: &FieldDef,
+        value: Obj,
+    ) -> Result<(), Error> {
+        stream.write_uvarint(record_key(key_field))?;
+        self.encode_field(stream, key_field, key)?;
+        if value != Obj::const_none() {
+            stream.write_uvarint(record_key(value_field))?;
+            self.encode_field(stream, value_field, value)?;
+        }
         Ok(())
     }
 
@@ -149,92 +315,204 @@ impl Encoder {
                     stream.write(buffer)?;
                 }
             }
+            FieldType::Msg(msg_type) if self.mode.get() == LengthMode::Record => {
+                let value = &Gc::<MsgObj>::try_from(value)?;
+                // Reserve the slot before measuring the sub-message, so that the
+                // lengths are stored in the order they are encoded.
+                let slot = {
+                    let mut lengths = self.lengths.borrow_mut();
+                    lengths.push(0).ok().map(|_| lengths.len() - 1)
+                };
+                let msg_len = self.message_len(&msg_type, value)?;
+                if let Some(slot) = slot {
+                    self.lengths.borrow_mut()[slot] = msg_len;
+                }
+
+                // Account for the message without walking it again.
+                stream.write_uvarint(msg_len as u64)?;
+                stream.skip(msg_len)?;
+            }
             FieldType::Msg(msg_type) => {
                 let value = &Gc::<MsgObj>::try_from(value)?;
-                // Calculate the message size by encoding it through `CountingWriter`.
-                let counter = &mut CounterStream { len: 0 };
-                self.encode_message(counter, &msg_type, value)?;
+                let msg_len = self.sub_message_len(&msg_type, value)?;
 
                 // Encode the message as length-delimited bytes.
-                stream.write_uvarint(counter.len as u64)?;
+                stream.write_uvarint(msg_len as u64)?;
                 self.encode_message(stream, &msg_type, value)?;
             }
+            FieldType::Fixed32 => {
+                let uint = u32::try_from(value)?;
+                stream.write_fixed32(uint)?;
+            }
+            FieldType::SFixed32 => {
+                let sint = i32::try_from(value)?;
+                stream.write_fixed32(sint as u32)?;
+            }
+            FieldType::Fixed64 => {
+                let uint = u64::try_from(value)?;
+                stream.write_fixed64(uint)?;
+            }
+            FieldType::SFixed64 => {
+                let sint = i64::try_from(value)?;
+                stream.write_fixed64(sint as u64)?;
+            }
+            FieldType::Int32 => {
+                // Negative values are sign-extended to 64 bits.
+                let int = i32::try_from(value)?;
+                stream.write_uvarint(int as i64 as u64)?;
+            }
+            FieldType::Int64 => {
+                let int = i64::try_from(value)?;
+                stream.write_uvarint(int as u64)?;
+            }
+            FieldType::Map(_) => {
+                // Map entries are encoded one by one in `encode_message`.
+                return Err(Error::TypeError);
+            }
         }
 
         Ok(())
     }
 }
 
-pub trait OutputStream {
-    fn write(&mut self, buf: &[u8]) -> Result<(), Error>;
-    fn write_byte(&mut self, val: u8) -> Result<(), Error>;
-
-    fn write_uvarint(&mut self, mut num: u64) -> Result<(), Error> {
-        loop {
-            let shifted = num >> 7;
-            let byte = (num & 0x7F) as u8;
-            if shifted != 0 {
-                num = shifted;
-                self.write_byte(byte | 0x80)?;
-            } else {
-                break self.write_byte(byte);
-            }
-        }
-    }
+/// Key of a field record, combining the field tag and the primitive type.
+fn record_key(field: &FieldDef) -> u64 {
+    let prim_type = field.get_type().primitive_type() as u64;
+    let field_tag = field.tag as u64;
+    field_tag << 3 | prim_type
 }
 
-pub struct CounterStream {
-    pub len: usize,
+/// Encode `obj` into chunks of `buf.len()` bytes, passing each of them to
+/// `sink`. Sub-message lengths are computed upfront in a single pass.
+pub fn encode_chunked(
+    obj: &MsgObj,
+    buf: &mut [u8],
+    sink: impl FnMut(&[u8]) -> Result<(), Error>,
+) -> Result<usize, Error> {
+    if buf.is_empty() {
+        return Err(error::end_of_buffer());
+    }
+    let msg = obj.def();
+    let encoder = Encoder::memoized(&msg, obj)?;
+    let stream = &mut ChunkStream::new(buf, sink);
+    encoder.encode_message(stream, &msg, obj)?;
+    stream.flush()?;
+    Ok(encoder.encoded_len())
 }
 
-impl OutputStream for CounterStream {
-    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
-        self.len += buf.len();
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use crate::micropython::testutil::mpy_init;
+
+    use super::{
+        super::{
+            stream::InputStream,
+            testutil::*,
+            visit::{read_value, Value},
+        },
+        *,
+    };
+
+    /// Encode `obj` the straightforward way, counting every sub-message.
+    fn encode_counted(obj: &MsgObj) -> std::vec::Vec<u8> {
+        let encoder = Encoder::new();
+        let mut buf = std::vec![0; encoder.message_len(&obj.def(), obj).unwrap()];
+        let stream = &mut BufferStream::new(&mut buf);
+        encoder.encode_message(stream, &obj.def(), obj).unwrap();
+        buf
     }
 
-    fn write_byte(&mut self, _val: u8) -> Result<(), Error> {
-        self.len += 1;
-        Ok(())
+    fn encode_chunks(obj: &MsgObj, chunk_size: usize) -> std::vec::Vec<u8> {
+        let mut buf = std::vec![0; chunk_size];
+        let mut encoded = std::vec::Vec::new();
+        let len = encode_chunked(obj, &mut buf, |chunk| {
+            assert!(!chunk.is_empty() && chunk.len() <= chunk_size);
+            encoded.extend(chunk);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(len, encoded.len());
+        encoded
     }
-}
 
-pub struct BufferStream<'a> {
-    buf: &'a mut [u8],
-    pos: usize,
-}
+    #[test]
+    fn memoized_lengths() {
+        unsafe { mpy_init() };
 
-impl<'a> BufferStream<'a> {
-    pub fn new(buf: &'a mut [u8]) -> Self {
-        Self { buf, pos: 0 }
+        // Sub-messages below, around and well above `MAX_MEMOIZED_LENGTHS`, the
+        // lengths that do not fit are counted on the fly.
+        for n in [1, 15, 16, 40] {
+            let obj = decode(GET_ADDRESS, &get_address(n));
+            let expected = encode_counted(&obj);
+            assert_eq!(encode(&obj), expected);
+            for chunk_size in [1, 7, 64, 4096] {
+                assert_eq!(encode_chunks(&obj, chunk_size), expected);
+            }
+        }
     }
 
-    pub fn len(&self) -> usize {
-        self.pos
-    }
-}
+    #[test]
+    fn signed_varints() {
+        unsafe { mpy_init() };
+
+        let int32 = FieldDef::scalar(1, 0, 11);
+        let int64 = FieldDef::scalar(1, 0, 12);
+        let encode_value = |field: &FieldDef, value: Obj| {
+            let mut buf = [0; 16];
+            let stream = &mut BufferStream::new(&mut buf);
+            Encoder::new().encode_field(stream, field, value)?;
+            let len = stream.len();
+            Ok::<_, Error>(buf[..len].to_vec())
+        };
 
-impl<'a> OutputStream for BufferStream<'a> {
-    fn write(&mut self, val: &[u8]) -> Result<(), Error> {
-        let pos = &mut self.pos;
-        let len = val.len();
-        self.buf
-            .get_mut(*pos..*pos + len)
-            .map(|buf| {
-                *pos += len;
-                buf.copy_from_slice(val);
-            })
-            .ok_or_else(error::end_of_buffer)
+        // Negative values take 10 bytes and decode back.
+        let minus_one = b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01";
+        let value = (-1_i32).try_into().unwrap();
+        assert_eq!(encode_value(&int32, value).unwrap(), minus_one);
+        assert_eq!(encode_value(&int64, value).unwrap(), minus_one);
+        let decoded = read_value(&mut InputStream::new(minus_one), &int32).unwrap();
+        assert_eq!(decoded, Value::Int(-1));
+
+        // Values out of the int32 range are refused.
+        let value = (1_i64 << 31).try_into().unwrap();
+        assert!(encode_value(&int32, value).is_err());
+        assert_eq!(
+            encode_value(&int64, value).unwrap(),
+            b"\x80\x80\x80\x80\x08"
+        );
+        let value = (i32::MIN as i64 - 1).try_into().unwrap();
+        assert!(encode_value(&int32, value).is_err());
     }
 
-    fn write_byte(&mut self, val: u8) -> Result<(), Error> {
-        let pos = &mut self.pos;
-        self.buf
-            .get_mut(*pos)
-            .map(|buf| {
-                *pos += 1;
-                *buf = val;
-            })
-            .ok_or_else(error::end_of_buffer)
+    #[test]
+    fn map_entries() {
+        unsafe { mpy_init() };
+
+        let entry = map_entry();
+        let encode_entry = |key: Obj, value: Obj| {
+            let mut buf = [0; 32];
+            let stream = &mut BufferStream::new(&mut buf);
+            Encoder::new().encode_map_entry(stream, &MAP_FIELD, &entry, key, value)?;
+            let len = stream.len();
+            Ok::<_, Error>(buf[..len].to_vec())
+        };
+        let value = Obj::try_from(&b"ab"[..]).unwrap();
+
+        // Unsigned keys cover the full uint64 range.
+        let key = u64::MAX.try_into().unwrap();
+        assert_eq!(
+            encode_entry(key, value).unwrap(),
+            b"\x0f\x08\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01\x12\x02ab"
+        );
+
+        // A `None` value is left out of the entry.
+        assert_eq!(
+            encode_entry(1_u8.into(), Obj::const_none()).unwrap(),
+            b"\x02\x08\x01"
+        );
+
+        // Keys not matching the key type are refused.
+        assert!(encode_entry((-1_i32).try_into().unwrap(), value).is_err());
+        assert!(encode_entry(Obj::try_from("a").unwrap(), value).is_err());
     }
 }
@@ -1,6 +1,9 @@
 use cstr_core::CStr;
 
-use crate::{error::Error, micropython::qstr::Qstr};
+use crate::error::Error;
+
+#[cfg(feature = "micropython")]
+use crate::micropython::qstr::Qstr;
 
 // XXX const version of `from_bytes_with_nul_unchecked` is nightly-only.
 
@@ -15,17 +18,96 @@ pub fn unknown_field_type() -> Error {
     Error::ValueError(msg)
 }
 
+#[cfg(feature = "micropython")]
 pub fn missing_required_field(field: Qstr) -> Error {
     let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Missing required field\0") };
     Error::ValueErrorParam(msg, field.into())
 }
 
+#[cfg(feature = "micropython")]
 pub fn invalid_value(field: Qstr) -> Error {
     let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Invalid value for field\0") };
     Error::ValueErrorParam(msg, field.into())
 }
 
+/// Message not matching its definition, for code that has no field at hand or
+/// cannot name it without MicroPython.
+pub fn invalid_message() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Invalid message.\0") };
+    Error::ValueError(msg)
+}
+
 pub fn end_of_buffer() -> Error {
     let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"End of buffer.\0") };
     Error::ValueError(msg)
 }
+
+pub fn max_depth_exceeded() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Maximum nesting depth exceeded.\0") };
+    Error::ValueError(msg)
+}
+
+#[cfg(feature = "micropython")]
+pub fn max_repeated_exceeded(field: Qstr) -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Too many values for field\0") };
+    Error::ValueErrorParam(msg, field.into())
+}
+
+pub fn max_alloc_exceeded() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Allocation limit exceeded.\0") };
+    Error::ValueError(msg)
+}
+
+pub fn unexpected_data() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Unexpected data after message.\0") };
+    Error::ValueError(msg)
+}
+
+pub fn invalid_magic() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Invalid magic.\0") };
+    Error::ValueError(msg)
+}
+
+pub fn truncated_frame() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Truncated frame.\0") };
+    Error::ValueError(msg)
+}
+
+#[cfg(feature = "micropython")]
+pub fn unknown_wire_id(wire_id: u16) -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Unknown wire type\0") };
+    Error::ValueErrorParam(msg, wire_id.into())
+}
+
+pub fn missing_wire_id() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Message has no wire type.\0") };
+    Error::ValueError(msg)
+}
+
+pub fn non_canonical_varint() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Non-canonical varint.\0") };
+    Error::ValueError(msg)
+}
+
+#[cfg(feature = "micropython")]
+pub fn duplicate_field(field: Qstr) -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Duplicate field\0") };
+    Error::ValueErrorParam(msg, field.into())
+}
+
+pub fn trailing_garbage() -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Trailing garbage after message.\0") };
+    Error::ValueError(msg)
+}
+
+#[cfg(feature = "micropython")]
+pub fn multiple_oneof_members(oneof: Qstr) -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Multiple members set in oneof\0") };
+    Error::ValueErrorParam(msg, oneof.into())
+}
+
+#[cfg(feature = "micropython")]
+pub fn invalid_map_key(field: Qstr) -> Error {
+    let msg = unsafe { CStr::from_bytes_with_nul_unchecked(b"Invalid key type for map field\0") };
+    Error::ValueErrorParam(msg, field.into())
+}
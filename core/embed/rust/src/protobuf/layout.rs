@@ -0,0 +1,41 @@
+//! Layout of the protobuf definitions blobs generated by `pb2py`.
+//!
+//! Shared by `defs.rs`, which reads the blobs at runtime, by `validate.rs`, and
+//! by the host-side code in `codegen/protobuf_types.rs` and
+//! `embed/rust/protobuf_defs`, which include this file as it is. Keep it in
+//! sync with the `c.Struct` definitions in `common/protob/pb2py`.
+
+/// Size of a packed field definition, see `FieldDef`.
+pub const FIELD_SIZE: usize = 7;
+/// Size of an entry of the names and wire lookup tables.
+pub const LOOKUP_SIZE: usize = 4;
+
+/// The flags live in the upper half of the `flags_and_type` byte of a field.
+pub const FLAGS_MASK: u8 = 0xF0;
+pub const TYPE_MASK: u8 = 0x0F;
+
+pub const FLAG_REQUIRED: u8 = 0b_1000_0000;
+pub const FLAG_REPEATED: u8 = 0b_0100_0000;
+pub const FLAG_EXPERIMENTAL: u8 = 0b_0010_0000;
+pub const FLAG_PACKED: u8 = 0b_0001_0000;
+
+pub const TYPE_UVARINT: u8 = 0;
+pub const TYPE_SVARINT: u8 = 1;
+pub const TYPE_BOOL: u8 = 2;
+pub const TYPE_BYTES: u8 = 3;
+pub const TYPE_STRING: u8 = 4;
+pub const TYPE_ENUM: u8 = 5;
+pub const TYPE_MSG: u8 = 6;
+pub const TYPE_FIXED32: u8 = 7;
+pub const TYPE_SFIXED32: u8 = 8;
+pub const TYPE_FIXED64: u8 = 9;
+pub const TYPE_SFIXED64: u8 = 10;
+pub const TYPE_INT32: u8 = 11;
+pub const TYPE_INT64: u8 = 12;
+pub const TYPE_MAP: u8 = 13;
+
+/// Set in the `flags_and_wire_id` of experimental messages.
+pub const MSG_EXPERIMENTAL: u16 = 0x8000;
+/// Mask of the wire ID in `flags_and_wire_id`, also used as the ID of
+/// messages that are never sent on the wire.
+pub const NO_WIRE_ID: u16 = 0x7FFF;
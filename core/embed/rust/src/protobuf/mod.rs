@@ -1,6 +1,26 @@
+#[cfg(feature = "micropython")]
 mod decode;
 mod defs;
+#[cfg(all(feature = "micropython", feature = "protobuf_debug"))]
+mod dump;
+#[cfg(feature = "micropython")]
 mod encode;
 mod error;
+mod layout;
+#[cfg(feature = "micropython")]
 mod obj;
+#[cfg(feature = "micropython")]
+mod path;
+mod stream;
+#[cfg(feature = "micropython")]
+mod streaming;
+#[cfg(all(test, feature = "micropython"))]
+mod testutil;
+#[cfg(feature = "protobuf_types")]
+mod types;
+#[cfg(test)]
+mod validate;
+mod visit;
+#[cfg(feature = "micropython")]
+mod wire;
 mod zigzag;
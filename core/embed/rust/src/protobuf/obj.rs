@@ -1,4 +1,4 @@
-use core::convert::TryFrom;
+use core::convert::{TryFrom, TryInto};
 
 use crate::{
     error::Error,
@@ -6,6 +6,7 @@ use crate::{
         dict::Dict,
         ffi,
         gc::Gc,
+        list::List,
         map::Map,
         obj::{Obj, ObjBase},
         qstr::Qstr,
@@ -19,12 +20,38 @@ use super::{
     defs::{find_name_by_msg_offset, get_msg, MsgDef},
 };
 
+#[cfg(feature = "protobuf_debug")]
+use super::defs::{FieldDef, FieldType};
+
+/// Decoded message instance, fields are kept in a map keyed by field name.
+///
+/// # Zero-copy bytes fields
+///
+/// When decoded by a `Decoder` in the zero-copy mode, bytes fields are not
+/// copied into new `bytes` objects, but are read-only `memoryview` objects
+/// pointing into the input buffer. The view does not keep the input buffer
+/// alive, because it may point into the middle of a GC block. Therefore:
+///
+/// - The input buffer has to stay reachable for as long as the message, or any
+///   bytes value taken out of it, is in use.
+/// - The input buffer must not be modified or reused in the meantime, i.e. the
+///   zero-copy mode is not suitable for the shared wire read buffer unless the
+///   message is consumed before the next read.
+/// - Encoding such a message is fine under the same conditions, the views are
+///   read through the buffer protocol.
+///
+/// Defaults of bytes fields point into the static definitions and are always
+/// valid.
 #[repr(C)]
 pub struct MsgObj {
     base: ObjBase,
     map: Map,
     msg_wire_id: Option<u16>,
     msg_offset: u16,
+    /// Encoded fields not present in the message definition, in the order they
+    /// were decoded. Either a list of bytes objects, or None if unknown fields
+    /// were not preserved.
+    unknown_fields: Obj,
 }
 
 impl MsgObj {
@@ -34,6 +61,7 @@ impl MsgObj {
             map: Map::with_capacity(capacity)?,
             msg_wire_id: msg.wire_id,
             msg_offset: msg.offset,
+            unknown_fields: Obj::const_none(),
         })
     }
 
@@ -49,6 +77,31 @@ impl MsgObj {
         unsafe { get_msg(self.msg_offset) }
     }
 
+    /// Name of the member of the oneof `group` that is set, i.e. is not
+    /// `None`. If several members were assigned from Python, the first one in
+    /// the definition is returned.
+    pub fn which_oneof(&self, group: u8) -> Option<Qstr> {
+        set_oneof_members(&self.def(), &self.map, group).next()
+    }
+
+    pub fn unknown_fields(&self) -> Obj {
+        self.unknown_fields
+    }
+
+    /// Keep the raw encoding of an unknown field, including its key, so that
+    /// it can be re-emitted by the encoder.
+    pub fn append_unknown_field(&mut self, raw: &[u8]) -> Result<(), Error> {
+        let raw = raw.try_into()?;
+        if self.unknown_fields == Obj::const_none() {
+            self.unknown_fields = List::alloc(&[raw])?.into();
+        } else {
+            let mut list = Gc::<List>::try_from(self.unknown_fields)?;
+            // SAFETY: The list is private to this message and not aliased.
+            unsafe { Gc::as_mut(&mut list) }.append(raw)?;
+        }
+        Ok(())
+    }
+
     fn obj_type() -> &'static Type {
         static TYPE: Type = obj_type! {
             name: Qstr::MP_QSTR_Msg,
@@ -85,7 +138,14 @@ impl MsgObj {
                 // we're returning a mutable dict.
                 Ok(Gc::new(Dict::with_map(self.map.try_clone()?))?.into())
             }
-            _ => Err(Error::AttributeError(attr)),
+            _ => {
+                // Name of a oneof group, return the name of the member that is set.
+                let group = self
+                    .def()
+                    .oneof_by_name(attr.to_u16())
+                    .ok_or(Error::AttributeError(attr))?;
+                Ok(self.which_oneof(group).into())
+            }
         }
     }
 
@@ -129,6 +189,18 @@ impl TryFrom<Obj> for Gc<MsgObj> {
     }
 }
 
+/// Names of the members of the oneof `group` of `msg` that are set in `map`,
+/// i.e. are not `None`, in the order of the definition.
+pub fn set_oneof_members<'a>(
+    msg: &'a MsgDef,
+    map: &'a Map,
+    group: u8,
+) -> impl Iterator<Item = Qstr> + 'a {
+    msg.oneof_members(group)
+        .map(|field| Qstr::from(field.name))
+        .filter(move |&name| matches!(map.get(name), Ok(value) if value != Obj::const_none()))
+}
+
 unsafe extern "C" fn msg_obj_attr(self_in: Obj, attr: ffi::qstr, dest: *mut Obj) {
     let block = || {
         let mut this = Gc::<MsgObj>::try_from(self_in)?;
@@ -169,6 +241,20 @@ impl MsgDefObj {
         &self.def
     }
 
+    /// Describe the fields of the message definition, as a list of dicts in
+    /// the order of the definition.
+    #[cfg(feature = "protobuf_debug")]
+    fn fields_info(&self) -> Result<Obj, Error> {
+        let decoder = Decoder::new(true);
+        let mut list = List::with_capacity(self.def.fields.len())?;
+        for field in self.def.fields {
+            let info = field_info(&decoder, &self.def, field)?;
+            // SAFETY: The list was just allocated and is not aliased.
+            unsafe { Gc::as_mut(&mut list) }.append(info)?;
+        }
+        Ok(list.into())
+    }
+
     fn obj_type() -> &'static Type {
         static TYPE: Type = obj_type! {
             name: Qstr::MP_QSTR_MsgDef,
@@ -230,6 +316,14 @@ unsafe extern "C" fn msg_def_obj_attr(self_in: Obj, attr: ffi::qstr, dest: *mut
                     dest.write(wire_id_obj);
                 };
             }
+            #[cfg(feature = "protobuf_debug")]
+            Qstr::MP_QSTR_MESSAGE_FIELDS => {
+                // Return the descriptions of the fields of this message def.
+                let fields = this.fields_info()?;
+                unsafe {
+                    dest.write(fields);
+                };
+            }
             Qstr::MP_QSTR_is_type_of => {
                 // Return the `is_type_of` bound method:
                 // dest[0] = function_obj
@@ -256,10 +350,10 @@ unsafe extern "C" fn msg_def_obj_call(
 ) -> Obj {
     let block = |_args: &[Obj], kwargs: &Map| {
         let this = Gc::<MsgDefObj>::try_from(self_in)?;
-        let decoder = Decoder {
-            enable_experimental: true,
-        };
-        let obj = decoder.message_from_values(kwargs, this.msg())?;
+        let decoder = Decoder::new(true);
+        let obj = decoder
+            .message_from_values(kwargs, this.msg())
+            .map_err(|err| decoder.error_with_path(err))?;
         Ok(obj)
     };
     unsafe { util::try_with_args_and_kwargs_inline(n_args, n_kw, args, block) }
@@ -277,6 +371,72 @@ unsafe extern "C" fn msg_def_obj_is_type_of(self_in: Obj, obj: Obj) -> Obj {
     unsafe { util::try_or_raise(block) }
 }
 
+/// Describe a single field as a dict with the `name`, `tag`, `type`,
+/// `required`, `repeated`, `experimental`, `packed`, `enum_values`,
+/// `msg_type`, `default` and `oneof` keys. Keys not relevant for the field are
+/// `None`.
+#[cfg(feature = "protobuf_debug")]
+fn field_info(decoder: &Decoder, msg: &MsgDef, field: &FieldDef) -> Result<Obj, Error> {
+    let field_type = field.get_type();
+    let type_name = field_type_name(&field_type);
+    let (enum_values, msg_type) = match field_type {
+        FieldType::Enum(enum_type) => {
+            let mut list = List::with_capacity(enum_type.values.len())?;
+            for value in enum_type.values.iter() {
+                // SAFETY: The list was just allocated and is not aliased.
+                unsafe { Gc::as_mut(&mut list) }.append(value.into())?;
+            }
+            (list.into(), Obj::const_none())
+        }
+        FieldType::Msg(msg_type) | FieldType::Map(msg_type) => {
+            (Obj::const_none(), MsgDefObj::alloc(msg_type)?.into())
+        }
+        _ => (Obj::const_none(), Obj::const_none()),
+    };
+    let default = decoder.default_value(msg, field)?;
+
+    let mut dict = Dict::alloc_with_capacity(11)?;
+    // SAFETY: The dict was just allocated and is not aliased.
+    let map = unsafe { Gc::as_mut(&mut dict) }.map_mut();
+    map.set(Qstr::MP_QSTR_name, Qstr::from(field.name))?;
+    map.set(Qstr::MP_QSTR_tag, field.tag)?;
+    map.set(Qstr::MP_QSTR_type, type_name)?;
+    map.set(Qstr::MP_QSTR_required, field.is_required())?;
+    map.set(Qstr::MP_QSTR_repeated, field.is_repeated())?;
+    map.set(Qstr::MP_QSTR_experimental, field.is_experimental())?;
+    map.set(Qstr::MP_QSTR_packed, field.is_packed())?;
+    map.set(Qstr::MP_QSTR_enum_values, enum_values)?;
+    map.set(Qstr::MP_QSTR_msg_type, msg_type)?;
+    map.set(Qstr::MP_QSTR_default, default)?;
+    let oneof = field
+        .oneof()
+        .and_then(|group| msg.oneofs.get(group as usize))
+        .map(Qstr::from);
+    map.set(Qstr::MP_QSTR_oneof, oneof)?;
+    Ok(dict.into())
+}
+
+/// Name of the field type, as reported by `MsgDef.MESSAGE_FIELDS`.
+#[cfg(feature = "protobuf_debug")]
+fn field_type_name(field_type: &FieldType) -> Qstr {
+    match field_type {
+        FieldType::UVarInt => Qstr::MP_QSTR_uvarint,
+        FieldType::SVarInt => Qstr::MP_QSTR_svarint,
+        FieldType::Bool => Qstr::MP_QSTR_bool,
+        FieldType::Bytes => Qstr::MP_QSTR_bytes,
+        FieldType::String => Qstr::MP_QSTR_string,
+        FieldType::Enum(_) => Qstr::MP_QSTR_enum,
+        FieldType::Msg(_) => Qstr::MP_QSTR_message,
+        FieldType::Fixed32 => Qstr::MP_QSTR_fixed32,
+        FieldType::SFixed32 => Qstr::MP_QSTR_sfixed32,
+        FieldType::Fixed64 => Qstr::MP_QSTR_fixed64,
+        FieldType::SFixed64 => Qstr::MP_QSTR_sfixed64,
+        FieldType::Int32 => Qstr::MP_QSTR_int32,
+        FieldType::Int64 => Qstr::MP_QSTR_int64,
+        FieldType::Map(_) => Qstr::MP_QSTR_map,
+    }
+}
+
 static MSG_DEF_OBJ_IS_TYPE_OF_OBJ: ffi::mp_obj_fun_builtin_fixed_t =
     obj_fn_2!(msg_def_obj_is_type_of);
 
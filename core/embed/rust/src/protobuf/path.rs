@@ -0,0 +1,133 @@
+use core::{
+    convert::TryFrom,
+    fmt::{self, Write},
+};
+
+use heapless::{String, Vec};
+
+use crate::{
+    error::Error,
+    micropython::{obj::Obj, qstr::Qstr},
+};
+
+use super::defs::find_name_by_msg_offset;
+
+/// Maximum number of tracked path segments. Deeper segments are elided.
+const MAX_SEGMENTS: usize = 24;
+
+/// Maximum length of the path rendered into a MicroPython string.
+const MAX_RENDERED_LEN: usize = 128;
+
+#[derive(Clone, Copy)]
+pub enum PathSegment {
+    /// Top-level message, identified by the offset of its definition.
+    Message(u16),
+    /// Singular field of the enclosing message.
+    Field(Qstr),
+    /// Element of a repeated field of the enclosing message.
+    Element(Qstr, usize),
+}
+
+/// Location of the value being decoded, i.e.
+/// `TxAckInput.tx.inputs[3].amount`.
+#[derive(Clone)]
+pub struct FieldPath {
+    segments: Vec<PathSegment, MAX_SEGMENTS>,
+    /// Number of pushed segments that did not fit into `segments`.
+    elided: usize,
+}
+
+impl FieldPath {
+    pub const fn new() -> Self {
+        Self {
+            segments: Vec::new(),
+            elided: 0,
+        }
+    }
+
+    pub fn push(&mut self, segment: PathSegment) {
+        if self.segments.push(segment).is_err() {
+            self.elided += 1;
+        }
+    }
+
+    pub fn pop(&mut self) {
+        if self.elided > 0 {
+            self.elided -= 1;
+        } else {
+            self.segments.pop();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+}
+
+impl fmt::Display for FieldPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PathSegment::Message(msg_offset) => match find_name_by_msg_offset(*msg_offset) {
+                    Some(name) => f.write_str(Qstr::from_u16(name).as_str())?,
+                    None => f.write_str("?")?,
+                },
+                PathSegment::Field(name) => {
+                    if i > 0 {
+                        f.write_str(".")?;
+                    }
+                    f.write_str(name.as_str())?;
+                }
+                PathSegment::Element(name, index) => {
+                    if i > 0 {
+                        f.write_str(".")?;
+                    }
+                    write!(f, "{}[{}]", name.as_str(), index)?;
+                }
+            }
+        }
+        if self.elided > 0 {
+            f.write_str("...")?;
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<&FieldPath> for Obj {
+    type Error = Error;
+
+    fn try_from(path: &FieldPath) -> Result<Self, Self::Error> {
+        let mut rendered = String::<MAX_RENDERED_LEN>::new();
+        // Overly long paths are truncated, the error can be ignored.
+        let _ = write!(rendered, "{}", path);
+        Obj::try_from(rendered.as_str())
+    }
+}
+
+/// Decoding error together with the location of the value at fault.
+pub struct PathError {
+    pub error: Error,
+    pub path: FieldPath,
+}
+
+impl From<PathError> for Error {
+    /// Value errors get the rendered path appended to their arguments, after
+    /// the message and the parameter of the original error. Other errors, and
+    /// errors without a path, are returned unchanged.
+    fn from(err: PathError) -> Self {
+        if err.path.is_empty() {
+            return err.error;
+        }
+        match (err.error, Obj::try_from(&err.path)) {
+            (Error::ValueError(msg), Ok(path)) => Error::ValueErrorParam(msg, path),
+            (Error::ValueErrorParam(msg, param), Ok(path)) => {
+                Error::ValueErrorParams(msg, param, path)
+            }
+            (error, _) => error,
+        }
+    }
+}
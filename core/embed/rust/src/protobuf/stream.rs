@@ -0,0 +1,284 @@
+use core::convert::TryInto;
+
+use crate::error::Error;
+
+use super::{defs, error};
+
+pub struct InputStream<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    /// Refuse varints that are not encoded in the minimal number of bytes.
+    canonical: bool,
+}
+
+impl<'a> InputStream<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            canonical: false,
+        }
+    }
+
+    /// Enable or disable the canonical checks, i.e. refuse non-minimal varints
+    /// and incomplete trailing keys. Streams split off by `read_stream` inherit
+    /// the setting.
+    pub fn set_canonical(&mut self, canonical: bool) {
+        self.canonical = canonical;
+    }
+
+    pub fn is_canonical(&self) -> bool {
+        self.canonical
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Return the part of the buffer consumed since position `start`.
+    pub fn consumed_since(&self, start: usize) -> &'a [u8] {
+        &self.buf[start..self.pos]
+    }
+
+    pub fn read_stream(&mut self, len: usize) -> Result<Self, Error> {
+        let buf = self.read(len)?;
+        let mut stream = Self::new(buf);
+        stream.canonical = self.canonical;
+        Ok(stream)
+    }
+
+    pub fn read(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let buf = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or_else(error::end_of_buffer)?;
+        self.pos += len;
+        Ok(buf)
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, Error> {
+        let val = self
+            .buf
+            .get(self.pos)
+            .copied()
+            .ok_or_else(error::end_of_buffer)?;
+        self.pos += 1;
+        Ok(val)
+    }
+
+    /// Return true if the rest of the stream contains a terminating varint
+    /// byte, i.e. `read_uvarint` does not run out of data.
+    pub fn has_complete_uvarint(&self) -> bool {
+        self.buf[self.pos..].iter().any(|byte| byte & 0x80 == 0)
+    }
+
+    pub fn read_uvarint(&mut self) -> Result<u64, Error> {
+        let mut uint = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.read_byte()?;
+            let bits = byte as u64 & 0x7F;
+            if shift >= 64 {
+                return Err(Error::OutOfRange);
+            }
+            if self.canonical {
+                // Bits not fitting into 64 bits, or a zero final byte following
+                // other bytes, mean that the value has a shorter encoding.
+                let overflows = shift > 0 && bits >> (64 - shift) != 0;
+                let padded = shift > 0 && byte == 0;
+                if overflows || padded {
+                    return Err(error::non_canonical_varint());
+                }
+            }
+            uint += bits << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(uint)
+    }
+
+    pub fn read_fixed32(&mut self) -> Result<u32, Error> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_fixed64(&mut self) -> Result<u64, Error> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Skip over a value of primitive type `prim_type`, without decoding it.
+    pub fn skip_value(&mut self, prim_type: u8) -> Result<(), Error> {
+        match prim_type {
+            defs::PRIMITIVE_TYPE_VARINT => {
+                self.read_uvarint()?;
+            }
+            defs::PRIMITIVE_TYPE_FIXED64 => {
+                self.read(8)?;
+            }
+            defs::PRIMITIVE_TYPE_LENGTH_DELIMITED => {
+                let len = self.read_uvarint()?.try_into()?;
+                self.read(len)?;
+            }
+            defs::PRIMITIVE_TYPE_FIXED32 => {
+                self.read(4)?;
+            }
+            _ => {
+                return Err(error::unknown_field_type());
+            }
+        }
+        Ok(())
+    }
+}
+
+pub trait OutputStream {
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error>;
+    fn write_byte(&mut self, val: u8) -> Result<(), Error>;
+
+    /// Account for `len` bytes without writing them. Only supported by streams
+    /// that merely measure the output.
+    fn skip(&mut self, _len: usize) -> Result<(), Error> {
+        Err(Error::TypeError)
+    }
+
+    fn write_uvarint(&mut self, mut num: u64) -> Result<(), Error> {
+        loop {
+            let shifted = num >> 7;
+            let byte = (num & 0x7F) as u8;
+            if shifted != 0 {
+                num = shifted;
+                self.write_byte(byte | 0x80)?;
+            } else {
+                break self.write_byte(byte);
+            }
+        }
+    }
+    fn write_fixed32(&mut self, num: u32) -> Result<(), Error> {
+        self.write(&num.to_le_bytes())
+    }
+
+    fn write_fixed64(&mut self, num: u64) -> Result<(), Error> {
+        self.write(&num.to_le_bytes())
+    }
+}
+
+pub struct CounterStream {
+    pub len: usize,
+}
+
+impl OutputStream for CounterStream {
+    fn write(&mut self, buf: &[u8]) -> Result<(), Error> {
+        self.len += buf.len();
+        Ok(())
+    }
+
+    fn write_byte(&mut self, _val: u8) -> Result<(), Error> {
+        self.len += 1;
+        Ok(())
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), Error> {
+        self.len += len;
+        Ok(())
+    }
+}
+
+pub struct BufferStream<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> BufferStream<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+impl<'a> OutputStream for BufferStream<'a> {
+    fn write(&mut self, val: &[u8]) -> Result<(), Error> {
+        let pos = &mut self.pos;
+        let len = val.len();
+        self.buf
+            .get_mut(*pos..*pos + len)
+            .map(|buf| {
+                *pos += len;
+                buf.copy_from_slice(val);
+            })
+            .ok_or_else(error::end_of_buffer)
+    }
+
+    fn write_byte(&mut self, val: u8) -> Result<(), Error> {
+        let pos = &mut self.pos;
+        self.buf
+            .get_mut(*pos)
+            .map(|buf| {
+                *pos += 1;
+                *buf = val;
+            })
+            .ok_or_else(error::end_of_buffer)
+    }
+}
+
+/// Output stream collecting the encoded bytes into `buf` and passing it to
+/// `sink` every time it gets full, i.e. to fill fixed-size transport reports.
+/// `flush` has to be called at the end to pass on the last, partial chunk.
+pub struct ChunkStream<'a, F>
+where
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    buf: &'a mut [u8],
+    pos: usize,
+    sink: F,
+}
+
+impl<'a, F> ChunkStream<'a, F>
+where
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    pub fn new(buf: &'a mut [u8], sink: F) -> Self {
+        Self { buf, pos: 0, sink }
+    }
+
+    /// Pass the buffered bytes to the sink, if there are any.
+    pub fn flush(&mut self) -> Result<(), Error> {
+        if self.pos > 0 {
+            (self.sink)(&self.buf[..self.pos])?;
+            self.pos = 0;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F> OutputStream for ChunkStream<'a, F>
+where
+    F: FnMut(&[u8]) -> Result<(), Error>,
+{
+    fn write(&mut self, mut val: &[u8]) -> Result<(), Error> {
+        while !val.is_empty() {
+            if self.pos == self.buf.len() {
+                self.flush()?;
+            }
+            let len = val.len().min(self.buf.len() - self.pos);
+            self.buf[self.pos..self.pos + len].copy_from_slice(&val[..len]);
+            self.pos += len;
+            val = &val[len..];
+        }
+        Ok(())
+    }
+
+    fn write_byte(&mut self, val: u8) -> Result<(), Error> {
+        self.write(&[val])
+    }
+}
@@ -0,0 +1,418 @@
+use core::convert::{TryFrom, TryInto};
+
+use heapless::Vec;
+
+use crate::{
+    error::Error,
+    micropython::{gc::Gc, obj::Obj},
+};
+
+use super::{
+    decode::Decoder,
+    defs::{self, FieldType, MsgDef},
+    error,
+    obj::MsgObj,
+    stream::InputStream,
+};
+
+/// Size of the transport chunks the decoder is usually fed with.
+pub const CHUNK_SIZE: usize = 64;
+
+/// Maximum number of messages being decoded at once, the top-level message
+/// included.
+const MAX_FRAMES: usize = 16;
+
+/// Field key followed by a varint, a fixed-size value or a length prefix.
+const MAX_HEAD_LEN: usize = 20;
+
+/// Message that is being decoded on one nesting level.
+struct Frame {
+    msg: MsgDef,
+    obj: Gc<MsgObj>,
+    /// Tag of the field of the parent message this message gets assigned to.
+    /// Unused for the top-level message.
+    tag: u8,
+    /// Number of encoded bytes of this message that were not consumed yet.
+    remaining: usize,
+}
+
+/// Interpretation of the beginning of a field record. `head` is the length of
+/// the key and the length prefix, `len` the length of what follows them.
+enum Head {
+    /// More bytes are needed to interpret the record.
+    Incomplete,
+    /// The complete record of `len` bytes is available.
+    Record { len: usize },
+    /// Key and length prefix of a sub-message field.
+    Message { tag: u8, head: usize, len: usize },
+    /// Key and length prefix of a record with a non-empty payload.
+    Payload { head: usize, len: usize },
+}
+
+/// Resumable decoder, fed with the encoded message in chunks of arbitrary
+/// size, i.e. as they arrive from the transport.
+///
+/// Field records are decoded by `Decoder` directly from the chunk containing
+/// them. Records split across chunks are collected in a buffer until complete,
+/// the buffer counts against the allocation limit of the decoder. Sub-messages
+/// are decoded in place without buffering, so that the result is the same
+/// `MsgObj` the one-shot `Decoder::message_from_stream` would produce.
+///
+/// The decoder holds references to objects on the MicroPython heap, and thus
+/// has to stay reachable by the garbage collector between the calls, i.e. be
+/// kept on the stack or inside a GC-allocated object. After an error is
+/// returned, the decoder has to be discarded.
+pub struct StreamingDecoder {
+    decoder: Decoder,
+    frames: Vec<Frame, MAX_FRAMES>,
+    /// Beginning of the record being read, up to the end of the value or of the
+    /// length prefix.
+    head: Vec<u8, MAX_HEAD_LEN>,
+    /// Length-delimited record being read, with the number of bytes filled.
+    body: Option<(Gc<[u8]>, usize)>,
+    result: Option<Obj>,
+}
+
+impl StreamingDecoder {
+    /// Start decoding a message of type `msg`, encoded in `len` bytes.
+    /// Experimental messages are not checked here, it is up to the caller to
+    /// refuse them.
+    /// Records are assembled in temporary buffers, so the zero-copy mode of
+    /// `decoder` is not supported and bytes fields are always copied.
+    pub fn new(mut decoder: Decoder, msg: MsgDef, len: usize) -> Result<Self, Error> {
+        decoder.zero_copy = false;
+        let obj = decoder.begin_message(&msg)?;
+        let mut this = Self {
+            decoder,
+            frames: Vec::new(),
+            head: Vec::new(),
+            body: None,
+            result: None,
+        };
+        let root = Frame {
+            msg,
+            obj,
+            tag: 0,
+            remaining: len,
+        };
+        if this.frames.push(root).is_err() {
+            return Err(error::max_depth_exceeded());
+        }
+        this.complete_frames()?;
+        Ok(this)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.result.is_some()
+    }
+
+    /// Return the decoded message, failing if not all of the encoded bytes
+    /// were fed.
+    pub fn finish(self) -> Result<Obj, Error> {
+        self.result.ok_or_else(error::end_of_buffer)
+    }
+
+    /// Decode the next part of the encoded message.
+    pub fn feed(&mut self, mut chunk: &[u8]) -> Result<(), Error> {
+        while !chunk.is_empty() {
+            if let Some(len) = self.feed_in_place(chunk)? {
+                chunk = &chunk[len..];
+                self.complete_frames()?;
+                continue;
+            }
+
+            let frame = self.frames.last_mut().ok_or_else(error::unexpected_data)?;
+            if let Some((buf, filled)) = &mut self.body {
+                // Copy as much of the payload as is available.
+                let len = chunk.len().min(buf.len() - *filled).min(frame.remaining);
+                // SAFETY: The buffer is private to this decoder.
+                let dest = unsafe { Gc::as_mut(buf) };
+                dest[*filled..*filled + len].copy_from_slice(&chunk[..len]);
+                *filled += len;
+                frame.remaining -= len;
+                chunk = &chunk[len..];
+
+                if *filled == buf.len() {
+                    decode_record(&self.decoder, frame, buf)?;
+                    self.body = None;
+                }
+            } else {
+                // The record continues in the next chunk, collect its head byte by
+                // byte, until we know how to continue.
+                self.head.push(chunk[0]).map_err(|_| Error::OutOfRange)?;
+                frame.remaining -= 1;
+                chunk = &chunk[1..];
+
+                match parse_head(&frame.msg, &self.head)? {
+                    Head::Incomplete => {}
+                    Head::Record { .. } => {
+                        decode_record(&self.decoder, frame, &self.head)?;
+                        self.head.clear();
+                    }
+                    Head::Payload { len, .. } => {
+                        if len > frame.remaining {
+                            return Err(error::end_of_buffer());
+                        }
+                        // The length is declared by the sender, check the allocation
+                        // limit before trusting it.
+                        let record_len = self.head.len() + len;
+                        self.decoder.charge_alloc(record_len)?;
+                        let mut buf = Gc::<[u8]>::new_bytes(record_len)?;
+                        // SAFETY: The buffer was just allocated and is not aliased.
+                        let dest = unsafe { Gc::as_mut(&mut buf) };
+                        dest[..self.head.len()].copy_from_slice(&self.head);
+                        self.body = Some((buf, self.head.len()));
+                        self.head.clear();
+                    }
+                    Head::Message { tag, len, .. } => {
+                        if len > frame.remaining {
+                            return Err(error::end_of_buffer());
+                        }
+                        frame.remaining -= len;
+                        self.head.clear();
+                        self.begin_frame(tag, len)?;
+                    }
+                }
+            }
+
+            self.complete_frames()?;
+        }
+        Ok(())
+    }
+
+    /// Decode the record starting at the beginning of `chunk` directly from it,
+    /// without buffering. Returns the number of bytes consumed, or `None` if the
+    /// record is not contained in `chunk` completely.
+    fn feed_in_place(&mut self, chunk: &[u8]) -> Result<Option<usize>, Error> {
+        if !self.head.is_empty() || self.body.is_some() {
+            return Ok(None);
+        }
+        let frame = self.frames.last_mut().ok_or_else(error::unexpected_data)?;
+        let available = &chunk[..chunk.len().min(frame.remaining)];
+        let consumed = match parse_head(&frame.msg, available)? {
+            Head::Record { len } => {
+                decode_record(&self.decoder, frame, &available[..len])?;
+                frame.remaining -= len;
+                len
+            }
+            Head::Payload { head, len } if len <= available.len() - head => {
+                decode_record(&self.decoder, frame, &available[..head + len])?;
+                frame.remaining -= head + len;
+                head + len
+            }
+            Head::Message { tag, head, len } if len <= frame.remaining - head => {
+                frame.remaining -= head + len;
+                self.begin_frame(tag, len)?;
+                head
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(consumed))
+    }
+
+    /// Start decoding a sub-message of field `tag` of the innermost message.
+    fn begin_frame(&mut self, tag: u8, len: usize) -> Result<(), Error> {
+        let parent = self.frames.last().ok_or_else(error::unexpected_data)?;
+        let field = parent
+            .msg
+            .field(tag)
+            .ok_or_else(|| Error::KeyError(tag.into()))?;
+        self.decoder.check_experimental(field)?;
+        let msg = match field.get_type() {
+            FieldType::Msg(msg) => msg,
+            _ => return Err(error::unknown_field_type()),
+        };
+        self.decoder.enter_field(field, &parent.obj)?;
+        let obj = self.decoder.begin_message(&msg)?;
+        let frame = Frame {
+            msg,
+            obj,
+            tag,
+            remaining: len,
+        };
+        if self.frames.push(frame).is_err() {
+            return Err(error::max_depth_exceeded());
+        }
+        Ok(())
+    }
+
+    /// Finish all messages that have been consumed completely, assigning them
+    /// into their parents.
+    fn complete_frames(&mut self) -> Result<(), Error> {
+        while let Some(frame) = self.frames.last() {
+            if frame.remaining > 0 || self.body.is_some() {
+                break;
+            }
+            if !self.head.is_empty() {
+                // The message ends in the middle of a record. Same as the one-shot
+                // decoder, we ignore an incomplete key unless in the strict mode, but
+                // fail on an incomplete value.
+                if InputStream::new(&self.head).read_uvarint().is_ok() {
+                    return Err(error::end_of_buffer());
+                }
+                if self.decoder.strict {
+                    return Err(error::trailing_garbage());
+                }
+                self.head.clear();
+            }
+
+            let mut frame = unwrap!(self.frames.pop());
+            // SAFETY: Message objects of unfinished frames are not shared.
+            let obj = unsafe { Gc::as_mut(&mut frame.obj) };
+            self.decoder.end_message(&frame.msg, obj)?;
+
+            match self.frames.last_mut() {
+                Some(parent) => {
+                    let field = parent
+                        .msg
+                        .field(frame.tag)
+                        .ok_or_else(|| Error::KeyError(frame.tag.into()))?;
+                    // SAFETY: Message objects of unfinished frames are not shared.
+                    let parent_obj = unsafe { Gc::as_mut(&mut parent.obj) };
+                    self.decoder.assign_field_into(
+                        &parent.msg,
+                        field,
+                        frame.obj.into(),
+                        parent_obj,
+                    )?;
+                    self.decoder.leave();
+                }
+                None => {
+                    self.result = Some(frame.obj.into());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Decode a complete field record into the message of `frame`.
+fn decode_record(decoder: &Decoder, frame: &mut Frame, record: &[u8]) -> Result<(), Error> {
+    // SAFETY: Message objects of unfinished frames are not shared.
+    let obj = unsafe { Gc::as_mut(&mut frame.obj) };
+    decoder.decode_fields_into(&mut InputStream::new(record), &frame.msg, obj)
+}
+
+/// Find out how much of a field record `head` contains, ignoring any bytes
+/// after the record. Records are shaped
+/// the same way the one-shot decoder reads them, i.e. based on the field
+/// definition if the field is known.
+fn parse_head(msg: &MsgDef, head: &[u8]) -> Result<Head, Error> {
+    let stream = &mut InputStream::new(head);
+    let field_key = match stream.read_uvarint() {
+        Ok(field_key) => field_key,
+        Err(_) => return Ok(Head::Incomplete),
+    };
+    let field_tag = u8::try_from(field_key >> 3)?;
+    let prim_type = u8::try_from(field_key & 7)?;
+
+    let field = msg.field(field_tag);
+    let field_type = field.map(|field| field.get_type());
+    let value_type = match (field, &field_type) {
+        (Some(field), Some(field_type))
+            if field.is_repeated()
+                && prim_type == defs::PRIMITIVE_TYPE_LENGTH_DELIMITED
+                && field_type.is_packable() =>
+        {
+            defs::PRIMITIVE_TYPE_LENGTH_DELIMITED
+        }
+        (_, Some(field_type)) => field_type.primitive_type(),
+        (_, None) => prim_type,
+    };
+
+    let complete = match value_type {
+        defs::PRIMITIVE_TYPE_VARINT => stream.read_uvarint().is_ok(),
+        defs::PRIMITIVE_TYPE_FIXED64 => stream.read(8).is_ok(),
+        defs::PRIMITIVE_TYPE_FIXED32 => stream.read(4).is_ok(),
+        defs::PRIMITIVE_TYPE_LENGTH_DELIMITED => match stream.read_uvarint() {
+            Ok(len) => {
+                let head = stream.position();
+                let len = len.try_into()?;
+                return Ok(match field_type {
+                    Some(FieldType::Msg(_)) => Head::Message {
+                        tag: field_tag,
+                        head,
+                        len,
+                    },
+                    _ if len == 0 => Head::Record { len: head },
+                    _ => Head::Payload { head, len },
+                });
+            }
+            Err(_) => false,
+        },
+        _ => return Err(error::unknown_field_type()),
+    };
+    Ok(if complete {
+        Head::Record {
+            len: stream.position(),
+        }
+    } else {
+        Head::Incomplete
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::micropython::testutil::mpy_init;
+
+    use super::{
+        super::{decode::DecodeLimits, testutil::*},
+        *,
+    };
+
+    fn decode_chunks<'a>(
+        decoder: Decoder,
+        wire_id: u16,
+        data: &[u8],
+        chunks: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<Obj, Error> {
+        let msg = MsgDef::for_wire_id(wire_id).unwrap();
+        let mut stream = StreamingDecoder::new(decoder, msg, data.len())?;
+        for chunk in chunks {
+            stream.feed(chunk)?;
+        }
+        stream.finish()
+    }
+
+    fn encode_obj(obj: Obj) -> std::vec::Vec<u8> {
+        encode(&Gc::<MsgObj>::try_from(obj).unwrap())
+    }
+
+    #[test]
+    fn split_at_every_offset() {
+        unsafe { mpy_init() };
+
+        let data = public_key();
+        let expected = encode(&decode(PUBLIC_KEY, &data));
+        assert_eq!(expected, data);
+
+        for split in 0..=data.len() {
+            let (head, tail) = data.split_at(split);
+            let obj = decode_chunks(Decoder::new(false), PUBLIC_KEY, &data, [head, tail]).unwrap();
+            assert_eq!(encode_obj(obj), expected, "split at {}", split);
+        }
+        let obj = decode_chunks(Decoder::new(false), PUBLIC_KEY, &data, data.chunks(1)).unwrap();
+        assert_eq!(encode_obj(obj), expected);
+    }
+
+    #[test]
+    fn alloc_limit() {
+        unsafe { mpy_init() };
+
+        // `SignMessage.message` declared to be 2000 bytes long.
+        let data = length_delimited(0x12, &[0; 2000]);
+        let limits = DecodeLimits {
+            max_alloc: 1024,
+            ..DecodeLimits::unlimited()
+        };
+
+        // The record does not fit, so its buffer is not even allocated.
+        let decoder = Decoder::new(false).with_limits(limits);
+        let msg = MsgDef::for_wire_id(SIGN_MESSAGE).unwrap();
+        let mut stream = StreamingDecoder::new(decoder, msg, data.len()).unwrap();
+        assert!(stream.feed(&data[..3]).is_err());
+
+        let decoder = Decoder::new(false);
+        assert!(decode_chunks(decoder, SIGN_MESSAGE, &data, data.chunks(CHUNK_SIZE)).is_ok());
+    }
+}
@@ -0,0 +1,101 @@
+//! Encoded messages shared by the tests of the MicroPython codec.
+
+use std::vec::Vec;
+
+use crate::micropython::{gc::Gc, qstr::Qstr};
+
+use super::{
+    decode::Decoder,
+    defs::{FieldDef, MsgDef, PackedU16Slice},
+    encode::Encoder,
+    obj::MsgObj,
+    stream::{BufferStream, InputStream},
+};
+
+pub const INITIALIZE: u16 = 0;
+pub const PUBLIC_KEY: u16 = 12;
+pub const GET_ADDRESS: u16 = 29;
+pub const SIGN_MESSAGE: u16 = 38;
+
+// Map entry with an uint64 `key` and a bytes value, named `bytes`.
+static MAP_ENTRY_FIELDS: [FieldDef; 2] = [
+    FieldDef::scalar(1, 0, 0).named(Qstr::MP_QSTR_key.to_u16()),
+    FieldDef::scalar(2, 0, 3).named(Qstr::MP_QSTR_bytes.to_u16()),
+];
+
+/// Map field named `dict`. Only its name is used when handling the entries
+/// directly, the entry definition is `map_entry()`.
+pub static MAP_FIELD: FieldDef = FieldDef::scalar(1, 0x40, 13).named(Qstr::MP_QSTR_dict.to_u16());
+
+pub fn map_entry() -> MsgDef {
+    MsgDef {
+        fields: &MAP_ENTRY_FIELDS,
+        oneofs: PackedU16Slice::new(&[]),
+        defaults: &[],
+        is_experimental: false,
+        wire_id: None,
+        offset: 0,
+    }
+}
+
+/// Record of a length-delimited field with the key byte `key`.
+pub fn length_delimited(key: u8, payload: &[u8]) -> Vec<u8> {
+    let mut record = std::vec![key];
+    let mut len = payload.len();
+    while len >= 0x80 {
+        record.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    record.push(len as u8);
+    record.extend(payload);
+    record
+}
+
+/// `PublicKey` with a nested `HDNodeType`, multi-byte varints and bytes fields
+/// longer than the head buffer of the streaming decoder.
+pub fn public_key() -> Vec<u8> {
+    let mut node = std::vec![0x08, 0x01, 0x10, 0xf8, 0xac, 0xd1, 0x91, 0x01];
+    node.extend([0x18, 0x80, 0x80, 0x80, 0x80, 0x08]);
+    node.extend(length_delimited(0x22, &[0xAA; 32]));
+    node.extend(length_delimited(0x32, &[0xBB; 33]));
+    let mut msg = length_delimited(0x0a, &node);
+    msg.extend(b"\x12\x04xpub\x18\x03");
+    msg
+}
+
+/// `GetAddress` with a multisig of `n` pubkeys, each of them a `HDNodeType`
+/// nested in a `HDNodePathType`, i.e. with `2 * n + 1` sub-messages.
+pub fn get_address(n: u8) -> Vec<u8> {
+    let mut multisig = Vec::new();
+    for i in 0..n {
+        let mut node = std::vec![0x08, 0x01, 0x10, i, 0x18, 0x00];
+        node.extend(length_delimited(0x22, &[0xAA; 4]));
+        node.extend(length_delimited(0x32, &[0xBB; 4]));
+        let mut pubkey = length_delimited(0x0a, &node);
+        pubkey.extend([0x10, i]);
+        multisig.extend(length_delimited(0x0a, &pubkey));
+    }
+    multisig.extend([0x18, 0x02]);
+    let mut msg = std::vec![0x08, 0x2c];
+    msg.extend(length_delimited(0x22, &multisig));
+    msg
+}
+
+/// Decode `data` as the message of type `wire_id` with the one-shot decoder.
+pub fn decode(wire_id: u16, data: &[u8]) -> Gc<MsgObj> {
+    let msg = MsgDef::for_wire_id(wire_id).unwrap();
+    let obj = Decoder::new(false)
+        .message_from_stream(&mut InputStream::new(data), &msg)
+        .unwrap();
+    Gc::try_from(obj).unwrap()
+}
+
+/// Encode `obj` the same way `protobuf_encode` does.
+pub fn encode(obj: &MsgObj) -> Vec<u8> {
+    let encoder = Encoder::memoized(&obj.def(), obj).unwrap();
+    let mut buf = std::vec![0; encoder.encoded_len()];
+    let stream = &mut BufferStream::new(&mut buf);
+    encoder.encode_message(stream, &obj.def(), obj).unwrap();
+    assert_eq!(stream.len(), buf.len());
+    buf
+}
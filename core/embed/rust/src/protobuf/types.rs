@@ -0,0 +1,229 @@
+//! Typed Rust counterparts of selected protobuf messages.
+//!
+//! The structs are generated by `codegen/protobuf_types.rs` from the same
+//! definitions blob the runtime decoder reads, for the messages listed in
+//! `PROTOBUF_TYPES` in `build.rs` and all messages they contain. Decoding goes
+//! through `visit_fields`, so no MicroPython objects are involved. Only built
+//! with the `protobuf_types` feature, the firmware does not use them yet.
+//!
+//! Field types follow the wire encoding, e.g. both `uint32` and `uint64` are
+//! `u64`, because the blob does not distinguish them. Optional fields are
+//! `Option`s unless they have a default value. Bytes and string fields borrow
+//! from the decoded buffer, repeated fields hold at most `MAX_REPEATED`
+//! values. Protobuf enums become newtypes over the numeric value. Oneof
+//! groups become Rust enums with a variant per member. Map fields are not
+//! supported.
+
+use heapless::Vec;
+
+use crate::error::Error;
+
+use super::{
+    defs::{self, FieldDef, MsgDef},
+    error,
+    stream::{BufferStream, CounterStream, InputStream, OutputStream},
+    visit::{self, Value, Visitor},
+};
+
+/// Maximum number of values of a repeated field of a generated message.
+pub const MAX_REPEATED: usize = 8;
+
+/// Newtype over the numeric value of a protobuf enum, only constructible from
+/// the values listed in the definition.
+macro_rules! protobuf_enum {
+    ($name:ident, [$($value:expr),*]) => {
+        #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+        pub struct $name(u16);
+
+        impl $name {
+            pub const VALUES: &'static [u16] = &[$($value),*];
+
+            pub fn value(self) -> u16 {
+                self.0
+            }
+        }
+
+        impl core::convert::TryFrom<u16> for $name {
+            type Error = Error;
+
+            fn try_from(value: u16) -> Result<Self, Self::Error> {
+                if Self::VALUES.contains(&value) {
+                    Ok(Self(value))
+                } else {
+                    Err(error::invalid_message())
+                }
+            }
+        }
+    };
+}
+
+include!(concat!(env!("OUT_DIR"), "/protobuf_types.rs"));
+
+pub trait Message<'a>: Default {
+    /// Offset of the message definition in the blob.
+    const DEF_OFFSET: u16;
+
+    /// Assign a decoded scalar value of field `tag`.
+    fn assign(&mut self, tag: u8, value: Value<'a>) -> Result<(), Error>;
+
+    /// Decode a sub-message of field `tag` of type `msg` from `stream` and
+    /// assign it.
+    fn assign_message(
+        &mut self,
+        tag: u8,
+        msg: &MsgDef,
+        stream: &mut InputStream<'a>,
+    ) -> Result<(), Error>;
+
+    /// Write all fields that have a value, ordered by their tags.
+    fn encode_fields(&self, stream: &mut impl OutputStream) -> Result<(), Error>;
+
+    fn def() -> MsgDef {
+        // SAFETY: The offset was taken right out of the definitions at build time.
+        unsafe { defs::get_msg(Self::DEF_OFFSET) }
+    }
+
+    fn decode(buf: &'a [u8]) -> Result<Self, Error> {
+        decode_message(&mut InputStream::new(buf), &Self::def())
+    }
+
+    fn encoded_len(&self) -> usize {
+        let counter = &mut CounterStream { len: 0 };
+        // Counting cannot fail, the error can be ignored.
+        let _ = self.encode_fields(counter);
+        counter.len
+    }
+
+    /// Encode the message into `buf`, returning the number of bytes written.
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let stream = &mut BufferStream::new(buf);
+        self.encode_fields(stream)?;
+        Ok(stream.len())
+    }
+}
+
+/// Decode a message of type `msg` from `stream`, failing if any of the
+/// required fields is missing. Experimental fields are not refused, and
+/// unknown fields are skipped.
+pub fn decode_message<'a, M: Message<'a>>(
+    stream: &mut InputStream<'a>,
+    msg: &MsgDef,
+) -> Result<M, Error> {
+    let mut builder = Builder {
+        message: M::default(),
+        seen: [0; 32],
+    };
+    visit::visit_fields(stream, msg, &mut builder)?;
+    for field in msg.fields {
+        if field.is_required() && !builder.has_seen(field.tag) {
+            return Err(missing_required_field(field));
+        }
+    }
+    Ok(builder.message)
+}
+
+/// Error for a missing required `field`. The field can be named only with
+/// MicroPython, which holds the qstr.
+#[cfg(feature = "micropython")]
+fn missing_required_field(field: &FieldDef) -> Error {
+    error::missing_required_field(field.name.into())
+}
+
+#[cfg(not(feature = "micropython"))]
+fn missing_required_field(_field: &FieldDef) -> Error {
+    error::invalid_message()
+}
+
+/// Visitor assigning the decoded fields into a generated message.
+struct Builder<M> {
+    message: M,
+    /// Bitmap of the tags of the decoded fields.
+    seen: [u8; 32],
+}
+
+impl<M> Builder<M> {
+    fn mark_seen(&mut self, tag: u8) {
+        self.seen[tag as usize / 8] |= 1 << (tag % 8);
+    }
+
+    fn has_seen(&self, tag: u8) -> bool {
+        self.seen[tag as usize / 8] & 1 << (tag % 8) != 0
+    }
+}
+
+impl<'a, M: Message<'a>> Visitor<'a> for Builder<M> {
+    fn visit_value(&mut self, field: &FieldDef, value: Value<'a>) -> Result<(), Error> {
+        self.mark_seen(field.tag);
+        self.message.assign(field.tag, value)
+    }
+
+    fn visit_message(
+        &mut self,
+        field: &FieldDef,
+        msg: &MsgDef,
+        stream: &mut InputStream<'a>,
+    ) -> Result<(), Error> {
+        self.mark_seen(field.tag);
+        self.message.assign_message(field.tag, msg, stream)
+    }
+}
+
+fn push<T>(values: &mut Vec<T, MAX_REPEATED>, value: T) -> Result<(), Error> {
+    values.push(value).map_err(|_| Error::OutOfRange)
+}
+
+fn write_buffer(stream: &mut impl OutputStream, buf: &[u8]) -> Result<(), Error> {
+    stream.write_uvarint(buf.len() as u64)?;
+    stream.write(buf)
+}
+
+fn write_message<'a>(
+    stream: &mut impl OutputStream,
+    message: &impl Message<'a>,
+) -> Result<(), Error> {
+    stream.write_uvarint(message.encoded_len() as u64)?;
+    message.encode_fields(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scalar_fields() {
+        let failure = Failure {
+            code: Some(FailureCode::try_from(7).unwrap()),
+            message: Some("abc"),
+        };
+        let mut buf = [0; 16];
+        let len = failure.encode(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"\x08\x07\x12\x03abc");
+        assert_eq!(failure.encoded_len(), len);
+        assert_eq!(Failure::decode(&buf[..len]).unwrap(), failure);
+        assert!(FailureCode::try_from(1000).is_err());
+    }
+
+    #[test]
+    fn default_and_required_fields() {
+        assert_eq!(Success::decode(b"").unwrap().message, "");
+        assert!(PinMatrixAck::decode(b"").is_err());
+        assert_eq!(PinMatrixAck::decode(b"\x0a\x041234").unwrap().pin, "1234");
+    }
+
+    #[test]
+    fn nested_message() {
+        let key = PublicKey {
+            node: HDNodeType {
+                depth: 1,
+                chain_code: &[0xAA; 4],
+                public_key: &[0xBB; 4],
+                ..HDNodeType::default()
+            },
+            xpub: "xpub",
+            root_fingerprint: None,
+        };
+        let mut buf = [0; 64];
+        let len = key.encode(&mut buf).unwrap();
+        assert_eq!(PublicKey::decode(&buf[..len]).unwrap(), key);
+    }
+}
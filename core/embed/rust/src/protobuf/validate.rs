@@ -0,0 +1,777 @@
+//! Validation and inspection of the protobuf definitions blobs.
+//!
+//! `get_msg` and `get_enum` read the blobs generated by `pb2py` without any
+//! checks. `validate` walks all of the tables and makes sure that every entry
+//! is in bounds, every reference points to the beginning of an entry, the
+//! lookup tables are sorted and agree with the messages, and the default
+//! values decode against their fields. `write_schema` prints the blobs as a
+//! readable schema.
+//!
+//! The module only depends on `core` and `layout.rs`, so that the host tool in
+//! `embed/rust/protobuf_defs` can include it as it is.
+
+use core::{fmt, str};
+
+use super::layout::{
+    FIELD_SIZE, FLAGS_MASK, FLAG_EXPERIMENTAL, FLAG_PACKED, FLAG_REPEATED, FLAG_REQUIRED,
+    LOOKUP_SIZE, MSG_EXPERIMENTAL, NO_WIRE_ID, TYPE_BOOL, TYPE_BYTES, TYPE_ENUM, TYPE_FIXED32,
+    TYPE_FIXED64, TYPE_INT32, TYPE_INT64, TYPE_MAP, TYPE_MASK, TYPE_MSG, TYPE_SFIXED32,
+    TYPE_SFIXED64, TYPE_STRING, TYPE_SVARINT, TYPE_UVARINT,
+};
+
+const TYPE_NAMES: [&str; 14] = [
+    "uvarint", "svarint", "bool", "bytes", "string", "enum", "message", "fixed32", "sfixed32",
+    "fixed64", "sfixed64", "int32", "int64", "map",
+];
+
+/// Contents of the `proto_*.data` files.
+#[derive(Clone, Copy)]
+pub struct Blobs<'a> {
+    pub enums: &'a [u8],
+    pub msgs: &'a [u8],
+    pub names: &'a [u8],
+    pub wire: &'a [u8],
+    /// The enum entries carry the qstr names of their values, only in debug
+    /// builds.
+    pub enum_names: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Table {
+    Enums,
+    Msgs,
+    Names,
+    Wire,
+}
+
+/// Inconsistency found in the blobs. `msg` and `offset` are offsets of entries
+/// in the respective table, `tag` identifies the field of the message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DefsError {
+    /// Entry starting at `offset` reaches past the end of the table.
+    Truncated { table: Table, offset: usize },
+    /// Lookup table length is not a multiple of the entry size.
+    InvalidLength { table: Table },
+    /// Values of an enum are not in ascending order.
+    UnsortedEnum { offset: usize },
+    /// Field tags of a message are zero, not ascending or not unique.
+    UnsortedFields { msg: usize, tag: u8 },
+    /// Unknown field type.
+    InvalidType { msg: usize, tag: u8 },
+    /// Combination of flags the codec does not handle, e.g. packed strings.
+    InvalidFlags { msg: usize, tag: u8 },
+    /// Enum or message type not pointing to the beginning of an entry.
+    InvalidReference { msg: usize, tag: u8 },
+    /// Map entry without a scalar key field or with a map value field.
+    InvalidMapEntry { msg: usize, tag: u8 },
+    /// Oneof group out of the range of the message groups.
+    InvalidOneof { msg: usize, tag: u8 },
+    /// Default value record not decoding against its field.
+    InvalidDefault { msg: usize, tag: u8 },
+    /// Lookup table keys are not in ascending order or not unique.
+    UnsortedLookup { table: Table, key: u16 },
+    /// Lookup table entry not pointing to the beginning of a message.
+    InvalidLookup { table: Table, key: u16 },
+    /// Message missing from the names table, or listed more than once.
+    MissingName { msg: usize },
+    /// Message wire ID not matching the wire table.
+    WireMismatch { msg: usize },
+    /// Writing the schema failed.
+    Output,
+}
+
+impl From<fmt::Error> for DefsError {
+    fn from(_: fmt::Error) -> Self {
+        Self::Output
+    }
+}
+
+/// Check the consistency of all of the tables.
+pub fn validate(blobs: &Blobs) -> Result<(), DefsError> {
+    // Walk the entries first, references can be checked only once all of them
+    // are known to be in bounds.
+    for entry in enums(blobs) {
+        let entry = entry?;
+        let mut values = entry.values();
+        if let Some(mut prev) = values.next() {
+            for value in values {
+                if value < prev {
+                    return Err(DefsError::UnsortedEnum {
+                        offset: entry.offset,
+                    });
+                }
+                prev = value;
+            }
+        }
+    }
+    for msg in msgs(blobs.msgs) {
+        msg?;
+    }
+
+    for msg in msgs(blobs.msgs) {
+        let msg = msg?;
+        validate_fields(blobs, &msg)?;
+        validate_defaults(blobs, &msg)?;
+    }
+
+    validate_lookup(blobs, Table::Names)?;
+    validate_lookup(blobs, Table::Wire)?;
+    for msg in msgs(blobs.msgs) {
+        let msg = msg?;
+        let named = lookup(blobs.names)
+            .filter(|&(_, offset)| offset as usize == msg.offset)
+            .count();
+        if named != 1 {
+            return Err(DefsError::MissingName { msg: msg.offset });
+        }
+        let wired = lookup(blobs.wire).find(|&(_, offset)| offset as usize == msg.offset);
+        if wired.map(|(wire_id, _)| wire_id) != msg.wire_id() {
+            return Err(DefsError::WireMismatch { msg: msg.offset });
+        }
+    }
+    Ok(())
+}
+
+fn validate_fields(blobs: &Blobs, msg: &MsgEntry) -> Result<(), DefsError> {
+    let oneofs_count = msg.oneofs.len() / 2;
+    let mut prev_tag = 0;
+    for field in msg.fields() {
+        let (msg, tag) = (msg.offset, field.tag);
+        if tag <= prev_tag {
+            return Err(DefsError::UnsortedFields { msg, tag });
+        }
+        prev_tag = tag;
+
+        if field.ftype as usize >= TYPE_NAMES.len() {
+            return Err(DefsError::InvalidType { msg, tag });
+        }
+        let repeated = field.has(FLAG_REPEATED);
+        let required = field.has(FLAG_REQUIRED);
+        let invalid_flags = (field.has(FLAG_PACKED) && !(repeated && field.is_packable()))
+            || (required && repeated)
+            || (field.ftype == TYPE_MAP && (repeated || required))
+            || (field.oneof > 0 && (repeated || required));
+        if invalid_flags {
+            return Err(DefsError::InvalidFlags { msg, tag });
+        }
+        if field.oneof as usize > oneofs_count {
+            return Err(DefsError::InvalidOneof { msg, tag });
+        }
+
+        let valid_reference = match field.ftype {
+            TYPE_ENUM => is_enum_start(blobs, field.reference),
+            TYPE_MSG | TYPE_MAP => is_msg_start(blobs, field.reference),
+            _ => true,
+        };
+        if !valid_reference {
+            return Err(DefsError::InvalidReference { msg, tag });
+        }
+        if field.ftype == TYPE_MAP {
+            let entry = MsgEntry::parse(blobs.msgs, field.reference as usize)?;
+            let key = entry.field(1).map(|key| key.ftype);
+            let value = entry.field(2).map(|value| value.ftype);
+            let valid_key = matches!(
+                key,
+                Some(TYPE_UVARINT..=TYPE_BOOL | TYPE_STRING | TYPE_FIXED32..=TYPE_INT64)
+            );
+            let valid_value = matches!(value, Some(ftype) if ftype != TYPE_MAP);
+            if !valid_key || !valid_value {
+                return Err(DefsError::InvalidMapEntry { msg, tag });
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_defaults(blobs: &Blobs, msg: &MsgEntry) -> Result<(), DefsError> {
+    let mut prev_tag = 0;
+    for record in defaults(blobs, msg) {
+        let (tag, _) = record?;
+        if tag <= prev_tag {
+            return Err(DefsError::InvalidDefault {
+                msg: msg.offset,
+                tag,
+            });
+        }
+        prev_tag = tag;
+    }
+    Ok(())
+}
+
+fn validate_lookup(blobs: &Blobs, table: Table) -> Result<(), DefsError> {
+    let buf = match table {
+        Table::Names => blobs.names,
+        _ => blobs.wire,
+    };
+    if buf.len() % LOOKUP_SIZE != 0 {
+        return Err(DefsError::InvalidLength { table });
+    }
+    let mut prev_key = None;
+    for (key, offset) in lookup(buf) {
+        if prev_key >= Some(key) {
+            return Err(DefsError::UnsortedLookup { table, key });
+        }
+        prev_key = Some(key);
+        if !is_msg_start(blobs, offset) {
+            return Err(DefsError::InvalidLookup { table, key });
+        }
+    }
+    Ok(())
+}
+
+/// Print all enums and messages of validated `blobs` into `out`, with the
+/// qstr names resolved by `qstr`.
+pub fn write_schema<'q>(
+    blobs: &Blobs,
+    qstr: impl Fn(u16) -> Option<&'q str>,
+    out: &mut impl fmt::Write,
+) -> Result<(), DefsError> {
+    let name = |id: u16| Name(qstr(id), id);
+    let msg_name = |offset: u16| {
+        lookup(blobs.names)
+            .find(|&(_, msg)| msg == offset)
+            .map_or(Name(None, 0), |(id, _)| name(id))
+    };
+
+    for entry in enums(blobs) {
+        let entry = entry?;
+        writeln!(out, "enum @{} {{", entry.offset)?;
+        let mut names = entry.names();
+        for value in entry.values() {
+            match names.next() {
+                Some(id) => writeln!(out, "    {} = {};", name(id), value)?,
+                None => writeln!(out, "    {};", value)?,
+            }
+        }
+        writeln!(out, "}}\n")?;
+    }
+
+    for msg in msgs(blobs.msgs) {
+        let msg = msg?;
+        write!(
+            out,
+            "message {} @{}",
+            msg_name(msg.offset as u16),
+            msg.offset
+        )?;
+        match (msg.wire_id(), msg.is_experimental()) {
+            (Some(wire_id), true) => write!(out, " [wire_id = {}, experimental]", wire_id)?,
+            (Some(wire_id), false) => write!(out, " [wire_id = {}]", wire_id)?,
+            (None, true) => write!(out, " [experimental]")?,
+            (None, false) => {}
+        }
+        writeln!(out, " {{")?;
+
+        for field in msg.fields() {
+            let type_name = TYPE_NAMES[field.ftype as usize];
+            if field.ftype == TYPE_MAP {
+                let entry = MsgEntry::parse(blobs.msgs, field.reference as usize)?;
+                let ftype = |tag| {
+                    entry
+                        .field(tag)
+                        .map_or("?", |f| TYPE_NAMES[f.ftype as usize])
+                };
+                write!(out, "    map<{}, {}>", ftype(1), ftype(2))?;
+            } else {
+                let label = if field.has(FLAG_REQUIRED) {
+                    "required"
+                } else if field.has(FLAG_REPEATED) {
+                    "repeated"
+                } else {
+                    "optional"
+                };
+                write!(out, "    {} ", label)?;
+                match field.ftype {
+                    TYPE_ENUM => write!(out, "enum@{}", field.reference)?,
+                    TYPE_MSG => write!(out, "{}", msg_name(field.reference))?,
+                    _ => write!(out, "{}", type_name)?,
+                }
+            }
+            write!(out, " {} = {}", name(field.name), field.tag)?;
+
+            let mut options = Options {
+                out: &mut *out,
+                empty: true,
+            };
+            if field.has(FLAG_PACKED) {
+                options.add(format_args!("packed"))?;
+            }
+            if field.has(FLAG_EXPERIMENTAL) {
+                options.add(format_args!("experimental"))?;
+            }
+            if let Some(group) = field.oneof.checked_sub(1) {
+                options.add(format_args!("oneof = {}", name(msg.oneof(group))))?;
+            }
+            for record in defaults(blobs, &msg) {
+                match record? {
+                    (tag, DefaultValue::Enum(value, Some(id))) if tag == field.tag => {
+                        options.add(format_args!("default = {} ({})", name(id), value))?
+                    }
+                    (tag, value) if tag == field.tag => {
+                        options.add(format_args!("default = {}", value))?
+                    }
+                    _ => {}
+                }
+            }
+            options.finish()?;
+        }
+        writeln!(out, "}}\n")?;
+    }
+    Ok(())
+}
+
+/// Qstr name, or its ID if the name is not known.
+struct Name<'q>(Option<&'q str>, u16);
+
+impl fmt::Display for Name<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(name) => f.write_str(name),
+            None => write!(f, "#{}", self.1),
+        }
+    }
+}
+
+/// Bracketed, comma-separated list of field options, ending the field line.
+struct Options<'o, W> {
+    out: &'o mut W,
+    empty: bool,
+}
+
+impl<'o, W: fmt::Write> Options<'o, W> {
+    fn add(&mut self, option: fmt::Arguments) -> fmt::Result {
+        let separator = if self.empty { " [" } else { ", " };
+        self.empty = false;
+        self.out.write_str(separator)?;
+        self.out.write_fmt(option)
+    }
+
+    fn finish(self) -> fmt::Result {
+        if !self.empty {
+            self.out.write_str("]")?;
+        }
+        self.out.write_str(";\n")
+    }
+}
+
+/// Default value of a field.
+enum DefaultValue<'a> {
+    UInt(u64),
+    Int(i64),
+    Bool(bool),
+    Bytes(&'a [u8]),
+    String(&'a str),
+    /// Value and its qstr name, if the blobs carry it.
+    Enum(u16, Option<u16>),
+}
+
+impl fmt::Display for DefaultValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UInt(num) => write!(f, "{}", num),
+            Self::Int(num) => write!(f, "{}", num),
+            Self::Bool(boolean) => write!(f, "{}", boolean),
+            Self::Bytes(bytes) => {
+                f.write_str("0x")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            Self::String(string) => write!(f, "{:?}", string),
+            Self::Enum(value, _) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// Records of the defaults stream of `msg`, as pairs of the field tag and the
+/// decoded value.
+fn defaults<'a>(
+    blobs: &'a Blobs,
+    msg: &'a MsgEntry,
+) -> impl Iterator<Item = Result<(u8, DefaultValue<'a>), DefsError>> + 'a {
+    let mut reader = Reader {
+        buf: msg.defaults,
+        pos: 0,
+    };
+    core::iter::from_fn(move || {
+        let tag = reader.byte()?;
+        let record = read_default(blobs, msg, tag, &mut reader).ok_or(DefsError::InvalidDefault {
+            msg: msg.offset,
+            tag,
+        });
+        if record.is_err() {
+            // Stop at the first invalid record, the rest cannot be parsed.
+            reader.pos = reader.buf.len();
+        }
+        Some(record.map(|value| (tag, value)))
+    })
+}
+
+fn read_default<'a>(
+    blobs: &Blobs,
+    msg: &MsgEntry,
+    tag: u8,
+    reader: &mut Reader<'a>,
+) -> Option<DefaultValue<'a>> {
+    let field = msg.field(tag)?;
+    if field.has(FLAG_REPEATED) || field.oneof > 0 {
+        return None;
+    }
+    Some(match field.ftype {
+        TYPE_UVARINT => DefaultValue::UInt(reader.uvarint()?),
+        TYPE_SVARINT => {
+            let num = reader.uvarint()?;
+            DefaultValue::Int((num >> 1) as i64 ^ -((num & 1) as i64))
+        }
+        TYPE_BOOL => match reader.uvarint()? {
+            0 => DefaultValue::Bool(false),
+            1 => DefaultValue::Bool(true),
+            _ => return None,
+        },
+        TYPE_BYTES | TYPE_STRING => {
+            let len = reader.uvarint()?;
+            let bytes = reader.take(usize::try_from(len).ok()?)?;
+            if field.ftype == TYPE_BYTES {
+                DefaultValue::Bytes(bytes)
+            } else {
+                DefaultValue::String(str::from_utf8(bytes).ok()?)
+            }
+        }
+        TYPE_ENUM => {
+            let value = u16::try_from(reader.uvarint()?).ok()?;
+            let entry = EnumEntry::parse(blobs, field.reference as usize).ok()?;
+            let index = entry.values().position(|v| v == value)?;
+            DefaultValue::Enum(value, entry.names().nth(index))
+        }
+        TYPE_FIXED32 => {
+            DefaultValue::UInt(u32::from_le_bytes(reader.take(4)?.try_into().ok()?).into())
+        }
+        TYPE_SFIXED32 => {
+            DefaultValue::Int(i32::from_le_bytes(reader.take(4)?.try_into().ok()?).into())
+        }
+        TYPE_FIXED64 => DefaultValue::UInt(u64::from_le_bytes(reader.take(8)?.try_into().ok()?)),
+        TYPE_SFIXED64 => DefaultValue::Int(i64::from_le_bytes(reader.take(8)?.try_into().ok()?)),
+        TYPE_INT32 => DefaultValue::Int(i32::try_from(reader.uvarint()? as i64).ok()?.into()),
+        TYPE_INT64 => DefaultValue::Int(reader.uvarint()? as i64),
+        _ => return None,
+    })
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn uvarint(&mut self) -> Option<u64> {
+        let mut uint = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            uint |= ((byte & 0x7F) as u64).checked_shl(shift)?;
+            if byte & 0x80 == 0 {
+                return Some(uint);
+            }
+        }
+        None
+    }
+}
+
+fn u16_values(bytes: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+}
+
+/// Entry of the enums table.
+struct EnumEntry<'a> {
+    offset: usize,
+    end: usize,
+    values: &'a [u8],
+    names: &'a [u8],
+}
+
+impl<'a> EnumEntry<'a> {
+    fn parse(blobs: &Blobs<'a>, offset: usize) -> Result<Self, DefsError> {
+        let reader = &mut Reader {
+            buf: blobs.enums,
+            pos: offset,
+        };
+        let truncated = DefsError::Truncated {
+            table: Table::Enums,
+            offset,
+        };
+        let count = reader.byte().ok_or(truncated)? as usize;
+        let values = reader.take(count * 2).ok_or(truncated)?;
+        let names = if blobs.enum_names {
+            reader.take(count * 2).ok_or(truncated)?
+        } else {
+            &[]
+        };
+        Ok(Self {
+            offset,
+            end: reader.pos,
+            values,
+            names,
+        })
+    }
+
+    fn values(&self) -> impl Iterator<Item = u16> + 'a {
+        u16_values(self.values)
+    }
+
+    /// Empty if the blobs do not carry the names.
+    fn names(&self) -> impl Iterator<Item = u16> + 'a {
+        u16_values(self.names)
+    }
+}
+
+/// Entry of the messages table.
+struct MsgEntry<'a> {
+    offset: usize,
+    end: usize,
+    flags_and_wire_id: u16,
+    fields: &'a [u8],
+    oneofs: &'a [u8],
+    defaults: &'a [u8],
+}
+
+impl<'a> MsgEntry<'a> {
+    fn parse(buf: &'a [u8], offset: usize) -> Result<Self, DefsError> {
+        let reader = &mut Reader { buf, pos: offset };
+        let truncated = DefsError::Truncated {
+            table: Table::Msgs,
+            offset,
+        };
+        let fields_count = reader.byte().ok_or(truncated)? as usize;
+        let defaults_size = reader.byte().ok_or(truncated)? as usize;
+        let flags_and_wire_id = reader.u16().ok_or(truncated)?;
+        let fields = reader.take(fields_count * FIELD_SIZE).ok_or(truncated)?;
+        let oneofs_count = reader.byte().ok_or(truncated)? as usize;
+        let oneofs = reader.take(oneofs_count * 2).ok_or(truncated)?;
+        let defaults = reader.take(defaults_size).ok_or(truncated)?;
+        Ok(Self {
+            offset,
+            end: reader.pos,
+            flags_and_wire_id,
+            fields,
+            oneofs,
+            defaults,
+        })
+    }
+
+    fn is_experimental(&self) -> bool {
+        self.flags_and_wire_id & MSG_EXPERIMENTAL != 0
+    }
+
+    fn wire_id(&self) -> Option<u16> {
+        match self.flags_and_wire_id & NO_WIRE_ID {
+            NO_WIRE_ID => None,
+            wire_id => Some(wire_id),
+        }
+    }
+
+    fn fields(&self) -> impl Iterator<Item = Field> + 'a {
+        self.fields.chunks_exact(FIELD_SIZE).map(|def| Field {
+            tag: def[0],
+            flags: def[1] & FLAGS_MASK,
+            ftype: def[1] & TYPE_MASK,
+            reference: u16::from_le_bytes([def[2], def[3]]),
+            name: u16::from_le_bytes([def[4], def[5]]),
+            oneof: def[6],
+        })
+    }
+
+    fn field(&self, tag: u8) -> Option<Field> {
+        self.fields().find(|field| field.tag == tag)
+    }
+
+    fn oneof(&self, group: u8) -> u16 {
+        u16_values(self.oneofs).nth(group as usize).unwrap_or(0)
+    }
+}
+
+/// Field definition, unpacked.
+struct Field {
+    tag: u8,
+    flags: u8,
+    ftype: u8,
+    /// Offset of the enum or message type.
+    reference: u16,
+    name: u16,
+    oneof: u8,
+}
+
+impl Field {
+    fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    fn is_packable(&self) -> bool {
+        !matches!(self.ftype, TYPE_BYTES | TYPE_STRING | TYPE_MSG | TYPE_MAP)
+    }
+}
+
+fn enums<'a>(blobs: &Blobs<'a>) -> impl Iterator<Item = Result<EnumEntry<'a>, DefsError>> {
+    let blobs = *blobs;
+    entries(
+        blobs.enums,
+        move |_, offset| EnumEntry::parse(&blobs, offset),
+        |entry| entry.end,
+    )
+}
+
+fn msgs<'a>(buf: &'a [u8]) -> impl Iterator<Item = Result<MsgEntry<'a>, DefsError>> {
+    entries(buf, MsgEntry::parse, |entry| entry.end)
+}
+
+/// Consecutive entries of a table, ending after the first error.
+fn entries<'a, T: 'a>(
+    buf: &'a [u8],
+    parse: impl Fn(&'a [u8], usize) -> Result<T, DefsError> + 'a,
+    end: fn(&T) -> usize,
+) -> impl Iterator<Item = Result<T, DefsError>> + 'a {
+    let mut offset = 0;
+    core::iter::from_fn(move || {
+        if offset >= buf.len() {
+            return None;
+        }
+        let entry = parse(buf, offset);
+        offset = entry.as_ref().map_or(buf.len(), end);
+        Some(entry)
+    })
+}
+
+fn is_enum_start(blobs: &Blobs, offset: u16) -> bool {
+    enums(blobs).any(|entry| matches!(entry, Ok(entry) if entry.offset == offset as usize))
+}
+
+fn is_msg_start(blobs: &Blobs, offset: u16) -> bool {
+    msgs(blobs.msgs).any(|entry| matches!(entry, Ok(entry) if entry.offset == offset as usize))
+}
+
+/// Pairs of the key and the message offset of a lookup table.
+fn lookup(buf: &[u8]) -> impl Iterator<Item = (u16, u16)> + '_ {
+    buf.chunks_exact(LOOKUP_SIZE).map(|entry| {
+        (
+            u16::from_le_bytes([entry[0], entry[1]]),
+            u16::from_le_bytes([entry[2], entry[3]]),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENUMS: &[u8] = b"\x02\x01\x00\x02\x00\x0a\x00\x0b\x00";
+    // Message with an enum field 1 defaulting to 2, and a uvarint field 2.
+    const MSGS: &[u8] = b"\x02\x02\x05\x00\
+        \x01\x05\x00\x00\x14\x00\x00\
+        \x02\x00\x00\x00\x15\x00\x00\
+        \x00\
+        \x01\x02";
+    const NAMES: &[u8] = b"\x1e\x00\x00\x00";
+    const WIRE: &[u8] = b"\x05\x00\x00\x00";
+
+    fn blobs<'a>(msgs: &'a [u8], names: &'a [u8]) -> Blobs<'a> {
+        Blobs {
+            enums: ENUMS,
+            msgs,
+            names,
+            wire: WIRE,
+            enum_names: true,
+        }
+    }
+
+    fn with_byte(buf: &[u8], pos: usize, byte: u8) -> Vec<u8> {
+        let mut buf = buf.to_vec();
+        buf[pos] = byte;
+        buf
+    }
+
+    #[test]
+    fn valid_defs() {
+        let blobs = blobs(MSGS, NAMES);
+        assert_eq!(validate(&blobs), Ok(()));
+
+        let qstr = |id| match id {
+            10 => Some("First"),
+            20 => Some("kind"),
+            30 => Some("Thing"),
+            _ => None,
+        };
+        let mut schema = String::new();
+        write_schema(&blobs, qstr, &mut schema).unwrap();
+        assert_eq!(
+            schema,
+            "enum @0 {\n    First = 1;\n    #11 = 2;\n}\n\n\
+             message Thing @0 [wire_id = 5] {\n    \
+             optional enum@0 kind = 1 [default = #11 (2)];\n    \
+             optional uvarint #21 = 2;\n}\n\n"
+        );
+
+        // Release builds leave out the names of the enum values.
+        let blobs = Blobs {
+            enums: &ENUMS[..5],
+            enum_names: false,
+            ..blobs
+        };
+        assert_eq!(validate(&blobs), Ok(()));
+        let mut schema = String::new();
+        write_schema(&blobs, qstr, &mut schema).unwrap();
+        assert!(schema.starts_with("enum @0 {\n    1;\n    2;\n}\n\n"));
+        assert!(schema.contains("kind = 1 [default = 2];"));
+    }
+
+    #[test]
+    fn invalid_defs() {
+        let validate_msgs = |msgs: &[u8]| validate(&blobs(msgs, NAMES));
+        assert_eq!(
+            validate_msgs(&MSGS[..MSGS.len() - 1]),
+            Err(DefsError::Truncated {
+                table: Table::Msgs,
+                offset: 0
+            })
+        );
+        // Enum reference into the middle of the entry.
+        assert_eq!(
+            validate_msgs(&with_byte(MSGS, 6, 1)),
+            Err(DefsError::InvalidReference { msg: 0, tag: 1 })
+        );
+        // Default value not in the enum.
+        assert_eq!(
+            validate_msgs(&with_byte(MSGS, MSGS.len() - 1, 3)),
+            Err(DefsError::InvalidDefault { msg: 0, tag: 1 })
+        );
+        // Duplicate field tag.
+        assert_eq!(
+            validate_msgs(&with_byte(MSGS, 11, 1)),
+            Err(DefsError::UnsortedFields { msg: 0, tag: 1 })
+        );
+        // Packed singular field.
+        assert_eq!(
+            validate_msgs(&with_byte(MSGS, 12, 0x10)),
+            Err(DefsError::InvalidFlags { msg: 0, tag: 2 })
+        );
+        assert_eq!(
+            validate(&blobs(MSGS, b"\x1e\x00\x01\x00")),
+            Err(DefsError::InvalidLookup {
+                table: Table::Names,
+                key: 30
+            })
+        );
+    }
+}
@@ -0,0 +1,351 @@
+//! Walking encoded messages without the MicroPython heap.
+//!
+//! `visit_fields` reads the field records of a message according to its
+//! `MsgDef` and reports the decoded values to a `Visitor`. Nothing is
+//! allocated, values borrow from the input buffer, so the walker can be used
+//! from Rust code without the `micropython` feature. The MicroPython decoder
+//! building `MsgObj` instances is one of the visitors.
+
+use core::{
+    convert::{TryFrom, TryInto},
+    str,
+};
+
+use crate::error::Error;
+
+use super::{
+    defs::{self, FieldDef, FieldType, MsgDef},
+    error,
+    stream::InputStream,
+    zigzag,
+};
+
+/// Decoded value of a scalar field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value<'a> {
+    /// `uint32`, `uint64`, `fixed32` and `fixed64` fields.
+    UInt(u64),
+    /// `sint32`, `sint64`, `sfixed32`, `sfixed64`, `int32` and `int64` fields.
+    Int(i64),
+    Bool(bool),
+    /// Borrowed from the input buffer.
+    Bytes(&'a [u8]),
+    /// Borrowed from the input buffer, already checked to be valid UTF-8.
+    String(&'a str),
+    /// Already checked to be one of the values of the enum type.
+    Enum(u16),
+}
+
+/// Receiver of the fields read by `visit_fields`.
+///
+/// Each value is wrapped in a `enter_field` and `leave_field` pair. For
+/// repeated fields, this happens once per element, also for packed ones.
+pub trait Visitor<'a> {
+    /// The next value of `field` is about to be read.
+    fn enter_field(&mut self, _field: &FieldDef) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// The value of `field` was processed.
+    fn leave_field(&mut self, _field: &FieldDef) {}
+
+    /// Scalar value of `field` was read.
+    fn visit_value(&mut self, field: &FieldDef, value: Value<'a>) -> Result<(), Error>;
+
+    /// Sub-message of `field`, either a message or a map entry, is encoded in
+    /// `stream`. Implementations usually call `visit_fields` with a visitor
+    /// for the sub-message, or skip the stream by ignoring it. The walker does
+    /// not limit the nesting depth, that is up to the visitor.
+    fn visit_message(
+        &mut self,
+        field: &FieldDef,
+        msg: &MsgDef,
+        stream: &mut InputStream<'a>,
+    ) -> Result<(), Error>;
+
+    /// Field record with a tag missing from the message definition was
+    /// skipped. `raw` is its complete encoding, key included.
+    fn visit_unknown(&mut self, _raw: &'a [u8]) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Read the field records of a message of type `msg` from `stream`, passing
+/// them to `visitor` in the order of the encoding.
+///
+/// An incomplete key at the end of the stream is ignored, unless the stream
+/// is in the canonical mode, see `InputStream::set_canonical`. Missing,
+/// default and duplicate fields are not checked, it is up to the visitor.
+pub fn visit_fields<'a, V>(
+    stream: &mut InputStream<'a>,
+    msg: &MsgDef,
+    visitor: &mut V,
+) -> Result<(), Error>
+where
+    V: Visitor<'a> + ?Sized,
+{
+    // Loop, trying to read the field key that contains the tag and primitive value
+    // type. If we fail to read the key, we are at the end of the stream. In the
+    // canonical mode, only a clean end of the stream is accepted.
+    loop {
+        let field_start = stream.position();
+        if stream.is_canonical() {
+            if stream.is_empty() {
+                break;
+            }
+            if !stream.has_complete_uvarint() {
+                return Err(error::trailing_garbage());
+            }
+        }
+        let field_key = match stream.read_uvarint() {
+            Ok(field_key) => field_key,
+            Err(err) if stream.is_canonical() => return Err(err),
+            Err(_) => break,
+        };
+        let field_tag = u8::try_from(field_key >> 3)?;
+        let prim_type = u8::try_from(field_key & 7)?;
+
+        match msg.field(field_tag) {
+            Some(field)
+                if field.is_repeated()
+                    && prim_type == defs::PRIMITIVE_TYPE_LENGTH_DELIMITED
+                    && field.get_type().is_packable() =>
+            {
+                // Packed repeated field, all values are concatenated in a single
+                // length-delimited record.
+                let packed_len = stream.read_uvarint()?.try_into()?;
+                let packed_stream = &mut stream.read_stream(packed_len)?;
+                while !packed_stream.is_empty() {
+                    visitor.enter_field(field)?;
+                    let value = read_value(packed_stream, field)?;
+                    visitor.visit_value(field, value)?;
+                    visitor.leave_field(field);
+                }
+            }
+            Some(field) => {
+                visitor.enter_field(field)?;
+                match field.get_type() {
+                    FieldType::Msg(msg_type) | FieldType::Map(msg_type) => {
+                        let msg_len = stream.read_uvarint()?.try_into()?;
+                        let sub_stream = &mut stream.read_stream(msg_len)?;
+                        visitor.visit_message(field, &msg_type, sub_stream)?;
+                    }
+                    _ => {
+                        let value = read_value(stream, field)?;
+                        visitor.visit_value(field, value)?;
+                    }
+                }
+                visitor.leave_field(field);
+            }
+            None => {
+                // Unknown field, skip it and hand over the raw encoding.
+                stream.skip_value(prim_type)?;
+                visitor.visit_unknown(stream.consumed_since(field_start))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read one scalar value of `field` from `stream`. Sub-messages are not
+/// scalar, see `Visitor::visit_message`.
+pub fn read_value<'a>(stream: &mut InputStream<'a>, field: &FieldDef) -> Result<Value<'a>, Error> {
+    match field.get_type() {
+        FieldType::UVarInt => {
+            let num = stream.read_uvarint()?;
+            Ok(Value::UInt(num))
+        }
+        FieldType::SVarInt => {
+            let num = stream.read_uvarint()?;
+            Ok(Value::Int(zigzag::to_signed(num)))
+        }
+        FieldType::Bool => {
+            let num = stream.read_uvarint()?;
+            Ok(Value::Bool(num != 0))
+        }
+        FieldType::Bytes => {
+            let buf_len = stream.read_uvarint()?.try_into()?;
+            let buf = stream.read(buf_len)?;
+            Ok(Value::Bytes(buf))
+        }
+        FieldType::String => {
+            let buf_len = stream.read_uvarint()?.try_into()?;
+            let buf = stream.read(buf_len)?;
+            let unicode = str::from_utf8(buf).map_err(|_| invalid_value(field))?;
+            Ok(Value::String(unicode))
+        }
+        FieldType::Enum(enum_type) => {
+            let enum_val = stream.read_uvarint()?.try_into()?;
+            if enum_type.values.contains(enum_val) {
+                Ok(Value::Enum(enum_val))
+            } else {
+                Err(invalid_value(field))
+            }
+        }
+        FieldType::Msg(_) | FieldType::Map(_) => Err(error::unknown_field_type()),
+        FieldType::Fixed32 => {
+            let num = stream.read_fixed32()?;
+            Ok(Value::UInt(num.into()))
+        }
+        FieldType::SFixed32 => {
+            let num = stream.read_fixed32()? as i32;
+            Ok(Value::Int(num.into()))
+        }
+        FieldType::Fixed64 => {
+            let num = stream.read_fixed64()?;
+            Ok(Value::UInt(num))
+        }
+        FieldType::SFixed64 => {
+            let num = stream.read_fixed64()? as i64;
+            Ok(Value::Int(num))
+        }
+        FieldType::Int32 => {
+            let num = stream.read_uvarint()? as i64;
+            let int = i32::try_from(num)?;
+            Ok(Value::Int(int.into()))
+        }
+        FieldType::Int64 => {
+            let num = stream.read_uvarint()? as i64;
+            Ok(Value::Int(num))
+        }
+    }
+}
+
+/// Error for a value that does not fit the type of `field`. The field can be
+/// named only with MicroPython, which holds the qstr.
+#[cfg(feature = "micropython")]
+fn invalid_value(field: &FieldDef) -> Error {
+    error::invalid_value(field.name.into())
+}
+
+#[cfg(not(feature = "micropython"))]
+fn invalid_value(_field: &FieldDef) -> Error {
+    error::invalid_message()
+}
+
+#[cfg(test)]
+mod tests {
+    use heapless::Vec;
+
+    use super::{super::defs::PackedU16Slice, *};
+
+    const REPEATED: u8 = 0b_0100_0000;
+    const INT32: u8 = 11;
+    const INT64: u8 = 12;
+
+    static FIELDS: [FieldDef; 3] = [
+        FieldDef::scalar(1, REPEATED, 0),
+        FieldDef::scalar(2, 0, 4),
+        FieldDef::scalar(3, 0, 1),
+    ];
+
+    fn msg() -> MsgDef {
+        MsgDef {
+            fields: &FIELDS,
+            oneofs: PackedU16Slice::new(&[]),
+            defaults: &[],
+            is_experimental: false,
+            wire_id: None,
+            offset: 0,
+        }
+    }
+
+    #[derive(Default)]
+    struct Recorder<'a> {
+        values: Vec<(u8, Value<'a>), 8>,
+        unknown: Vec<&'a [u8], 8>,
+        depth: usize,
+    }
+
+    impl<'a> Visitor<'a> for Recorder<'a> {
+        fn enter_field(&mut self, _field: &FieldDef) -> Result<(), Error> {
+            self.depth += 1;
+            Ok(())
+        }
+
+        fn leave_field(&mut self, _field: &FieldDef) {
+            self.depth -= 1;
+        }
+
+        fn visit_value(&mut self, field: &FieldDef, value: Value<'a>) -> Result<(), Error> {
+            assert_eq!(self.depth, 1);
+            self.values.push((field.tag, value)).unwrap();
+            Ok(())
+        }
+
+        fn visit_message(
+            &mut self,
+            _field: &FieldDef,
+            _msg: &MsgDef,
+            _stream: &mut InputStream<'a>,
+        ) -> Result<(), Error> {
+            unreachable!()
+        }
+
+        fn visit_unknown(&mut self, raw: &'a [u8]) -> Result<(), Error> {
+            self.unknown.push(raw).unwrap();
+            Ok(())
+        }
+    }
+
+    fn visit(buf: &[u8], canonical: bool) -> Result<Recorder<'_>, Error> {
+        let mut recorder = Recorder::default();
+        let stream = &mut InputStream::new(buf);
+        stream.set_canonical(canonical);
+        visit_fields(stream, &msg(), &mut recorder)?;
+        Ok(recorder)
+    }
+
+    #[test]
+    fn visit_scalars() {
+        let buf = b"\x08\x01\x0a\x03\x02\x96\x01\x12\x02hi\x18\x03\x20\x07";
+        let recorder = visit(buf, false).unwrap();
+        assert_eq!(
+            recorder.values,
+            [
+                (1, Value::UInt(1)),
+                (1, Value::UInt(2)),
+                (1, Value::UInt(150)),
+                (2, Value::String("hi")),
+                (3, Value::Int(-2)),
+            ]
+        );
+        assert_eq!(recorder.depth, 0);
+        assert_eq!(recorder.unknown, [&b"\x20\x07"[..]]);
+    }
+
+    #[test]
+    fn visit_canonical() {
+        // An incomplete trailing key is only refused in the canonical mode.
+        assert!(visit(b"\x08\x01\x80", false).is_ok());
+        assert!(visit(b"\x08\x01\x80", true).is_err());
+        // Same for varints with a redundant final byte.
+        assert!(visit(b"\x08\x81\x00", false).is_ok());
+        assert!(visit(b"\x08\x81\x00", true).is_err());
+    }
+
+    #[test]
+    fn read_signed_varints() {
+        let read = |ftype: u8, buf: &'static [u8]| {
+            read_value(&mut InputStream::new(buf), &FieldDef::scalar(1, 0, ftype))
+        };
+        // Negative values are sign-extended to 10 bytes, same for both types.
+        let minus_one = b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01";
+        assert_eq!(read(INT32, minus_one).unwrap(), Value::Int(-1));
+        assert_eq!(read(INT64, minus_one).unwrap(), Value::Int(-1));
+        assert_eq!(read(INT32, b"\x96\x01").unwrap(), Value::Int(150));
+        // 2^31 does not fit into int32.
+        assert!(read(INT32, b"\x80\x80\x80\x80\x08").is_err());
+        assert_eq!(
+            read(INT64, b"\x80\x80\x80\x80\x08").unwrap(),
+            Value::Int(1 << 31)
+        );
+    }
+
+    #[test]
+    fn visit_invalid() {
+        assert!(visit(b"\x12\x02\xff\xfe", false).is_err());
+        assert!(visit(b"\x12\x05hi", false).is_err());
+        assert!(visit(b"\x08", false).is_err());
+    }
+}
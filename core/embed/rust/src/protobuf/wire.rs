@@ -0,0 +1,286 @@
+//! Framing of messages into the v1 transport packets, see `trezor.wire.codec_v1`.
+//!
+//! The initial packet starts with the `?##` magic, followed by the big-endian
+//! 2-byte wire type and 4-byte data length. Continuation packets only start
+//! with the `?` marker. The last packet is padded with zeros.
+
+use core::convert::{TryFrom, TryInto};
+
+use crate::{error::Error, micropython::obj::Obj};
+
+use super::{
+    decode::{DecodeLimits, Decoder},
+    defs::MsgDef,
+    encode::Encoder,
+    error,
+    obj::MsgObj,
+    stream::OutputStream,
+    streaming::StreamingDecoder,
+};
+
+pub const REPORT_LEN: usize = 64;
+
+const REPORT_MARKER: u8 = b'?';
+const REPORT_MAGIC: u8 = b'#';
+/// Offset of data in the initial packet.
+const REPORT_INIT_DATA: usize = 9;
+/// Offset of data in a continuation packet.
+const REPORT_CONT_DATA: usize = 1;
+
+/// Encode `obj` and split it into transport packets, passing each of them to
+/// `sink`. Fails if the message type does not have a wire type assigned.
+pub fn encode_packets(
+    obj: &MsgObj,
+    sink: impl FnMut(&[u8; REPORT_LEN]) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let msg = obj.def();
+    let wire_id = msg.wire_id.ok_or_else(error::missing_wire_id)?;
+    let encoder = Encoder::memoized(&msg, obj)?;
+    let len = u32::try_from(encoder.encoded_len())?;
+
+    let stream = &mut PacketStream::new(wire_id, len, sink);
+    encoder.encode_message(stream, &msg, obj)?;
+    stream.flush()
+}
+
+/// Output stream filling transport packets, starting with the header of the
+/// initial packet.
+struct PacketStream<F>
+where
+    F: FnMut(&[u8; REPORT_LEN]) -> Result<(), Error>,
+{
+    report: [u8; REPORT_LEN],
+    pos: usize,
+    sink: F,
+}
+
+impl<F> PacketStream<F>
+where
+    F: FnMut(&[u8; REPORT_LEN]) -> Result<(), Error>,
+{
+    fn new(wire_id: u16, len: u32, sink: F) -> Self {
+        let mut report = [0; REPORT_LEN];
+        report[0] = REPORT_MARKER;
+        report[1] = REPORT_MAGIC;
+        report[2] = REPORT_MAGIC;
+        report[3..5].copy_from_slice(&wire_id.to_be_bytes());
+        report[5..9].copy_from_slice(&len.to_be_bytes());
+        Self {
+            report,
+            pos: REPORT_INIT_DATA,
+            sink,
+        }
+    }
+
+    /// Emit the current packet, zero-padded, and start a continuation packet.
+    fn flush(&mut self) -> Result<(), Error> {
+        self.report[self.pos..].fill(0);
+        (self.sink)(&self.report)?;
+        self.report[0] = REPORT_MARKER;
+        self.pos = REPORT_CONT_DATA;
+        Ok(())
+    }
+}
+
+impl<F> OutputStream for PacketStream<F>
+where
+    F: FnMut(&[u8; REPORT_LEN]) -> Result<(), Error>,
+{
+    fn write(&mut self, mut val: &[u8]) -> Result<(), Error> {
+        while !val.is_empty() {
+            if self.pos == REPORT_LEN {
+                self.flush()?;
+            }
+            let len = val.len().min(REPORT_LEN - self.pos);
+            self.report[self.pos..self.pos + len].copy_from_slice(&val[..len]);
+            self.pos += len;
+            val = &val[len..];
+        }
+        Ok(())
+    }
+
+    fn write_byte(&mut self, val: u8) -> Result<(), Error> {
+        self.write(&[val])
+    }
+}
+
+/// Reassembles incoming transport packets into a typed message.
+///
+/// Same as `StreamingDecoder`, the reassembler has to stay reachable by the
+/// garbage collector while the packets are being fed.
+pub struct PacketDecoder {
+    decoder: Option<Decoder>,
+    stream: Option<StreamingDecoder>,
+    /// Number of message bytes not received yet.
+    remaining: usize,
+}
+
+impl PacketDecoder {
+    /// The packets come from the host, so `DecodeLimits::WIRE` replaces the
+    /// limits of `decoder`.
+    pub fn new(decoder: Decoder) -> Self {
+        Self {
+            decoder: Some(decoder.with_limits(DecodeLimits::WIRE)),
+            stream: None,
+            remaining: 0,
+        }
+    }
+
+    /// Wire type and length of the message, read from the initial packet.
+    pub fn parse_header(packet: &[u8]) -> Result<(u16, usize), Error> {
+        if packet.len() < REPORT_INIT_DATA {
+            return Err(error::truncated_frame());
+        }
+        if packet[0] != REPORT_MARKER || packet[1] != REPORT_MAGIC || packet[2] != REPORT_MAGIC {
+            return Err(error::invalid_magic());
+        }
+        let wire_id = u16::from_be_bytes([packet[3], packet[4]]);
+        let len = u32::from_be_bytes([packet[5], packet[6], packet[7], packet[8]]);
+        Ok((wire_id, len.try_into()?))
+    }
+
+    /// Process the next packet. Returns the decoded message once all of its
+    /// packets were fed, padding of the last packet is ignored. After the
+    /// message is returned, further packets are refused.
+    pub fn feed(&mut self, packet: &[u8]) -> Result<Option<Obj>, Error> {
+        let data = if self.stream.is_none() {
+            let (wire_id, len) = Self::parse_header(packet)?;
+            let msg =
+                MsgDef::for_wire_id(wire_id).ok_or_else(|| error::unknown_wire_id(wire_id))?;
+            let decoder = self.decoder.take().ok_or_else(error::unexpected_data)?;
+            if msg.is_experimental && !decoder.enable_experimental {
+                return Err(error::experimental_not_enabled());
+            }
+            self.stream = Some(StreamingDecoder::new(decoder, msg, len)?);
+            self.remaining = len;
+            &packet[REPORT_INIT_DATA..]
+        } else {
+            if packet.len() < REPORT_CONT_DATA {
+                return Err(error::truncated_frame());
+            }
+            if packet[0] != REPORT_MARKER {
+                return Err(error::invalid_magic());
+            }
+            &packet[REPORT_CONT_DATA..]
+        };
+
+        let data = &data[..data.len().min(self.remaining)];
+        self.remaining -= data.len();
+        let stream = unwrap!(self.stream.as_mut());
+        stream.feed(data)?;
+
+        if self.remaining == 0 {
+            let stream = unwrap!(self.stream.take());
+            stream.finish().map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cstr_core::CStr;
+
+    use crate::micropython::{gc::Gc, testutil::mpy_init};
+
+    use super::{
+        super::{defs::FieldType, testutil::*},
+        *,
+    };
+
+    fn error_msg<T>(result: Result<T, Error>) -> &'static CStr {
+        match result {
+            Err(Error::ValueError(msg)) | Err(Error::ValueErrorParam(msg, _)) => msg,
+            _ => panic!("expected a value error"),
+        }
+    }
+
+    fn assert_error<T>(result: Result<T, Error>, expected: Error) {
+        assert_eq!(error_msg(result), error_msg::<()>(Err(expected)));
+    }
+
+    fn packets(obj: &MsgObj) -> std::vec::Vec<[u8; REPORT_LEN]> {
+        let mut packets = std::vec::Vec::new();
+        encode_packets(obj, |packet| {
+            packets.push(*packet);
+            Ok(())
+        })
+        .unwrap();
+        packets
+    }
+
+    #[test]
+    fn round_trip() {
+        unsafe { mpy_init() };
+
+        let data = get_address(10);
+        let obj = decode(GET_ADDRESS, &data);
+        let expected = encode(&obj);
+        let packets = packets(&obj);
+        assert!(packets.len() > 2);
+        assert_eq!(
+            PacketDecoder::parse_header(&packets[0]).unwrap(),
+            (GET_ADDRESS, expected.len())
+        );
+        for packet in &packets[1..] {
+            assert_eq!(packet[0], REPORT_MARKER);
+        }
+
+        let mut decoder = PacketDecoder::new(Decoder::new(false));
+        let (last, rest) = packets.split_last().unwrap();
+        for packet in rest {
+            assert!(decoder.feed(packet).unwrap().is_none());
+        }
+        let obj = decoder.feed(last).unwrap().unwrap();
+        assert_eq!(encode(&Gc::<MsgObj>::try_from(obj).unwrap()), expected);
+
+        // Nothing is accepted after the message.
+        assert_error(decoder.feed(&packets[0]), error::unexpected_data());
+    }
+
+    #[test]
+    fn framing_errors() {
+        unsafe { mpy_init() };
+
+        let obj = decode(GET_ADDRESS, &get_address(10));
+        let packets = packets(&obj);
+        let feed = |packets: &[&[u8]]| -> Result<(), Error> {
+            let mut decoder = PacketDecoder::new(Decoder::new(false));
+            for packet in packets {
+                decoder.feed(packet)?;
+            }
+            Ok(())
+        };
+
+        // Initial packet.
+        assert_error(feed(&[&packets[0][..8]]), error::truncated_frame());
+        let mut header = packets[0];
+        header[1] = b'!';
+        assert_error(feed(&[&header[..]]), error::invalid_magic());
+        header = packets[0];
+        header[3..5].copy_from_slice(&0xFFFF_u16.to_be_bytes());
+        assert_error(feed(&[&header[..]]), error::unknown_wire_id(0xFFFF));
+
+        // Continuation packet.
+        assert_error(feed(&[&packets[0][..], &[]]), error::truncated_frame());
+        let mut cont = packets[1];
+        cont[0] = b'#';
+        assert_error(feed(&[&packets[0][..], &cont[..]]), error::invalid_magic());
+    }
+
+    #[test]
+    fn missing_wire_id() {
+        unsafe { mpy_init() };
+
+        // `HDNodeType` is only used as a field, it has no wire type.
+        let public_key = MsgDef::for_wire_id(PUBLIC_KEY).unwrap();
+        let node = match public_key.field(1).unwrap().get_type() {
+            FieldType::Msg(node) => node,
+            _ => panic!("expected a message field"),
+        };
+        let obj = Decoder::new(false).empty_message(&node).unwrap();
+        let result = encode_packets(&obj, |_| Ok(()));
+        assert_error(result, error::missing_wire_id());
+    }
+}
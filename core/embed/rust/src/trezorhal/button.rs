@@ -0,0 +1,7 @@
+use super::ffi;
+
+/// Read the next button event. The event type is in the top byte, followed by
+/// the button number. Returns 0 if there is no new event.
+pub fn read() -> u32 {
+    unsafe { ffi::button_read() }
+}
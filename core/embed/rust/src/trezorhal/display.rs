@@ -23,6 +23,10 @@ pub fn backlight(val: i32) -> i32 {
     unsafe { ffi::display_backlight(val) }
 }
 
+pub fn refresh() {
+    unsafe { ffi::display_refresh() }
+}
+
 pub fn text(baseline_x: i16, baseline_y: i16, text: &str, font: i32, fgcolor: u16, bgcolor: u16) {
     unsafe {
         ffi::display_text(
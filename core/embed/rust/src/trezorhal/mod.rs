@@ -1,4 +1,6 @@
 pub mod bip39;
+#[cfg(feature = "buttons")]
+pub mod button;
 #[macro_use]
 #[allow(unused_macros)]
 pub mod common;
@@ -13,6 +15,8 @@ pub mod random;
 pub mod rgb_led;
 pub mod slip39;
 pub mod storage;
+#[cfg(feature = "touch")]
+pub mod touch;
 pub mod uzlib;
 
 pub mod buffers;
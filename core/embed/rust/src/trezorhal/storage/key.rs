@@ -0,0 +1,274 @@
+//! Typed keys of the values in storage.
+//!
+//! The storage works with raw `u16` appkeys and byte slices. `StorageKey`
+//! packs the app namespace, key ID and public flag into the appkey, and
+//! converts the value through the `StorageValue` implementation of its type.
+//! A key declared as `StorageKey<bool>` therefore cannot be written with a
+//! `u32`. The encodings match the ones of `storage/common.py`.
+
+use core::{marker::PhantomData, str};
+
+use heapless::String;
+
+use super::{ffi, StorageError, StorageResult};
+
+/// The largest app namespace ID, the two most significant bits of the app
+/// byte are reserved for flags.
+pub const MAX_APPID: u8 = 0x3F;
+
+/// App namespace reserved for the values of the Rust storage layer itself.
+pub const APP_RUST: u8 = MAX_APPID;
+
+/// App namespace of a raw appkey, without the flags.
+pub const fn app(appkey: u16) -> u8 {
+    (appkey >> 8) as u8 & MAX_APPID
+}
+
+/// Key ID of a raw appkey within its app namespace.
+pub const fn id(appkey: u16) -> u8 {
+    appkey as u8
+}
+
+/// Whether the value of a raw appkey is stored unencrypted.
+pub const fn is_public(appkey: u16) -> bool {
+    (appkey >> 8) as u8 & ffi::FLAG_PUBLIC as u8 != 0
+}
+
+/// Conversion of a value type from and to its storage encoding.
+pub trait StorageValue: Sized {
+    /// Buffer large enough for any encoded value of the type.
+    type Buffer: AsMut<[u8]>;
+
+    fn buffer() -> Self::Buffer;
+
+    /// Encode the value into `buffer`, or borrow it directly if it is stored
+    /// as is.
+    fn encode<'a>(&'a self, buffer: &'a mut Self::Buffer) -> &'a [u8];
+
+    /// Decode the value, returns `None` if `data` is not a valid encoding.
+    fn decode(data: &[u8]) -> Option<Self>;
+}
+
+impl StorageValue for bool {
+    type Buffer = [u8; 1];
+
+    fn buffer() -> Self::Buffer {
+        [0; 1]
+    }
+
+    fn encode<'a>(&'a self, buffer: &'a mut Self::Buffer) -> &'a [u8] {
+        buffer[0] = *self as u8;
+        buffer
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        match data {
+            [0] => Some(false),
+            [1] => Some(true),
+            _ => None,
+        }
+    }
+}
+
+macro_rules! impl_storage_value_uint {
+    ($($uint:ty),*) => {
+        $(
+            /// Stored in the big-endian order.
+            impl StorageValue for $uint {
+                type Buffer = [u8; core::mem::size_of::<$uint>()];
+
+                fn buffer() -> Self::Buffer {
+                    [0; core::mem::size_of::<$uint>()]
+                }
+
+                fn encode<'a>(&'a self, buffer: &'a mut Self::Buffer) -> &'a [u8] {
+                    *buffer = self.to_be_bytes();
+                    buffer
+                }
+
+                fn decode(data: &[u8]) -> Option<Self> {
+                    data.try_into().ok().map(<$uint>::from_be_bytes)
+                }
+            }
+        )*
+    };
+}
+
+impl_storage_value_uint!(u8, u16, u32);
+
+/// Stored as UTF-8 without any terminator, at most `N` bytes long.
+impl<const N: usize> StorageValue for String<N> {
+    type Buffer = [u8; N];
+
+    fn buffer() -> Self::Buffer {
+        [0; N]
+    }
+
+    fn encode<'a>(&'a self, _buffer: &'a mut Self::Buffer) -> &'a [u8] {
+        self.as_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let mut string = String::new();
+        string.push_str(str::from_utf8(data).ok()?).ok()?;
+        Some(string)
+    }
+}
+
+/// Stored as is, values of a different length are invalid.
+impl<const N: usize> StorageValue for [u8; N] {
+    type Buffer = [u8; N];
+
+    fn buffer() -> Self::Buffer {
+        [0; N]
+    }
+
+    fn encode<'a>(&'a self, _buffer: &'a mut Self::Buffer) -> &'a [u8] {
+        self
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        data.try_into().ok()
+    }
+}
+
+/// Key of a value of type `T` in storage.
+pub struct StorageKey<T> {
+    appkey: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T> StorageKey<T> {
+    /// Private key `id` in the app namespace `app`. The value is encrypted and
+    /// can only be accessed while the storage is unlocked.
+    pub const fn new(app: u8, id: u8) -> Self {
+        Self::with_flags(app, id, 0)
+    }
+
+    /// Public key `id` in the app namespace `app`. The value is not encrypted
+    /// and can be read even while the storage is locked.
+    pub const fn public(app: u8, id: u8) -> Self {
+        Self::with_flags(app, id, ffi::FLAG_PUBLIC as u8)
+    }
+
+    const fn with_flags(app: u8, id: u8, flags: u8) -> Self {
+        // App 0 is reserved for the internal values of the storage.
+        assert!(app != 0 && app <= MAX_APPID);
+        Self {
+            appkey: u16::from_be_bytes([app | flags, id]),
+            _value: PhantomData,
+        }
+    }
+
+    /// Raw appkey for the untyped `storage` functions.
+    pub const fn appkey(&self) -> u16 {
+        self.appkey
+    }
+
+    pub const fn app(&self) -> u8 {
+        app(self.appkey)
+    }
+
+    pub const fn id(&self) -> u8 {
+        id(self.appkey)
+    }
+
+    pub const fn is_public(&self) -> bool {
+        is_public(self.appkey)
+    }
+}
+
+impl<T: StorageValue> StorageKey<T> {
+    /// Check if the value exists.
+    pub fn has(&self) -> bool {
+        super::has(self.appkey)
+    }
+
+    /// Get the value, or `None` if it does not exist. Private values do not
+    /// exist while the storage is locked. Returns an error if the stored value
+    /// is not a valid encoding of `T`.
+    pub fn get(&self) -> StorageResult<Option<T>> {
+        let mut buffer = T::buffer();
+        match super::get(self.appkey, buffer.as_mut()) {
+            Ok(data) => T::decode(data).map(Some).ok_or(StorageError::InvalidData),
+            // The value exists, but it does not fit into the buffer.
+            Err(_) if self.has() => Err(StorageError::InvalidData),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Get the value, or `default` if it does not exist.
+    pub fn get_or(&self, default: T) -> StorageResult<T> {
+        Ok(self.get()?.unwrap_or(default))
+    }
+
+    /// Get the value, or the default value of `T` if it does not exist.
+    pub fn get_or_default(&self) -> StorageResult<T>
+    where
+        T: Default,
+    {
+        Ok(self.get()?.unwrap_or_default())
+    }
+
+    /// Set the value.
+    pub fn set(&self, value: T) -> StorageResult<()> {
+        let mut buffer = T::buffer();
+        super::set(self.appkey, value.encode(&mut buffer))
+    }
+
+    /// Delete the value. Returns an error if the value does not exist.
+    pub fn delete(&self) -> StorageResult<()> {
+        super::delete(self.appkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode<T: StorageValue>(value: T) -> heapless::Vec<u8, 64> {
+        let mut buffer = T::buffer();
+        heapless::Vec::from_slice(value.encode(&mut buffer)).unwrap()
+    }
+
+    #[test]
+    fn test_appkey() {
+        let key = StorageKey::<bool>::new(0x01, 0x23);
+        assert_eq!(key.appkey(), 0x0123);
+        assert_eq!((key.app(), key.id(), key.is_public()), (0x01, 0x23, false));
+
+        let key = StorageKey::<bool>::public(MAX_APPID, 0x04);
+        assert_eq!(key.appkey(), 0xBF04);
+        assert_eq!(
+            (key.app(), key.id(), key.is_public()),
+            (MAX_APPID, 0x04, true)
+        );
+    }
+
+    #[test]
+    fn test_encoding() {
+        assert_eq!(encode(true), [1]);
+        assert_eq!(encode(false), [0]);
+        assert_eq!(encode(0xABu8), [0xAB]);
+        assert_eq!(encode(0x0102u16), [0x01, 0x02]);
+        assert_eq!(encode(0x01020304u32), [0x01, 0x02, 0x03, 0x04]);
+        assert_eq!(encode(String::<8>::from("label")), *b"label");
+        assert_eq!(encode([7u8; 3]), [7, 7, 7]);
+    }
+
+    #[test]
+    fn test_decoding() {
+        assert_eq!(bool::decode(&[1]), Some(true));
+        assert_eq!(bool::decode(&[2]), None);
+        assert_eq!(bool::decode(&[]), None);
+        assert_eq!(u8::decode(&[0xAB]), Some(0xAB));
+        assert_eq!(u8::decode(&[0, 0xAB]), None);
+        assert_eq!(u32::decode(&[0x01, 0x02, 0x03, 0x04]), Some(0x01020304));
+        assert_eq!(u32::decode(&[0x01, 0x02, 0x03]), None);
+        assert_eq!(String::<8>::decode(b"label").as_deref(), Some("label"));
+        assert_eq!(String::<4>::decode(b"label"), None);
+        assert_eq!(String::<8>::decode(b"\xff"), None);
+        assert_eq!(<[u8; 3]>::decode(&[7, 7, 7]), Some([7; 3]));
+        assert_eq!(<[u8; 3]>::decode(&[7, 7]), None);
+    }
+}
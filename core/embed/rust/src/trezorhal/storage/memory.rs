@@ -0,0 +1,660 @@
+//! In-memory replacement of the C storage for unit tests.
+//!
+//! `MemoryStorage` keeps the values in a map and follows the observable
+//! behaviour of `storage.c`: PIN and external salt, locking, private values
+//! hidden while locked, public values writable while locked only with
+//! `FLAGS_WRITE`, monotonic counters and the growing delay after wrong PIN
+//! attempts. Nothing is encrypted, and the delay is only reported to the PIN
+//! delay callback and added to `total_delay`, not actually waited.
+//!
+//! With the `storage_memory` feature, the `storage` functions of test builds
+//! go to a global `MemoryStorage` through the `ffi` module below instead of
+//! the C library.
+
+use std::{collections::BTreeMap, vec::Vec};
+
+use super::{
+    key::{self, is_public},
+    ExternalSalt, PinCallbackResult, PinDelayCallback, MAX_WIPE_CODE_LEN,
+};
+use crate::trezorhal::ffi::{EXTERNAL_SALT_SIZE, FLAGS_WRITE};
+
+/// App reserved for the internal values of the storage.
+const APP_STORAGE: u8 = 0x00;
+
+/// Wrong PIN attempts before the storage is wiped.
+const PIN_MAX_TRIES: u32 = 16;
+
+/// Seconds the PIN key derivation takes on the device.
+const DERIVE_SECS: u32 = 1;
+
+/// Counters are stored as the value followed by a tally of `1` bits, like in
+/// `storage.c`. The tally is not used here.
+const COUNTER_TAIL: [u8; 8] = [0xFF; 8];
+
+const VERIFYING_PIN_MSG: &str = "Verifying PIN";
+const PROCESSING_MSG: &str = "Processing";
+const STARTING_MSG: &str = "Starting up";
+
+pub struct MemoryStorage {
+    initialized: bool,
+    unlocked: bool,
+    /// `None` until the storage is formatted by `init` or `wipe`.
+    pin: Option<Vec<u8>>,
+    salt: Option<ExternalSalt>,
+    wipe_code: Option<Vec<u8>>,
+    pin_fails: u32,
+    values: BTreeMap<u16, Vec<u8>>,
+    callback: Option<PinDelayCallback>,
+    ui_message: Option<&'static str>,
+    ui_total: u32,
+    ui_rem: u32,
+    total_delay: u32,
+}
+
+impl MemoryStorage {
+    /// Storage on a blank flash.
+    pub const fn new() -> Self {
+        Self {
+            initialized: false,
+            unlocked: false,
+            pin: None,
+            salt: None,
+            wipe_code: None,
+            pin_fails: 0,
+            values: BTreeMap::new(),
+            callback: None,
+            ui_message: None,
+            ui_total: 0,
+            ui_rem: 0,
+            total_delay: 0,
+        }
+    }
+
+    /// Same as `storage::init`, formats the storage if it is blank. The
+    /// storage is locked afterwards.
+    pub fn init(&mut self, callback: Option<PinDelayCallback>) {
+        self.initialized = true;
+        self.callback = callback;
+        if self.pin.is_none() {
+            self.wipe();
+        }
+        self.unlocked = false;
+    }
+
+    /// Erase all values and reset the PIN to an empty one. The storage is
+    /// left unlocked, same as in `storage.c`.
+    pub fn wipe(&mut self) {
+        self.values.clear();
+        self.salt = None;
+        self.wipe_code = None;
+        self.pin_fails = 0;
+        if self.initialized {
+            self.unlocked = true;
+            self.ui_total = DERIVE_SECS;
+            self.ui_rem = self.ui_total;
+            self.ui_message = Some(PROCESSING_MSG);
+            self.derive();
+            self.pin = Some(Vec::new());
+        } else {
+            self.pin = None;
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    pub fn lock(&mut self) {
+        self.unlocked = false;
+    }
+
+    /// Unlock with `pin` and `salt`. Each attempt first reports the delay of
+    /// 2^n - 1 seconds after n wrong attempts, the attempt is abandoned if the
+    /// callback aborts it.
+    pub fn unlock(&mut self, pin: &[u8], salt: Option<&ExternalSalt>) -> bool {
+        if !self.initialized {
+            return false;
+        }
+        self.ui_total = DERIVE_SECS;
+        self.ui_rem = self.ui_total;
+        self.ui_message = Some(match (pin.is_empty(), self.ui_message) {
+            (true, None) => STARTING_MSG,
+            (true, Some(_)) => PROCESSING_MSG,
+            (false, _) => VERIFYING_PIN_MSG,
+        });
+        self.check_pin(pin, salt)
+    }
+
+    pub fn change_pin(
+        &mut self,
+        old_pin: &[u8],
+        new_pin: &[u8],
+        old_salt: Option<&ExternalSalt>,
+        new_salt: Option<&ExternalSalt>,
+    ) -> bool {
+        if !self.initialized {
+            return false;
+        }
+        self.ui_total = 2 * DERIVE_SECS;
+        self.ui_rem = self.ui_total;
+        self.ui_message = Some(if !old_pin.is_empty() && new_pin.is_empty() {
+            VERIFYING_PIN_MSG
+        } else {
+            PROCESSING_MSG
+        });
+        if !self.check_pin(old_pin, old_salt) || self.is_wipe_code(new_pin) {
+            return false;
+        }
+        self.derive();
+        self.pin = Some(new_pin.to_vec());
+        self.salt = new_salt.copied();
+        true
+    }
+
+    pub fn has_pin(&self) -> bool {
+        self.initialized && matches!(&self.pin, Some(pin) if !pin.is_empty())
+    }
+
+    pub fn pin_remaining(&self) -> u32 {
+        if self.initialized {
+            PIN_MAX_TRIES - self.pin_fails
+        } else {
+            0
+        }
+    }
+
+    /// Only known while the storage is unlocked.
+    pub fn has_wipe_code(&self) -> bool {
+        self.initialized && self.unlocked && self.wipe_code.is_some()
+    }
+
+    /// Set the wipe code after checking the PIN, an empty one removes it.
+    pub fn change_wipe_code(
+        &mut self,
+        pin: &[u8],
+        salt: Option<&ExternalSalt>,
+        wipe_code: &[u8],
+    ) -> bool {
+        if !self.initialized || !pin.is_empty() && pin == wipe_code {
+            return false;
+        }
+        self.ui_total = DERIVE_SECS;
+        self.ui_rem = self.ui_total;
+        self.ui_message = Some(if !pin.is_empty() && wipe_code.is_empty() {
+            VERIFYING_PIN_MSG
+        } else {
+            PROCESSING_MSG
+        });
+        if !self.check_pin(pin, salt) || wipe_code.len() > MAX_WIPE_CODE_LEN {
+            return false;
+        }
+        self.wipe_code = (!wipe_code.is_empty()).then(|| wipe_code.to_vec());
+        true
+    }
+
+    /// Wipe the storage if `pin` is the wipe code. The device would shut down
+    /// afterwards, here the storage is just left wiped. Returns false if
+    /// the storage was wiped.
+    pub fn ensure_not_wipe_code(&mut self, pin: &[u8]) -> bool {
+        if self.is_wipe_code(pin) {
+            self.wipe();
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Seconds of the delays after wrong PIN attempts reported so far.
+    pub fn total_delay(&self) -> u32 {
+        self.total_delay
+    }
+
+    /// Value of `key`, private values are only readable while unlocked.
+    pub fn get(&self, key: u16) -> Option<&[u8]> {
+        let app = (key >> 8) as u8;
+        if !self.initialized || app == APP_STORAGE {
+            return None;
+        }
+        if !is_public(key) && !self.unlocked {
+            return None;
+        }
+        self.values.get(&key).map(Vec::as_slice)
+    }
+
+    pub fn set(&mut self, key: u16, data: &[u8]) -> bool {
+        if !self.is_writable(key) {
+            return false;
+        }
+        self.values.insert(key, data.to_vec());
+        true
+    }
+
+    /// Returns false if the value does not exist.
+    pub fn delete(&mut self, key: u16) -> bool {
+        self.is_writable(key) && self.values.remove(&key).is_some()
+    }
+
+    /// Only public keys can hold counters.
+    pub fn set_counter(&mut self, key: u16, count: u32) -> bool {
+        if !is_public(key) {
+            return false;
+        }
+        let mut data = count.to_ne_bytes().to_vec();
+        data.extend_from_slice(&COUNTER_TAIL);
+        self.set(key, &data)
+    }
+
+    /// Increment the counter of `key` and return the new value, a missing
+    /// counter starts at 0.
+    pub fn next_counter(&mut self, key: u16) -> Option<u32> {
+        if !is_public(key) || !self.is_writable(key) {
+            return None;
+        }
+        let count = match self.values.get(&key) {
+            Some(data) => {
+                let count = u32::from_ne_bytes(data.get(..4)?.try_into().ok()?);
+                count.checked_add(1)?
+            }
+            None => 0,
+        };
+        self.set_counter(key, count).then_some(count)
+    }
+
+    /// Next value of the app namespace `app` after the first `offset` values,
+    /// in the order of the keys. Same as `storage_next_entry`, except that the
+    /// offset counts the values instead of flash words.
+    pub fn next_entry(&self, app: u8, offset: &mut u32) -> Option<(u16, usize)> {
+        if !self.initialized || app == APP_STORAGE {
+            return None;
+        }
+        for (&appkey, data) in self.values.iter().skip(*offset as usize) {
+            *offset += 1;
+            if key::app(appkey) == app && (is_public(appkey) || self.unlocked) {
+                return Some((appkey, data.len()));
+            }
+        }
+        None
+    }
+
+    fn is_writable(&self, key: u16) -> bool {
+        let app = (key >> 8) as u8;
+        let flags_write = FLAGS_WRITE as u8;
+        self.initialized
+            && app != APP_STORAGE
+            && (self.unlocked || app & flags_write == flags_write)
+    }
+
+    fn is_wipe_code(&self, pin: &[u8]) -> bool {
+        self.wipe_code.as_deref() == Some(pin)
+    }
+
+    /// Same steps as `unlock` in `storage.c`.
+    fn check_pin(&mut self, pin: &[u8], salt: Option<&ExternalSalt>) -> bool {
+        if !self.ensure_not_wipe_code(pin) {
+            return false;
+        }
+        if self.pin_fails >= PIN_MAX_TRIES {
+            self.wipe();
+            return false;
+        }
+
+        // Report the delay of 2^fails - 1 seconds in ten steps per second.
+        let wait = (1 << self.pin_fails) - 1;
+        self.ui_total += wait;
+        let total = self.ui_total;
+        for rem in (total - wait + 1..=total).rev() {
+            self.ui_rem = rem;
+            for i in 0..10 {
+                let progress = ((total - rem) * 10 + i) * 100 / total;
+                if self.report(rem, progress) == PinCallbackResult::Abort {
+                    return false;
+                }
+            }
+            self.total_delay += 1;
+        }
+        self.ui_rem = total - wait;
+        self.derive();
+
+        // The failure is counted before the PIN is checked.
+        self.pin_fails += 1;
+        if self.pin.as_deref() != Some(pin) || self.salt.as_ref() != salt {
+            if self.pin_fails >= PIN_MAX_TRIES {
+                self.wipe();
+            }
+            return false;
+        }
+        self.unlocked = true;
+        self.pin_fails = 0;
+        true
+    }
+
+    /// Report the progress of a PIN key derivation, which cannot be aborted.
+    fn derive(&mut self) {
+        let (total, rem) = (self.ui_total, self.ui_rem);
+        self.report(rem, (total - rem) * 1000 / total);
+        for i in 1..=10 {
+            let progress = ((total - rem) * 1000 + i * DERIVE_SECS * 100) / total;
+            self.report(rem - i * DERIVE_SECS / 10, progress);
+        }
+        self.ui_rem -= DERIVE_SECS;
+    }
+
+    fn report(&self, wait: u32, progress: u32) -> PinCallbackResult {
+        match (self.callback, self.ui_message) {
+            (Some(callback), Some(message)) => callback(wait, progress, message),
+            _ => PinCallbackResult::Continue,
+        }
+    }
+}
+
+/// Drop-in replacement of the C storage functions used by the `storage`
+/// wrappers, backed by a global `MemoryStorage`. The pointer arguments have
+/// the same requirements as in `storage.h`.
+#[cfg(feature = "storage_memory")]
+#[allow(clippy::missing_safety_doc)]
+pub mod ffi {
+    use core::{ptr, slice};
+    use std::sync::{Mutex, PoisonError};
+
+    use super::{
+        super::{PinCallbackResult, PIN_UI_CALLBACK},
+        ExternalSalt, MemoryStorage,
+    };
+
+    pub use crate::trezorhal::ffi::{
+        secbool, secfalse, sectrue, EXTERNAL_SALT_SIZE, FLAG_PUBLIC, HW_ENTROPY_DATA,
+        PIN_UI_WAIT_CALLBACK,
+    };
+
+    static STORAGE: Mutex<MemoryStorage> = Mutex::new(MemoryStorage::new());
+
+    /// Access the global storage, e.g. to emulate a power loss.
+    pub fn with<T>(f: impl FnOnce(&mut MemoryStorage) -> T) -> T {
+        // Keep going after a failed test, the storage is reset by the next one.
+        f(&mut STORAGE.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+
+    fn to_secbool(value: bool) -> secbool {
+        if value {
+            sectrue
+        } else {
+            secfalse
+        }
+    }
+
+    unsafe fn to_salt<'a>(salt: *const u8) -> Option<&'a ExternalSalt> {
+        unsafe { salt.cast::<ExternalSalt>().as_ref() }
+    }
+
+    /// The callback of `storage_init` is always the wrapper of
+    /// `PIN_UI_CALLBACK`, so the Rust callback is called directly.
+    fn ui_callback(wait: u32, progress: u32, message: &str) -> PinCallbackResult {
+        unsafe { PIN_UI_CALLBACK }
+            .map(|c| c(wait, progress, message))
+            .unwrap_or(PinCallbackResult::Continue)
+    }
+
+    /// Start with a blank flash.
+    pub unsafe fn flash_init() {
+        with(|s| *s = MemoryStorage::new());
+    }
+
+    pub unsafe fn storage_init(_callback: PIN_UI_WAIT_CALLBACK, _salt: *const u8, _salt_len: u16) {
+        with(|s| s.init(Some(ui_callback)));
+    }
+
+    pub unsafe fn storage_wipe() {
+        with(|s| s.wipe());
+    }
+
+    pub unsafe fn storage_is_unlocked() -> secbool {
+        to_secbool(with(|s| s.is_unlocked()))
+    }
+
+    pub unsafe fn storage_lock() {
+        with(|s| s.lock());
+    }
+
+    pub unsafe fn storage_unlock(pin: *const u8, pin_len: usize, ext_salt: *const u8) -> secbool {
+        let pin = unsafe { slice::from_raw_parts(pin, pin_len) };
+        let salt = unsafe { to_salt(ext_salt) };
+        to_secbool(with(|s| s.unlock(pin, salt)))
+    }
+
+    pub unsafe fn storage_change_pin(
+        oldpin: *const u8,
+        oldpin_len: usize,
+        newpin: *const u8,
+        newpin_len: usize,
+        old_ext_salt: *const u8,
+        new_ext_salt: *const u8,
+    ) -> secbool {
+        let old_pin = unsafe { slice::from_raw_parts(oldpin, oldpin_len) };
+        let new_pin = unsafe { slice::from_raw_parts(newpin, newpin_len) };
+        let old_salt = unsafe { to_salt(old_ext_salt) };
+        let new_salt = unsafe { to_salt(new_ext_salt) };
+        to_secbool(with(|s| s.change_pin(old_pin, new_pin, old_salt, new_salt)))
+    }
+
+    pub unsafe fn storage_has_pin() -> secbool {
+        to_secbool(with(|s| s.has_pin()))
+    }
+
+    pub unsafe fn storage_get_pin_rem() -> u32 {
+        with(|s| s.pin_remaining())
+    }
+
+    pub unsafe fn storage_ensure_not_wipe_code(pin: *const u8, pin_len: usize) {
+        let pin = unsafe { slice::from_raw_parts(pin, pin_len) };
+        with(|s| s.ensure_not_wipe_code(pin));
+    }
+
+    pub unsafe fn storage_has_wipe_code() -> secbool {
+        to_secbool(with(|s| s.has_wipe_code()))
+    }
+
+    pub unsafe fn storage_change_wipe_code(
+        pin: *const u8,
+        pin_len: usize,
+        ext_salt: *const u8,
+        wipe_code: *const u8,
+        wipe_code_len: usize,
+    ) -> secbool {
+        let pin = unsafe { slice::from_raw_parts(pin, pin_len) };
+        let salt = unsafe { to_salt(ext_salt) };
+        let wipe_code = unsafe { slice::from_raw_parts(wipe_code, wipe_code_len) };
+        to_secbool(with(|s| s.change_wipe_code(pin, salt, wipe_code)))
+    }
+
+    pub unsafe fn storage_has(key: u16) -> secbool {
+        to_secbool(with(|s| s.get(key).is_some()))
+    }
+
+    pub unsafe fn storage_get(
+        key: u16,
+        val: *mut cty::c_void,
+        max_len: u16,
+        len: *mut u16,
+    ) -> secbool {
+        with(|s| match s.get(key) {
+            Some(data) => {
+                unsafe { *len = data.len() as u16 };
+                if val.is_null() {
+                    sectrue
+                } else if data.len() > max_len as usize {
+                    secfalse
+                } else {
+                    unsafe { ptr::copy_nonoverlapping(data.as_ptr(), val.cast(), data.len()) };
+                    sectrue
+                }
+            }
+            None => secfalse,
+        })
+    }
+
+    pub unsafe fn storage_set(key: u16, val: *const cty::c_void, len: u16) -> secbool {
+        let data = unsafe { slice::from_raw_parts(val.cast(), len as usize) };
+        to_secbool(with(|s| s.set(key, data)))
+    }
+
+    pub unsafe fn storage_delete(key: u16) -> secbool {
+        to_secbool(with(|s| s.delete(key)))
+    }
+
+    pub unsafe fn storage_set_counter(key: u16, count: u32) -> secbool {
+        to_secbool(with(|s| s.set_counter(key, count)))
+    }
+
+    pub unsafe fn storage_next_counter(key: u16, count: *mut u32) -> secbool {
+        match with(|s| s.next_counter(key)) {
+            Some(next) => {
+                unsafe { *count = next };
+                sectrue
+            }
+            None => secfalse,
+        }
+    }
+
+    pub unsafe fn storage_next_entry(
+        app_id: u8,
+        offset: *mut u32,
+        key: *mut u16,
+        len: *mut u16,
+    ) -> secbool {
+        let offset = unsafe { &mut *offset };
+        match with(|s| s.next_entry(app_id, offset)) {
+            Some((next_key, next_len)) => {
+                unsafe {
+                    *key = next_key;
+                    *len = next_len as u16;
+                }
+                sectrue
+            }
+            None => secfalse,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    const PRIVATE_KEY: u16 = 0x0101;
+    const PUBLIC_KEY: u16 = 0x8101;
+    const WRITABLE_KEY: u16 = 0xC101;
+
+    fn storage() -> MemoryStorage {
+        let mut storage = MemoryStorage::new();
+        storage.init(None);
+        storage
+    }
+
+    #[test]
+    fn test_access() {
+        let mut storage = storage();
+        assert!(!storage.is_unlocked());
+        assert!(!storage.set(PRIVATE_KEY, b"private"));
+        assert!(!storage.set(PUBLIC_KEY, b"public"));
+        assert!(storage.set(WRITABLE_KEY, b"writable"));
+
+        assert!(storage.unlock(b"", None));
+        assert!(storage.set(PRIVATE_KEY, b"private"));
+        assert!(storage.set(PUBLIC_KEY, b"public"));
+        assert!(!storage.set(0x0001, b"reserved"));
+
+        storage.lock();
+        assert_eq!(storage.get(PRIVATE_KEY), None);
+        assert_eq!(storage.get(PUBLIC_KEY), Some(&b"public"[..]));
+        assert!(!storage.delete(PUBLIC_KEY));
+        assert!(storage.delete(WRITABLE_KEY));
+        assert!(!storage.delete(WRITABLE_KEY));
+
+        // Values survive a restart, but not a wipe.
+        storage.init(None);
+        assert!(storage.unlock(b"", None));
+        assert_eq!(storage.get(PRIVATE_KEY), Some(&b"private"[..]));
+        storage.wipe();
+        assert!(storage.is_unlocked());
+        assert_eq!(storage.get(PRIVATE_KEY), None);
+    }
+
+    #[test]
+    fn test_counter() {
+        let mut storage = storage();
+        assert!(!storage.set_counter(PRIVATE_KEY, 1));
+        assert_eq!(storage.next_counter(WRITABLE_KEY), Some(0));
+        assert_eq!(storage.next_counter(WRITABLE_KEY), Some(1));
+        assert_eq!(storage.next_counter(PUBLIC_KEY), None);
+
+        assert!(storage.unlock(b"", None));
+        assert!(storage.set_counter(PUBLIC_KEY, u32::MAX - 1));
+        assert_eq!(storage.next_counter(PUBLIC_KEY), Some(u32::MAX));
+        assert_eq!(storage.next_counter(PUBLIC_KEY), None);
+    }
+
+    #[test]
+    fn test_wipe_code() {
+        let mut storage = storage();
+        assert!(storage.change_pin(b"", b"1234", None, None));
+        assert!(!storage.change_wipe_code(b"1234", None, b"1234"));
+        assert!(storage.change_wipe_code(b"1234", None, b"5678"));
+        assert!(storage.has_wipe_code());
+        assert!(storage.set(PRIVATE_KEY, b"private"));
+
+        // Unlocking with the wipe code wipes the storage.
+        storage.lock();
+        assert!(!storage.unlock(b"5678", None));
+        assert!(!storage.has_pin());
+        assert!(!storage.has_wipe_code());
+        assert_eq!(storage.get(PRIVATE_KEY), None);
+    }
+
+    static LAST_WAIT: AtomicU32 = AtomicU32::new(0);
+
+    fn record_wait(wait: u32, _progress: u32, _message: &str) -> PinCallbackResult {
+        LAST_WAIT.store(wait, Ordering::Relaxed);
+        PinCallbackResult::Continue
+    }
+
+    fn abort(_wait: u32, _progress: u32, _message: &str) -> PinCallbackResult {
+        PinCallbackResult::Abort
+    }
+
+    #[test]
+    fn test_pin() {
+        let mut storage = storage();
+        let salt = [1; EXTERNAL_SALT_SIZE as usize];
+        assert!(storage.change_pin(b"", b"1234", None, Some(&salt)));
+        assert!(storage.has_pin());
+        storage.lock();
+        assert!(!storage.unlock(b"1234", None));
+        assert!(!storage.unlock(b"4321", Some(&salt)));
+        assert_eq!(storage.pin_remaining(), PIN_MAX_TRIES - 2);
+
+        // The third attempt waits 2^2 - 1 seconds, it is not counted if
+        // aborted during the wait.
+        storage.init(Some(abort));
+        assert!(!storage.unlock(b"1234", Some(&salt)));
+        assert_eq!(storage.pin_remaining(), PIN_MAX_TRIES - 2);
+        assert_eq!(storage.total_delay(), 1);
+
+        storage.init(Some(record_wait));
+        assert!(storage.unlock(b"1234", Some(&salt)));
+        assert_eq!(storage.pin_remaining(), PIN_MAX_TRIES);
+        assert_eq!(storage.total_delay(), 1 + 3);
+        assert_eq!(LAST_WAIT.load(Ordering::Relaxed), 0);
+
+        // Too many wrong attempts wipe the storage.
+        assert!(storage.set(PRIVATE_KEY, b"private"));
+        storage.init(None);
+        for _ in 0..PIN_MAX_TRIES {
+            assert!(!storage.unlock(b"", None));
+        }
+        assert!(!storage.has_pin());
+        assert!(storage.is_unlocked());
+        assert_eq!(storage.get(PRIVATE_KEY), None);
+    }
+}
@@ -1,8 +1,22 @@
-use super::ffi;
 use crate::error::Error;
-use core::ptr;
+use core::{iter, ptr};
 use cstr_core::{cstr, CStr};
 
+pub mod key;
+#[cfg(test)]
+pub mod memory;
+pub mod schema;
+#[cfg(test)]
+mod testutil;
+pub mod transaction;
+
+#[cfg(not(test))]
+use super::ffi;
+#[cfg(all(test, feature = "storage_memory"))]
+use memory::ffi;
+#[cfg(all(test, not(feature = "storage_memory")))]
+use testutil::ffi;
+
 /// Result of PIN delay callback.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PinCallbackResult {
@@ -22,6 +36,9 @@ pub type PinDelayCallback = fn(wait: u32, progress: u32, message: &str) -> PinCa
 
 pub type ExternalSalt = [u8; ffi::EXTERNAL_SALT_SIZE as usize];
 
+/// The maximum length of the wipe code in bytes.
+pub const MAX_WIPE_CODE_LEN: usize = 50;
+
 /// Static reference to the currently set PIN callback function.
 static mut PIN_UI_CALLBACK: Option<PinDelayCallback> = None;
 
@@ -57,6 +74,14 @@ pub enum StorageError {
     DeleteFailed,
     /// Failed to perform the get-and-increment operation on a counter.
     CounterFailed,
+    /// The operation needs the storage unlocked.
+    Locked,
+    /// The PIN or the external salt is wrong, or the operation was aborted.
+    InvalidPin,
+    /// The wipe code cannot be the same as the PIN.
+    WipeCodeSameAsPin,
+    /// The storage schema is newer than the firmware supports.
+    UnknownSchema,
 }
 
 impl From<StorageError> for Error {
@@ -69,6 +94,14 @@ impl From<StorageError> for Error {
             StorageError::CounterFailed => {
                 Error::ValueError(cstr!("Retrieving counter value failed"))
             }
+            StorageError::Locked => Error::ValueError(cstr!("Storage is locked")),
+            StorageError::InvalidPin => Error::ValueError(cstr!("Invalid PIN")),
+            StorageError::WipeCodeSameAsPin => {
+                Error::ValueError(cstr!("Wipe code must be different from PIN"))
+            }
+            StorageError::UnknownSchema => {
+                Error::ValueError(cstr!("Storage schema is newer than the firmware"))
+            }
         }
     }
 }
@@ -111,10 +144,29 @@ pub fn lock() {
 }
 
 /// Unlock storage with PIN and optional external salt.
-/// Returns true if the PIN + salt combination is correct.
+/// Returns true if the PIN + salt combination is correct. The unlocked storage
+/// is migrated to `schema::FIRMWARE`, see `unlock_to_schema`.
 pub fn unlock(pin: &str, salt: Option<&ExternalSalt>) -> bool {
+    unlock_to_schema(&schema::FIRMWARE, pin, salt)
+}
+
+/// Unlock storage and migrate it to `schema`. A storage from a newer firmware
+/// is left as is. If the migration fails, the storage is locked again and
+/// false is returned even though the PIN + salt combination is correct.
+fn unlock_to_schema(schema: &schema::Schema, pin: &str, salt: Option<&ExternalSalt>) -> bool {
     let salt = salt.map(|s| s.as_ptr()).unwrap_or(ptr::null());
-    ffi::sectrue == unsafe { ffi::storage_unlock(pin.as_ptr() as *const _, pin.len(), salt) }
+    let unlocked =
+        ffi::sectrue == unsafe { ffi::storage_unlock(pin.as_ptr() as *const _, pin.len(), salt) };
+    if !unlocked {
+        return false;
+    }
+    match schema.migrate_on_unlock() {
+        Ok(_) | Err(StorageError::UnknownSchema) => true,
+        Err(_) => {
+            lock();
+            false
+        }
+    }
 }
 
 /// Change PIN and/or external salt.
@@ -155,6 +207,57 @@ pub fn ensure_not_wipe_pin(pin: &str) {
     }
 }
 
+/// Check if storage has wipe code set.
+/// Returns an error if the storage is locked.
+pub fn has_wipe_code() -> StorageResult<bool> {
+    if !is_unlocked() {
+        return Err(StorageError::Locked);
+    }
+    Ok(ffi::sectrue == unsafe { ffi::storage_has_wipe_code() })
+}
+
+/// Set or change the wipe code, after checking the PIN and optional external
+/// salt. The PIN check counts as an unlock attempt, and leaves the storage
+/// unlocked on success.
+/// The wipe code must be different from the PIN.
+pub fn change_wipe_code(
+    pin: &str,
+    salt: Option<&ExternalSalt>,
+    wipe_code: &str,
+) -> StorageResult<()> {
+    if wipe_code.is_empty() || wipe_code.len() > MAX_WIPE_CODE_LEN {
+        Err(StorageError::InvalidData)
+    } else if pin == wipe_code {
+        Err(StorageError::WipeCodeSameAsPin)
+    } else {
+        set_wipe_code(pin, salt, wipe_code)
+    }
+}
+
+/// Remove the wipe code, after checking the PIN and optional external salt.
+/// See `change_wipe_code`.
+pub fn remove_wipe_code(pin: &str, salt: Option<&ExternalSalt>) -> StorageResult<()> {
+    set_wipe_code(pin, salt, "")
+}
+
+fn set_wipe_code(pin: &str, salt: Option<&ExternalSalt>, wipe_code: &str) -> StorageResult<()> {
+    if ffi::sectrue
+        == unsafe {
+            ffi::storage_change_wipe_code(
+                pin.as_ptr() as *const _,
+                pin.len(),
+                salt.map(|s| s.as_ptr()).unwrap_or(ptr::null()),
+                wipe_code.as_ptr() as *const _,
+                wipe_code.len(),
+            )
+        }
+    {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidPin)
+    }
+}
+
 /// Check if value for `appkey` exists.
 pub fn has(appkey: u16) -> bool {
     ffi::sectrue == unsafe { ffi::storage_has(appkey) }
@@ -239,9 +342,55 @@ pub fn delete(appkey: u16) -> StorageResult<()> {
     }
 }
 
+/// Value found in storage by `entries`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub appkey: u16,
+    /// Length of the value, without the encryption overhead.
+    pub len: usize,
+}
+
+impl Entry {
+    pub fn app(&self) -> u8 {
+        key::app(self.appkey)
+    }
+
+    pub fn id(&self) -> u8 {
+        key::id(self.appkey)
+    }
+
+    pub fn is_public(&self) -> bool {
+        key::is_public(self.appkey)
+    }
+
+    /// Read the value into `data`, decrypting it if it is private. See `get`.
+    pub fn read<'a>(&self, data: &'a mut [u8]) -> StorageResult<&'a [u8]> {
+        get(self.appkey, data)
+    }
+}
+
+/// List the values in the app namespace `app`, in the order they are stored
+/// in flash. Only the lengths are looked up, the values are not decrypted.
+/// Private values are listed only while the storage is unlocked. The storage
+/// must not be modified until the iterator is dropped.
+pub fn entries(app: u8) -> impl Iterator<Item = Entry> {
+    assert!(app <= key::MAX_APPID);
+    let mut offset = 0;
+    iter::from_fn(move || {
+        let mut appkey = 0;
+        let mut len = 0;
+        let found = unsafe { ffi::storage_next_entry(app, &mut offset, &mut appkey, &mut len) };
+        (ffi::sectrue == found).then_some(Entry {
+            appkey,
+            len: len as usize,
+        })
+    })
+}
+
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use super::{key::StorageKey, *};
+    use heapless::String;
 
     const APPKEY: u16 = 0x0101;
 
@@ -330,4 +479,91 @@ mod tests {
         assert!(!unlock("", None));
         assert!(unlock("1234", None));
     }
+
+    #[test]
+    fn test_typed_key() {
+        const FLAG: StorageKey<bool> = StorageKey::new(0x01, 0x02);
+        const LABEL: StorageKey<String<8>> = StorageKey::new(0x01, 0x03);
+        const COUNT: StorageKey<u32> = StorageKey::public(0x01, 0x04);
+
+        init_storage(true);
+        assert_eq!(FLAG.get(), Ok(None));
+        assert_eq!(FLAG.get_or(true), Ok(true));
+        assert_eq!(FLAG.set(true), Ok(()));
+        assert_eq!(FLAG.get(), Ok(Some(true)));
+        assert_eq!(LABEL.set(String::from("label")), Ok(()));
+        assert_eq!(LABEL.get_or_default().as_deref(), Ok("label"));
+        assert_eq!(COUNT.set(7), Ok(()));
+
+        // Only the public value is readable while locked.
+        lock();
+        assert_eq!(FLAG.get(), Ok(None));
+        assert_eq!(COUNT.get_or_default(), Ok(7));
+
+        // Values of the wrong size do not decode.
+        assert!(unlock("", None));
+        assert_eq!(set(FLAG.appkey(), &[1, 0]), Ok(()));
+        assert_eq!(FLAG.get(), Err(StorageError::InvalidData));
+        assert_eq!(FLAG.delete(), Ok(()));
+        assert!(!FLAG.has());
+    }
+
+    #[test]
+    fn test_entries() {
+        init_storage(true);
+        assert_eq!(set(0x0105, b"private"), Ok(()));
+        assert_eq!(set(0x8103, b"public"), Ok(()));
+        assert_eq!(set(0xC103, b"writable"), Ok(()));
+        assert_eq!(set(0x0201, b"other app"), Ok(()));
+
+        // Overwritten values are listed once, deleted ones not at all.
+        assert_eq!(set(0x0105, b"private"), Ok(()));
+        assert_eq!(set(0x0106, b"deleted"), Ok(()));
+        assert_eq!(delete(0x0106), Ok(()));
+
+        let mut listed: Vec<_> = entries(0x01)
+            .map(|e| (e.id(), e.is_public(), e.len))
+            .collect();
+        listed.sort_unstable();
+        assert_eq!(listed, [(3, true, 6), (3, true, 8), (5, false, 7)]);
+
+        let entry = entries(0x01).find(|e| !e.is_public()).unwrap();
+        assert_eq!(entry.appkey, 0x0105);
+        let mut data = [0u8; 16];
+        assert_eq!(entry.read(&mut data), Ok(&b"private"[..]));
+
+        lock();
+        assert_eq!(entries(0x01).count(), 2);
+        assert_eq!(entries(0x03).count(), 0);
+    }
+
+    #[test]
+    fn test_wipe_code() {
+        init_storage(true);
+        assert!(change_pin("", "1234", None, None));
+        assert_eq!(has_wipe_code(), Ok(false));
+        assert_eq!(
+            change_wipe_code("1234", None, "1234"),
+            Err(StorageError::WipeCodeSameAsPin)
+        );
+        assert_eq!(
+            change_wipe_code("4321", None, "5678"),
+            Err(StorageError::InvalidPin)
+        );
+        assert_eq!(
+            change_wipe_code("1234", None, ""),
+            Err(StorageError::InvalidData)
+        );
+        assert_eq!(change_wipe_code("1234", None, "5678"), Ok(()));
+        assert_eq!(has_wipe_code(), Ok(true));
+
+        // The PIN cannot be changed to the wipe code.
+        assert!(!change_pin("1234", "5678", None, None));
+
+        lock();
+        assert_eq!(has_wipe_code(), Err(StorageError::Locked));
+        assert_eq!(remove_wipe_code("1234", None), Ok(()));
+        assert!(is_unlocked());
+        assert_eq!(has_wipe_code(), Ok(false));
+    }
 }
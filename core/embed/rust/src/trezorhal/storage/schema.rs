@@ -0,0 +1,368 @@
+//! Versioned layout of the values in storage.
+//!
+//! The firmware declares its `Schema` in `FIRMWARE`, as the ordered list of
+//! `Migration`s that lead from an empty storage to its current layout. The
+//! schema version of the storage is kept next to the values, and `unlock`
+//! applies the migrations newer than it through `Schema::migrate`. A storage
+//! with a version newer than `FIRMWARE` is left as is.
+//!
+//! Each migration is committed as a single `Transaction` together with its
+//! version, so a power loss leaves the storage either before or after it. The
+//! steps of one migration all see the storage as it was before the migration,
+//! so a key should only be touched by one of them.
+
+use super::{
+    get, has, is_unlocked,
+    key::{StorageKey, APP_RUST},
+    transaction::{recover, Transaction},
+    StorageError, StorageResult,
+};
+
+/// Schema version of the storage, a missing value means version 0. ID 0x01 of
+/// the app is the transaction journal.
+const VERSION_KEY: StorageKey<u32> = StorageKey::new(APP_RUST, 0x02);
+
+/// Migrations of the firmware. Append new ones at the end, released ones must
+/// not change.
+const FIRMWARE_MIGRATIONS: &[Migration] = &[];
+
+/// Schema of the firmware, the storage is migrated to it on every unlock.
+pub static FIRMWARE: Schema = Schema::new(FIRMWARE_MIGRATIONS);
+
+/// Size of the journal used when migrating on unlock. The changes of each
+/// migration, see `transaction::set_size`, and the journals of the other
+/// transactions, which are recovered first, must fit into it.
+pub const JOURNAL_SIZE: usize = 1024;
+
+/// Size of the largest value renamed or converted by the firmware migrations,
+/// together with its converted encoding.
+pub const BUFFER_SIZE: usize = 512;
+
+/// Conversion of a value to its new encoding. Writes the result into `buffer`
+/// or borrows it from `data`, returns an error if `data` cannot be converted.
+pub type Convert = for<'a> fn(data: &'a [u8], buffer: &'a mut [u8]) -> StorageResult<&'a [u8]>;
+
+/// A change of a single value. Values that do not exist are left alone, so the
+/// steps work regardless of which values a device has set.
+#[derive(Copy, Clone)]
+pub enum Step {
+    /// Move the value to another appkey, replacing the value stored there.
+    Rename { from: u16, to: u16 },
+    /// Change the encoding of the value.
+    Convert { appkey: u16, convert: Convert },
+    /// Delete the value.
+    Delete { appkey: u16 },
+}
+
+impl Step {
+    /// Stage the step in `transaction`. Values being renamed or converted are
+    /// read into `buffer`, which also holds the converted value.
+    pub fn stage(&self, transaction: &mut Transaction, buffer: &mut [u8]) -> StorageResult<()> {
+        match *self {
+            Self::Rename { from, to } => {
+                if has(from) {
+                    let data = get(from, buffer)?;
+                    transaction.set(to, data)?;
+                    transaction.delete(from)?;
+                }
+            }
+            Self::Convert { appkey, convert } => {
+                if has(appkey) {
+                    let len = get(appkey, buffer)?.len();
+                    let (data, rest) = buffer.split_at_mut(len);
+                    transaction.set(appkey, convert(data, rest)?)?;
+                }
+            }
+            Self::Delete { appkey } => transaction.delete(appkey)?,
+        }
+        Ok(())
+    }
+}
+
+/// Steps leading to the schema `version`.
+pub struct Migration {
+    pub version: u32,
+    pub steps: &'static [Step],
+}
+
+/// Registry of all migrations, ordered by their versions.
+pub struct Schema {
+    migrations: &'static [Migration],
+}
+
+impl Schema {
+    /// Panics if the versions of `migrations` are not increasing, or start at
+    /// 0, which is the version of a storage without any migrations applied.
+    pub const fn new(migrations: &'static [Migration]) -> Self {
+        let mut version = 0;
+        let mut i = 0;
+        while i < migrations.len() {
+            assert!(migrations[i].version > version);
+            version = migrations[i].version;
+            i += 1;
+        }
+        Self { migrations }
+    }
+
+    /// Version the migrations lead to.
+    pub fn version(&self) -> u32 {
+        self.migrations.last().map_or(0, |m| m.version)
+    }
+
+    /// Apply the migrations that are newer than the schema version of the
+    /// storage, and return the version before the migration. Called by
+    /// `unlock`, it does nothing when the storage is up to date.
+    ///
+    /// `journal` stages the changes of one migration, see `Transaction::new`,
+    /// and `buffer` holds the value being renamed or converted. Returns an
+    /// error if the storage is locked or comes from a newer firmware. If a
+    /// migration fails, the storage is left at the version before it.
+    pub fn migrate(&self, journal: &mut [u8], buffer: &mut [u8]) -> StorageResult<u32> {
+        if !is_unlocked() {
+            return Err(StorageError::Locked);
+        }
+        // Finish a migration interrupted by a power loss first.
+        recover(journal)?;
+        let stored = version()?;
+        if stored > self.version() {
+            return Err(StorageError::UnknownSchema);
+        }
+        for migration in self.migrations.iter().filter(|m| m.version > stored) {
+            let mut transaction = Transaction::new(journal);
+            for step in migration.steps {
+                step.stage(&mut transaction, buffer)?;
+            }
+            transaction.set_value(&VERSION_KEY, migration.version)?;
+            transaction.commit()?;
+        }
+        Ok(stored)
+    }
+
+    /// `migrate` with buffers of `JOURNAL_SIZE` and `BUFFER_SIZE`.
+    pub(super) fn migrate_on_unlock(&self) -> StorageResult<u32> {
+        let mut journal = [0u8; JOURNAL_SIZE];
+        let mut buffer = [0u8; BUFFER_SIZE];
+        self.migrate(&mut journal, &mut buffer)
+    }
+}
+
+/// Schema version of the storage. Returns an error if the storage is locked.
+pub fn version() -> StorageResult<u32> {
+    if !is_unlocked() {
+        return Err(StorageError::Locked);
+    }
+    VERSION_KEY.get_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            delete, init, is_unlocked, lock, set,
+            testutil::{blank, is_state},
+            unlock, unlock_to_schema,
+        },
+        *,
+    };
+
+    const KEYS: [u16; 3] = [KEY_A, KEY_B, KEY_C];
+    const KEY_A: u16 = 0x0301;
+    const KEY_B: u16 = 0x0302;
+    const KEY_C: u16 = 0x0303;
+
+    const OLD: [Option<&[u8]>; 3] = [Some(&[7]), Some(b"b"), None];
+    const NEW: [Option<&[u8]>; 3] = [None, None, Some(&[0, 7])];
+
+    const MIGRATIONS: &[Migration] = &[
+        Migration {
+            version: 1,
+            steps: &[
+                Step::Rename {
+                    from: KEY_A,
+                    to: KEY_C,
+                },
+                Step::Delete { appkey: KEY_B },
+            ],
+        },
+        Migration {
+            version: 3,
+            steps: &[Step::Convert {
+                appkey: KEY_C,
+                convert: widen,
+            }],
+        },
+    ];
+
+    static SCHEMA: Schema = Schema::new(MIGRATIONS);
+
+    /// Store a `u8` as a big-endian `u16`.
+    fn widen<'a>(data: &'a [u8], buffer: &'a mut [u8]) -> StorageResult<&'a [u8]> {
+        let widened = buffer.get_mut(..2).ok_or(StorageError::InvalidData)?;
+        match data {
+            [value] => {
+                widened.copy_from_slice(&[0, *value]);
+                Ok(widened)
+            }
+            _ => Err(StorageError::InvalidData),
+        }
+    }
+
+    fn commit(step: Step) -> StorageResult<()> {
+        let mut journal = [0u8; 64];
+        let mut buffer = [0u8; 16];
+        let mut transaction = Transaction::new(&mut journal);
+        step.stage(&mut transaction, &mut buffer)?;
+        transaction.commit()
+    }
+
+    fn migrate(schema: &Schema) -> StorageResult<u32> {
+        let mut journal = [0u8; 64];
+        let mut buffer = [0u8; 16];
+        schema.migrate(&mut journal, &mut buffer)
+    }
+
+    #[test]
+    fn test_steps() {
+        blank();
+        assert_eq!(set(KEY_A, &[7]), Ok(()));
+        assert_eq!(set(KEY_B, b"b"), Ok(()));
+
+        assert_eq!(
+            commit(Step::Rename {
+                from: KEY_A,
+                to: KEY_C
+            }),
+            Ok(())
+        );
+        assert!(is_state(KEYS, [None, Some(b"b"), Some(&[7])]));
+        assert_eq!(
+            commit(Step::Convert {
+                appkey: KEY_C,
+                convert: widen
+            }),
+            Ok(())
+        );
+        assert!(is_state(KEYS, [None, Some(b"b"), Some(&[0, 7])]));
+        assert_eq!(commit(Step::Delete { appkey: KEY_B }), Ok(()));
+        assert!(is_state(KEYS, [None, None, Some(&[0, 7])]));
+
+        // Missing values are left alone.
+        assert_eq!(
+            commit(Step::Rename {
+                from: KEY_A,
+                to: KEY_C
+            }),
+            Ok(())
+        );
+        assert_eq!(
+            commit(Step::Convert {
+                appkey: KEY_B,
+                convert: widen
+            }),
+            Ok(())
+        );
+        assert_eq!(commit(Step::Delete { appkey: KEY_B }), Ok(()));
+        assert!(is_state(KEYS, [None, None, Some(&[0, 7])]));
+
+        // A failed conversion does not change the value.
+        assert_eq!(
+            commit(Step::Convert {
+                appkey: KEY_C,
+                convert: widen
+            }),
+            Err(StorageError::InvalidData)
+        );
+        assert!(is_state(KEYS, [None, None, Some(&[0, 7])]));
+    }
+
+    fn prepare() {
+        assert_eq!(VERSION_KEY.set(0), Ok(()));
+        assert_eq!(set(KEY_A, &[7]), Ok(()));
+        assert_eq!(set(KEY_B, b"b"), Ok(()));
+    }
+
+    fn restart(schema: &Schema) -> bool {
+        init();
+        unlock_to_schema(schema, "", None)
+    }
+
+    #[test]
+    fn test_migrate() {
+        blank();
+        // A new storage is migrated on unlock.
+        lock();
+        assert!(restart(&SCHEMA));
+        assert_eq!(version(), Ok(3));
+        assert_eq!(SCHEMA.version(), 3);
+
+        prepare();
+        assert!(is_state(KEYS, OLD));
+        assert_eq!(migrate(&SCHEMA), Ok(0));
+        assert_eq!(version(), Ok(3));
+        assert!(is_state(KEYS, NEW));
+
+        // The migrations only run once.
+        assert_eq!(migrate(&SCHEMA), Ok(3));
+        assert!(is_state(KEYS, NEW));
+
+        // Only the newer migrations run.
+        assert_eq!(VERSION_KEY.set(1), Ok(()));
+        assert_eq!(set(KEY_C, &[8]), Ok(()));
+        assert_eq!(migrate(&SCHEMA), Ok(1));
+        assert!(is_state(KEYS, [None, None, Some(&[0, 8])]));
+
+        // Unlocking migrates the storage.
+        assert_eq!(VERSION_KEY.set(1), Ok(()));
+        assert_eq!(set(KEY_C, &[9]), Ok(()));
+        lock();
+        assert!(restart(&SCHEMA));
+        assert_eq!(version(), Ok(3));
+        assert!(is_state(KEYS, [None, None, Some(&[0, 9])]));
+
+        // A failed migration keeps the previous version.
+        assert_eq!(VERSION_KEY.set(1), Ok(()));
+        assert_eq!(migrate(&SCHEMA), Err(StorageError::InvalidData));
+        assert_eq!(version(), Ok(1));
+
+        // Unlocking reports the failed migration and leaves the storage locked.
+        // The empty firmware schema is older, it leaves the storage as is.
+        lock();
+        assert!(!restart(&SCHEMA));
+        assert!(!is_unlocked());
+        assert!(unlock("", None));
+        assert_eq!(version(), Ok(1));
+        assert_eq!(delete(KEY_C), Ok(()));
+        assert_eq!(migrate(&SCHEMA), Ok(1));
+
+        // The storage was migrated by a newer firmware, unlocking leaves it as
+        // is.
+        let older = Schema::new(&MIGRATIONS[..1]);
+        assert_eq!(migrate(&older), Err(StorageError::UnknownSchema));
+        lock();
+        assert!(restart(&older));
+        assert_eq!(version(), Ok(3));
+
+        lock();
+        assert_eq!(version(), Err(StorageError::Locked));
+        assert_eq!(migrate(&SCHEMA), Err(StorageError::Locked));
+    }
+
+    #[test]
+    #[cfg(not(feature = "storage_memory"))]
+    fn test_power_loss() {
+        use super::super::testutil::for_each_power_loss;
+
+        for_each_power_loss(
+            "trezorhal::storage::schema::tests::test_power_loss",
+            prepare,
+            || migrate(&SCHEMA),
+            |result| {
+                // Migrating again finishes an interrupted migration.
+                assert!(matches!(result, None | Some(Ok(0))));
+                assert!(matches!(migrate(&SCHEMA), Ok(0 | 1 | 3)));
+                assert_eq!(version(), Ok(3));
+                assert!(is_state(KEYS, NEW));
+            },
+        );
+    }
+}
@@ -0,0 +1,153 @@
+//! Fixture of the storage tests, on both the in-memory storage and the flash
+//! of the emulator.
+//!
+//! `for_each_power_loss` needs the emulated flash. A power loss in the middle
+//! of a single storage write leaves a torn value, which `storage.c` detects
+//! on the next unlock and halts with a fault, same as on the device. The power
+//! is therefore only lost between the writes, by restoring the flash as it was
+//! before the first flash write of the storage write that is cut off.
+
+use std::vec::Vec;
+#[cfg(not(feature = "storage_memory"))]
+use std::{env, process, process::Command};
+
+#[cfg(feature = "storage_memory")]
+use super::ffi;
+use super::{get, init, lock, unlock, wipe};
+
+/// The C storage, with the writes counted for `for_each_power_loss`. The
+/// storage functions of the tests go through this module.
+#[cfg(not(feature = "storage_memory"))]
+pub mod ffi {
+    pub use crate::trezorhal::ffi::*;
+
+    use crate::trezorhal::ffi as c;
+
+    /// Storage writes left until the power loss.
+    static mut WRITES_LEFT: Option<u32> = None;
+
+    pub fn power_loss_after(writes: u32) {
+        // SAFETY: The storage tests run in a single thread.
+        unsafe { WRITES_LEFT = Some(writes) };
+    }
+
+    /// Lose the power at the first flash write of the storage write about to
+    /// start, if it is the one.
+    fn count_write() {
+        // SAFETY: The storage tests run in a single thread.
+        unsafe {
+            match WRITES_LEFT {
+                Some(0) => {
+                    WRITES_LEFT = None;
+                    c::flash_power_loss_after(0);
+                }
+                Some(writes) => WRITES_LEFT = Some(writes - 1),
+                None => {}
+            }
+        }
+    }
+
+    pub unsafe fn storage_set(key: u16, val: *const cty::c_void, len: u16) -> secbool {
+        count_write();
+        unsafe { c::storage_set(key, val, len) }
+    }
+
+    pub unsafe fn storage_delete(key: u16) -> secbool {
+        count_write();
+        unsafe { c::storage_delete(key) }
+    }
+
+    pub unsafe fn storage_set_counter(key: u16, count: u32) -> secbool {
+        count_write();
+        unsafe { c::storage_set_counter(key, count) }
+    }
+
+    pub unsafe fn storage_next_counter(key: u16, count: *mut u32) -> secbool {
+        count_write();
+        unsafe { c::storage_next_counter(key, count) }
+    }
+}
+
+/// Start with an empty storage, unlocked with the empty PIN.
+pub fn blank() {
+    unsafe { ffi::flash_init() };
+    init();
+    wipe();
+    lock();
+    restart();
+}
+
+/// Power the storage on again and unlock it with the empty PIN.
+pub fn restart() {
+    init();
+    assert!(unlock("", None));
+}
+
+pub fn read(appkey: u16) -> Option<Vec<u8>> {
+    let mut data = [0u8; 16];
+    get(appkey, &mut data).ok().map(<[u8]>::to_vec)
+}
+
+/// Check that each of `appkeys` holds the value in `values`.
+pub fn is_state<const N: usize>(appkeys: [u16; N], values: [Option<&[u8]>; N]) -> bool {
+    appkeys.map(read) == values.map(|v| v.map(<[u8]>::to_vec))
+}
+
+/// Number of storage writes before the power loss, set for the test processes
+/// started by `for_each_power_loss`.
+#[cfg(not(feature = "storage_memory"))]
+const WRITES_VAR: &str = "STORAGE_POWER_LOSS_AFTER";
+
+/// Exit codes of the test processes, depending on whether `update` was done
+/// before the power loss.
+#[cfg(not(feature = "storage_memory"))]
+const EXIT_DONE: i32 = 42;
+#[cfg(not(feature = "storage_memory"))]
+const EXIT_INTERRUPTED: i32 = 43;
+
+/// Run `update` on the storage left by `prepare`, losing the power after each
+/// number of storage writes in turn, until `update` is done before the power
+/// loss. After each run, the storage is restarted and `check` gets the result
+/// of `update`, or `None` if the power was lost.
+///
+/// Each run happens in a new process of the test `test`, so that a fault of
+/// `storage.c` or a failed check ends only that run.
+#[cfg(not(feature = "storage_memory"))]
+pub fn for_each_power_loss<T>(
+    test: &str,
+    prepare: impl FnOnce(),
+    update: impl FnOnce() -> T,
+    check: impl FnOnce(Option<T>),
+) {
+    if let Ok(writes) = env::var(WRITES_VAR) {
+        blank();
+        prepare();
+        ffi::power_loss_after(writes.parse().unwrap());
+        let result = update();
+        let interrupted = ffi::sectrue == unsafe { ffi::flash_power_loss() };
+        restart();
+        check((!interrupted).then_some(result));
+        process::exit(if interrupted {
+            EXIT_INTERRUPTED
+        } else {
+            EXIT_DONE
+        });
+    }
+
+    for writes in 0.. {
+        let output = Command::new(env::current_exe().unwrap())
+            .args([test, "--exact", "--test-threads=1"])
+            .env(WRITES_VAR, writes.to_string())
+            .output()
+            .unwrap();
+        match output.status.code() {
+            Some(EXIT_DONE) => break,
+            Some(EXIT_INTERRUPTED) => {}
+            _ => panic!(
+                "power loss after {} writes:\n{}",
+                writes,
+                String::from_utf8_lossy(&output.stdout)
+            ),
+        }
+    }
+}
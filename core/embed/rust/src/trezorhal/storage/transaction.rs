@@ -0,0 +1,245 @@
+//! Atomic updates of several storage values.
+//!
+//! A `Transaction` stages sets and deletes in a journal. On commit, the
+//! journal is written to storage as a single value first, followed by a public
+//! marker holding its length. Only then are the changes applied one by one and
+//! the marker and the journal deleted. If the power is lost in between,
+//! `recover` applies a journal with a matching marker again and drops one
+//! without it unread, so either all or none of the changes survive. A power
+//! loss in the middle of a single storage write is not covered, `storage.c`
+//! finds the torn value and halts with a fault.
+//!
+//! The journal is a private value, so committing and recovering a transaction
+//! needs the storage unlocked.
+
+use super::{
+    delete, get, get_length, has, is_unlocked,
+    key::{StorageKey, StorageValue, APP_RUST},
+    set, StorageError, StorageResult,
+};
+
+/// Appkey of the journal of the transaction being committed.
+const JOURNAL_KEY: u16 = u16::from_be_bytes([APP_RUST, 0x01]);
+
+/// Length of the journal, written once the journal is complete.
+const COMMITTED: StorageKey<u16> = StorageKey::public(APP_RUST, 0x01);
+
+/// Journal records start with the operation and the big-endian appkey. Sets
+/// continue with the big-endian length and the data.
+const OP_SET: u8 = 1;
+const OP_DELETE: u8 = 2;
+
+/// Journal size of a set of `len` bytes.
+pub const fn set_size(len: usize) -> usize {
+    5 + len
+}
+
+/// Journal size of a delete.
+pub const DELETE_SIZE: usize = 3;
+
+/// Sets and deletes to be committed together.
+pub struct Transaction<'a> {
+    journal: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Transaction<'a> {
+    /// Start a transaction, staging the changes in `buffer`. See `set_size`
+    /// and `DELETE_SIZE` for the space it needs.
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self {
+            journal: buffer,
+            len: 0,
+        }
+    }
+
+    /// Stage setting `appkey` to `data`. Returns an error if the journal
+    /// buffer is full.
+    pub fn set(&mut self, appkey: u16, data: &[u8]) -> StorageResult<()> {
+        let len = u16::try_from(data.len()).map_err(|_| StorageError::InvalidData)?;
+        let [key_hi, key_lo] = appkey.to_be_bytes();
+        let [len_hi, len_lo] = len.to_be_bytes();
+        self.push(&[OP_SET, key_hi, key_lo, len_hi, len_lo], data)
+    }
+
+    /// Stage setting the typed `key` to `value`.
+    pub fn set_value<T: StorageValue>(
+        &mut self,
+        key: &StorageKey<T>,
+        value: T,
+    ) -> StorageResult<()> {
+        let mut buffer = T::buffer();
+        self.set(key.appkey(), value.encode(&mut buffer))
+    }
+
+    /// Stage deleting `appkey`. Deleting a missing value is not an error.
+    pub fn delete(&mut self, appkey: u16) -> StorageResult<()> {
+        let [key_hi, key_lo] = appkey.to_be_bytes();
+        self.push(&[OP_DELETE, key_hi, key_lo], &[])
+    }
+
+    /// Write all staged changes. A transaction left unfinished by a power
+    /// loss is recovered first, using the journal buffer. If this returns an
+    /// error after the journal was written, the changes are still applied by
+    /// the next `recover`.
+    pub fn commit(self) -> StorageResult<()> {
+        let (journal, buffer) = self.journal.split_at_mut(self.len);
+        recover(buffer)?;
+        if journal.is_empty() {
+            return Ok(());
+        }
+        let len = u16::try_from(journal.len()).map_err(|_| StorageError::InvalidData)?;
+        set(JOURNAL_KEY, journal)?;
+        COMMITTED.set(len)?;
+        apply(journal)?;
+        finish()
+    }
+
+    fn push(&mut self, header: &[u8], data: &[u8]) -> StorageResult<()> {
+        let start = self.len;
+        let end = start + header.len() + data.len();
+        let record = self
+            .journal
+            .get_mut(start..end)
+            .ok_or(StorageError::InvalidData)?;
+        let (record_header, record_data) = record.split_at_mut(header.len());
+        record_header.copy_from_slice(header);
+        record_data.copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// Finish a transaction interrupted by a power loss, reading its journal into
+/// `buffer`. Called by `unlock` before the values are used, see
+/// `schema::JOURNAL_SIZE`. Returns an error if the journal does not fit into
+/// `buffer`.
+pub fn recover(buffer: &mut [u8]) -> StorageResult<()> {
+    if !is_unlocked() {
+        return Err(StorageError::Locked);
+    }
+    // A missing or torn marker means that the journal may be incomplete, it is
+    // deleted without being read.
+    let committed = COMMITTED.get().unwrap_or(None).map(usize::from);
+    if committed.is_some() && get_length(JOURNAL_KEY).ok() == committed {
+        let journal = get(JOURNAL_KEY, buffer)?;
+        apply(journal)?;
+    }
+    finish()
+}
+
+/// Delete the marker and the journal, in this order so that a journal is never
+/// left with a marker after a power loss.
+fn finish() -> StorageResult<()> {
+    if COMMITTED.has() {
+        COMMITTED.delete()?;
+    }
+    if has(JOURNAL_KEY) {
+        delete(JOURNAL_KEY)?;
+    }
+    Ok(())
+}
+
+/// Apply the journal records. Doing it more than once has the same result.
+fn apply(mut journal: &[u8]) -> StorageResult<()> {
+    while let [op, key_hi, key_lo, rest @ ..] = journal {
+        let appkey = u16::from_be_bytes([*key_hi, *key_lo]);
+        journal = match (*op, rest) {
+            (OP_SET, [len_hi, len_lo, rest @ ..]) => {
+                let len = u16::from_be_bytes([*len_hi, *len_lo]) as usize;
+                let data = rest.get(..len).ok_or(StorageError::InvalidData)?;
+                set(appkey, data)?;
+                &rest[len..]
+            }
+            (OP_DELETE, _) => {
+                if has(appkey) {
+                    delete(appkey)?;
+                }
+                rest
+            }
+            _ => return Err(StorageError::InvalidData),
+        };
+    }
+    if journal.is_empty() {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidData)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::{
+            lock,
+            testutil::{blank, is_state, read},
+        },
+        *,
+    };
+
+    const KEYS: [u16; 3] = [KEY_A, KEY_B, KEY_C];
+    const KEY_A: u16 = 0x0101;
+    const KEY_B: u16 = 0x0102;
+    const KEY_C: u16 = 0x8103;
+
+    const OLD: [Option<&[u8]>; 3] = [Some(b"old a"), Some(b"old b"), None];
+    const NEW: [Option<&[u8]>; 3] = [Some(b"new a"), None, Some(b"new c")];
+
+    fn commit() -> StorageResult<()> {
+        let mut buffer = [0u8; 64];
+        let mut transaction = Transaction::new(&mut buffer);
+        transaction.set(KEY_A, b"new a")?;
+        transaction.delete(KEY_B)?;
+        transaction.delete(KEY_C)?;
+        transaction.set(KEY_C, b"new c")?;
+        transaction.commit()
+    }
+
+    #[test]
+    fn test_commit() {
+        blank();
+        assert_eq!(commit(), Ok(()));
+        assert!(is_state(KEYS, NEW));
+        assert!(!has(JOURNAL_KEY) && !COMMITTED.has());
+
+        // The staged changes must fit into the buffer.
+        let mut buffer = [0u8; 8];
+        let mut transaction = Transaction::new(&mut buffer);
+        assert_eq!(transaction.delete(KEY_A), Ok(()));
+        assert_eq!(
+            transaction.set(KEY_B, b"big"),
+            Err(StorageError::InvalidData)
+        );
+        assert_eq!(transaction.set(KEY_B, b""), Ok(()));
+        assert_eq!(transaction.commit(), Ok(()));
+        assert_eq!(read(KEY_A), None);
+        assert_eq!(get_length(KEY_B), Ok(0));
+
+        // The journal is private, the storage must be unlocked.
+        lock();
+        assert_eq!(recover(&mut [0u8; 64]), Err(StorageError::Locked));
+        assert_eq!(commit(), Err(StorageError::Locked));
+    }
+
+    #[test]
+    #[cfg(not(feature = "storage_memory"))]
+    fn test_power_loss() {
+        use super::super::testutil::for_each_power_loss;
+
+        for_each_power_loss(
+            "trezorhal::storage::transaction::tests::test_power_loss",
+            || {
+                assert_eq!(set(KEY_A, b"old a"), Ok(()));
+                assert_eq!(set(KEY_B, b"old b"), Ok(()));
+            },
+            commit,
+            |result| {
+                let mut buffer = [0u8; 64];
+                assert_eq!(recover(&mut buffer), Ok(()));
+                assert!(matches!(result, None | Some(Ok(()))));
+                assert!(is_state(KEYS, NEW) || result.is_none() && is_state(KEYS, OLD));
+                assert!(!has(JOURNAL_KEY) && !COMMITTED.has());
+            },
+        );
+    }
+}
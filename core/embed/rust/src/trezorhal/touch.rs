@@ -0,0 +1,8 @@
+use super::ffi;
+
+/// Read the next touch event. The event type is in the top byte, followed by
+/// 12 bits of the X and 12 bits of the Y coordinate. Returns 0 if there is no
+/// new event.
+pub fn read() -> u32 {
+    unsafe { ffi::touch_read() }
+}
@@ -37,6 +37,11 @@ pub fn set_backlight(val: i32) {
     display::backlight(val);
 }
 
+/// Show the painted content, only needed outside of the layout event loop.
+pub fn refresh() {
+    display::refresh();
+}
+
 pub fn fade_backlight(target: i32) {
     const BACKLIGHT_DELAY: Duration = Duration::from_millis(14);
     const BACKLIGHT_STEP: usize = 15;
@@ -1,3 +1,7 @@
+#[cfg(feature = "buttons")]
+use crate::trezorhal::button;
+#[cfg(feature = "touch")]
+use crate::trezorhal::touch;
 use crate::{error, ui::geometry::Point};
 use core::convert::TryInto;
 
@@ -27,6 +31,13 @@ impl ButtonEvent {
         };
         Ok(result)
     }
+
+    /// Read the next event from the button driver, if any.
+    #[cfg(feature = "buttons")]
+    pub fn poll() -> Option<Self> {
+        let event = button::read();
+        Self::new(event >> 24, event & 0xFF_FFFF).ok()
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -52,4 +63,11 @@ impl TouchEvent {
         };
         Ok(result)
     }
+
+    /// Read the next event from the touch driver, if any.
+    #[cfg(feature = "touch")]
+    pub fn poll() -> Option<Self> {
+        let event = touch::read();
+        Self::new(event >> 24, (event >> 12) & 0xFFF, event & 0xFFF).ok()
+    }
 }
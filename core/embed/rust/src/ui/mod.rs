@@ -8,6 +8,7 @@ pub mod display;
 pub mod event;
 pub mod geometry;
 pub mod lerp;
+pub mod pin_delay;
 mod util;
 
 #[cfg(feature = "micropython")]
@@ -1,5 +1,6 @@
 pub mod component;
 pub mod constant;
+pub mod pin_delay;
 pub mod theme;
 
 #[cfg(feature = "micropython")]
@@ -0,0 +1,30 @@
+//! PIN delay screen, see `ui::pin_delay`.
+
+use crate::{
+    trezorhal::storage::PinCallbackResult,
+    ui::{
+        geometry::{Point, Rect},
+        pin_delay::{self, Layout, Progress},
+    },
+};
+
+use super::{
+    constant::{screen, HEIGHT, WIDTH},
+    theme::{BG, FG},
+};
+
+const LAYOUT: Layout = Layout {
+    screen: screen(),
+    fg: FG,
+    bg: BG,
+    title_y: 12,
+    seconds: Rect::new(Point::new(0, HEIGHT - 24), Point::new(WIDTH, HEIGHT)),
+    seconds_y: HEIGHT - 12,
+    progress: Progress::Loader,
+};
+
+/// Show the message on top, the progress in the loader and the remaining
+/// seconds below it. Can be used as the `PinDelayCallback` directly.
+pub fn callback(wait: u32, progress: u32, message: &str) -> PinCallbackResult {
+    pin_delay::paint(&LAYOUT, wait, progress, message)
+}
@@ -1,6 +1,7 @@
 pub mod component;
 pub mod constant;
 pub mod event;
+pub mod pin_delay;
 pub mod theme;
 
 #[cfg(feature = "micropython")]
@@ -0,0 +1,202 @@
+//! Progress screen shown while the storage delays the PIN verification.
+//!
+//! The storage reports the remaining seconds, the progress and a message
+//! through the `PinDelayCallback`. Each model describes where the parts of the
+//! screen go in a `Layout` and passes it to `paint` in its
+//! `pin_delay::callback`, which can be passed to
+//! `storage::set_pin_delay_callback` directly. Same as in `trezor/pin.py`, the
+//! message and the remaining seconds are only repainted when they change.
+
+use core::fmt::Write;
+
+use heapless::String;
+
+#[cfg(feature = "buttons")]
+use crate::trezorhal::button;
+#[cfg(feature = "touch")]
+use crate::trezorhal::touch;
+#[cfg(feature = "buttons")]
+use crate::ui::event::ButtonEvent;
+#[cfg(feature = "touch")]
+use crate::ui::event::TouchEvent;
+use crate::{
+    trezorhal::storage::PinCallbackResult,
+    ui::{
+        display::{self, Color, Font},
+        geometry::{Point, Rect},
+    },
+};
+
+/// Maximum progress reported by the storage.
+const PROGRESS_MAX: u32 = 1000;
+
+/// Where a model paints the parts of the PIN delay screen.
+pub struct Layout {
+    /// Area cleared when a new delay starts.
+    pub screen: Rect,
+    pub fg: Color,
+    pub bg: Color,
+    /// Baseline of the message, centered horizontally on the screen.
+    pub title_y: i16,
+    /// Area cleared before showing the remaining seconds.
+    pub seconds: Rect,
+    /// Baseline of the remaining seconds, centered horizontally on the screen.
+    pub seconds_y: i16,
+    pub progress: Progress,
+}
+
+/// How the progress of the delay is shown.
+pub enum Progress {
+    /// Circular loader in the middle of the screen.
+    Loader,
+    /// Horizontal bar filling up from the left.
+    Bar(Rect),
+}
+
+/// Paint the reported state of the delay according to `layout`, and return
+/// whether the user wants to abort the delay.
+pub fn paint(layout: &Layout, wait: u32, progress: u32, message: &str) -> PinCallbackResult {
+    let repaint = update(wait, progress);
+    let center_x = layout.screen.center().x;
+    if repaint.message {
+        // Input from before the delay must not abort it.
+        drain_input();
+        display::rect_fill(layout.screen, layout.bg);
+        display::text_center(
+            Point::new(center_x, layout.title_y),
+            message,
+            Font::BOLD,
+            layout.fg,
+            layout.bg,
+        );
+    }
+    match layout.progress {
+        Progress::Loader => display::loader(progress as u16, 0, layout.fg, layout.bg, None),
+        Progress::Bar(bar) => {
+            let fill_to = (bar.width() as u32 + 1) * progress.min(PROGRESS_MAX) / PROGRESS_MAX;
+            display::bar_with_text_and_fill(bar, None, layout.fg, layout.bg, -1, fill_to as i16);
+        }
+    }
+    if let Some(seconds) = repaint.seconds {
+        display::rect_fill(layout.seconds, layout.bg);
+        display::text_center(
+            Point::new(center_x, layout.seconds_y),
+            &seconds_left(seconds),
+            Font::NORMAL,
+            layout.fg,
+            layout.bg,
+        );
+    }
+    display::refresh();
+    let result = poll_abort();
+    if result == PinCallbackResult::Abort {
+        // The next delay starts over.
+        reset();
+    }
+    result
+}
+
+/// Parts of the screen to repaint after a progress report.
+pub struct Repaint {
+    /// Clear the screen and show the message.
+    pub message: bool,
+    /// Show the remaining seconds.
+    pub seconds: Option<u32>,
+}
+
+/// Remaining seconds and progress of the previous report.
+static mut PREVIOUS: Option<(u32, u32)> = None;
+
+/// Remember the reported `wait` and `progress`, and return what changed since
+/// the previous report. A progress of 0 starts a new delay, so the whole
+/// screen is repainted. Once the delay is over, the next report starts a new
+/// one.
+fn update(wait: u32, progress: u32) -> Repaint {
+    let current = (progress < PROGRESS_MAX).then_some((wait, progress));
+    // SAFETY: The storage callback is only invoked from the main thread.
+    let previous = unsafe {
+        let previous = PREVIOUS;
+        PREVIOUS = current;
+        previous
+    };
+    let message = progress == 0 && previous.map(|(_, p)| p) != Some(0);
+    let seconds_changed = message || previous.map(|(w, _)| w) != Some(wait);
+    Repaint {
+        message,
+        seconds: seconds_changed.then_some(wait),
+    }
+}
+
+/// Forget the previous report, so that the next one starts a new delay.
+fn reset() {
+    // SAFETY: The storage callback is only invoked from the main thread.
+    unsafe { PREVIOUS = None };
+}
+
+/// Text showing the remaining `seconds` of the delay.
+fn seconds_left(seconds: u32) -> String<24> {
+    let mut text = String::new();
+    // The longest text fits into the capacity.
+    let _ = match seconds {
+        0 => write!(text, "Done"),
+        1 => write!(text, "1 second left"),
+        _ => write!(text, "{} seconds left", seconds),
+    };
+    text
+}
+
+/// Drop the events waiting in the input drivers.
+fn drain_input() {
+    #[cfg(feature = "touch")]
+    while touch::read() != 0 {}
+    #[cfg(feature = "buttons")]
+    while button::read() != 0 {}
+}
+
+/// Abort the delay if the user tapped the screen or released a button since
+/// the previous report.
+fn poll_abort() -> PinCallbackResult {
+    #[cfg(feature = "touch")]
+    if let Some(TouchEvent::TouchEnd(_)) = TouchEvent::poll() {
+        return PinCallbackResult::Abort;
+    }
+    #[cfg(feature = "buttons")]
+    if let Some(ButtonEvent::ButtonReleased(_)) = ButtonEvent::poll() {
+        return PinCallbackResult::Abort;
+    }
+    PinCallbackResult::Continue
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repaint(wait: u32, progress: u32) -> (bool, Option<u32>) {
+        let repaint = update(wait, progress);
+        (repaint.message, repaint.seconds)
+    }
+
+    #[test]
+    fn test_update() {
+        reset();
+        assert_eq!(repaint(3, 0), (true, Some(3)));
+        // The user aborts at the first report, the next delay starts over.
+        reset();
+        assert_eq!(repaint(3, 0), (true, Some(3)));
+        assert_eq!(repaint(3, 100), (false, None));
+        assert_eq!(repaint(2, 400), (false, Some(2)));
+        assert_eq!(repaint(0, 1000), (false, Some(0)));
+        // Another delay starts, again at the same number of seconds.
+        assert_eq!(repaint(3, 0), (true, Some(3)));
+        assert_eq!(repaint(3, 1000), (false, None));
+        assert_eq!(repaint(3, 0), (true, Some(3)));
+    }
+
+    #[test]
+    fn test_seconds_left() {
+        assert_eq!(seconds_left(0), "Done");
+        assert_eq!(seconds_left(1), "1 second left");
+        assert_eq!(seconds_left(15), "15 seconds left");
+        assert_eq!(seconds_left(u32::MAX), "4294967295 seconds left");
+    }
+}
@@ -104,6 +104,14 @@ static inline secbool flash_erase(uint8_t sector) {
 secbool __wur flash_write_byte(uint8_t sector, uint32_t offset, uint8_t data);
 secbool __wur flash_write_word(uint8_t sector, uint32_t offset, uint32_t data);
 
+#ifdef TREZOR_EMULATOR
+// Emulated power loss for tests. Only the next `writes` byte or word writes and
+// sector erases are kept by flash_power_loss(), which reverts the later ones
+// and returns secfalse if there were not that many.
+void flash_power_loss_after(int32_t writes);
+secbool flash_power_loss(void);
+#endif
+
 #define FLASH_OTP_NUM_BLOCKS 16
 #define FLASH_OTP_BLOCK_SIZE 32
 
@@ -92,6 +92,23 @@ const uint8_t STORAGE_SECTORS[STORAGE_SECTORS_COUNT] = {
 static uint8_t *FLASH_BUFFER = NULL;
 static uint32_t FLASH_SIZE;
 
+// Emulated power loss, see flash_power_loss_after().
+static int32_t WRITES_LEFT = -1;
+static uint8_t *POWER_LOSS_BUFFER = NULL;
+
+// Called before every write and erase. Saves the contents of the flash once
+// all of the writes before the power loss are done.
+static void count_write(void) {
+  if (WRITES_LEFT == 0) {
+    POWER_LOSS_BUFFER = malloc(FLASH_SIZE);
+    ensure(sectrue * (POWER_LOSS_BUFFER != NULL), "malloc failed");
+    memcpy(POWER_LOSS_BUFFER, FLASH_BUFFER, FLASH_SIZE);
+  }
+  if (WRITES_LEFT >= 0) {
+    WRITES_LEFT--;
+  }
+}
+
 static void flash_exit(void) {
   int r = munmap(FLASH_BUFFER, FLASH_SIZE);
   ensure(sectrue * (r == 0), "munmap failed");
@@ -166,6 +183,7 @@ secbool flash_erase_sectors(const uint8_t *sectors, int len,
     const uint32_t offset = FLASH_SECTOR_TABLE[sector] - FLASH_SECTOR_TABLE[0];
     const uint32_t size =
         FLASH_SECTOR_TABLE[sector + 1] - FLASH_SECTOR_TABLE[sector];
+    count_write();
     memset(FLASH_BUFFER + offset, 0xFF, size);
     if (progress) {
       progress(i + 1, len);
@@ -182,6 +200,7 @@ secbool flash_write_byte(uint8_t sector, uint32_t offset, uint8_t data) {
   if ((flash[0] & data) != data) {
     return secfalse;  // we cannot change zeroes to ones
   }
+  count_write();
   flash[0] = data;
   return sectrue;
 }
@@ -197,10 +216,28 @@ secbool flash_write_word(uint8_t sector, uint32_t offset, uint32_t data) {
   if ((flash[0] & data) != data) {
     return secfalse;  // we cannot change zeroes to ones
   }
+  count_write();
   flash[0] = data;
   return sectrue;
 }
 
+void flash_power_loss_after(int32_t writes) {
+  free(POWER_LOSS_BUFFER);
+  POWER_LOSS_BUFFER = NULL;
+  WRITES_LEFT = writes;
+}
+
+secbool flash_power_loss(void) {
+  WRITES_LEFT = -1;
+  if (POWER_LOSS_BUFFER == NULL) {
+    return secfalse;
+  }
+  memcpy(FLASH_BUFFER, POWER_LOSS_BUFFER, FLASH_SIZE);
+  free(POWER_LOSS_BUFFER);
+  POWER_LOSS_BUFFER = NULL;
+  return sectrue;
+}
+
 secbool flash_otp_read(uint8_t block, uint8_t offset, uint8_t *data,
                        uint8_t datalen) {
   return secfalse;
@@ -19,8 +19,24 @@ def decode(
     buffer: bytes,
     msg_type: Type[T],
     enable_experimental: bool,
+    *,
+    preserve_unknown: bool = False,
+    strict: bool = False,
+    zero_copy: bool = False,
+    limits: bool = False,
 ) -> T:
-    """Decode data in the buffer into the specified message type."""
+    """Decode data in the buffer into the specified message type.
+    If `preserve_unknown` is set, fields missing from the message definition
+    are kept on the message and emitted again by `encode`.
+    If `strict` is set, only the canonical encoding is accepted. Non-minimal
+    varints, repeated occurrences of a singular field and trailing garbage
+    after the last field raise `ValueError`.
+    If `zero_copy` is set, bytes fields are returned as read-only memoryviews
+    into `buffer`. The buffer then must be kept alive and unmodified for as
+    long as the message is in use.
+    If `limits` is set, the nesting depth, the number of repeated values
+    and the allocated memory are bounded the same as for messages read
+    from the wire. Exceeding them raises `ValueError`."""
 
 
 # extmod/rustmods/modtrezorproto.c
@@ -32,3 +48,10 @@ def encoded_length(msg: MessageType) -> int:
 def encode(buffer: bytearray, msg: MessageType) -> int:
     """Encode the message into the specified buffer. Return length of
     encoding."""
+
+
+# extmod/rustmods/modtrezorproto.c
+def dump(msg: MessageType, *, json: bool = False) -> str:
+    """Render the message in the protobuf text format, or in JSON if `json`
+    is set. Enums are shown by name, bytes as hex strings. Only available
+    in debug builds."""
@@ -23,10 +23,17 @@ if TYPE_CHECKING:
 
 <%
 required_fields = [f for f in message.fields if f.required]
-repeated_fields = [f for f in message.fields if f.repeated]
+repeated_fields = [f for f in message.fields if f.repeated and not f.is_map]
+map_fields = [f for f in message.fields if f.is_map]
 optional_fields = [f for f in message.fields if f.optional]
 
+def map_type(field):
+    key, value = field.map_fields
+    return f"dict[{key.python_type}, {value.python_type}]"
+
 def member_type(field):
+    if field.is_map:
+        return map_type(field)
     if field.required:
         return field.python_type
     if field.optional and field.default_value is not None:
@@ -41,6 +48,9 @@ def member_type(field):
 % for field in message.fields:
         ${field.name}: "${member_type(field)}"
 % endfor
+% for oneof in message.oneofs:
+        ${oneof}: "str | None"
+% endfor
 
         def __init__(
             self,
@@ -51,6 +61,9 @@ def member_type(field):
 % for field in repeated_fields:
             ${field.name}: "list[${field.python_type}] | None" = None,
 % endfor
+% for field in map_fields:
+            ${field.name}: "${map_type(field)} | None" = None,
+% endfor
 % for field in optional_fields:
             ${field.name}: "${field.python_type} | None" = None,
 % endfor
@@ -8,19 +8,26 @@ encoded_length = trezorproto.encoded_length
 type_for_name = trezorproto.type_for_name
 type_for_wire = trezorproto.type_for_wire
 
+if __debug__:
+    dump = trezorproto.dump
+
 if TYPE_CHECKING:
     # XXX
     # Note that MessageType "subclasses" are not true subclasses, but instead instances
     # of the built-in metaclass MsgDef. MessageType instances are in fact instances of
     # the built-in type Msg. That is why isinstance checks do not work, and instead the
     # MessageTypeSubclass.is_type_of() method must be used.
-    from typing import TypeGuard, TypeVar
+    from typing import Any, TypeGuard, TypeVar
 
     T = TypeVar("T", bound="MessageType")
 
     class MessageType:
         MESSAGE_NAME: str = "MessageType"
         MESSAGE_WIRE_TYPE: int | None = None
+        # Descriptions of the fields, dicts with the keys `name`, `tag`, `type`,
+        # `required`, `repeated`, `experimental`, `packed`, `enum_values`,
+        # `msg_type`, `default` and `oneof`. Only available in debug builds.
+        MESSAGE_FIELDS: list[dict[str, Any]] = []
 
         @classmethod
         def is_type_of(cls: type[T], msg: "MessageType") -> TypeGuard[T]:
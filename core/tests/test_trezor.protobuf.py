@@ -1,7 +1,7 @@
 from common import *
 
 from trezor import protobuf
-from trezor.messages import WebAuthnCredential, Failure, SignMessage, DebugLinkMemoryRead
+from trezor.messages import WebAuthnCredential, WebAuthnCredentials, Failure, SignMessage, DebugLinkMemoryRead, GetPublicKey
 
 
 def load_uvarint32(data: bytes) -> int:
@@ -109,6 +109,178 @@ class TestProtobuf(unittest.TestCase):
         self.assertEqual(nmsg.message, b"hello")
         self.assertEqual(nmsg.coin_name, "Bitcoin")
 
+    def test_skip_unknown_fixed(self):
+        # field number 15 is unknown to WebAuthnCredential
+        fixed64 = bytes([(15 << 3) | 1]) + b"\x01\x02\x03\x04\x05\x06\x07\x08"
+        fixed32 = bytes([(15 << 3) | 5]) + b"\x01\x02\x03\x04"
+        index = bytes([(1 << 3) | 0, 42])
+
+        msg = load_message(WebAuthnCredential, fixed64 + index + fixed32)
+        self.assertEqual(msg.index, 42)
+
+        # truncated fixed-width value
+        with self.assertRaises(ValueError):
+            load_message(WebAuthnCredential, index + fixed32[:-1])
+
+    def test_packed_repeated(self):
+        # address_n = [1, 2] packed, followed by address_n = 3 unpacked
+        packed = b"\x0a\x02\x01\x02"
+        unpacked = b"\x08\x03"
+        msg = load_message(GetPublicKey, packed + unpacked)
+        self.assertEqual(msg.address_n, [1, 2, 3])
+
+        # empty packed record
+        msg = load_message(GetPublicKey, b"\x0a\x00")
+        self.assertEqual(msg.address_n, [])
+
+        # packed record truncated in the middle of a varint
+        with self.assertRaises(ValueError):
+            load_message(GetPublicKey, b"\x0a\x01\x81")
+
+    def test_repeated_limit(self):
+        # 1024 values are still accepted
+        packed = b"\x0a\x80\x08" + b"\x01" * 1024
+        msg = protobuf.decode(packed, GetPublicKey, False, limits=True)
+        self.assertEqual(len(msg.address_n), 1024)
+
+        # one more is rejected
+        with self.assertRaises(ValueError):
+            protobuf.decode(packed + b"\x08\x01", GetPublicKey, False, limits=True)
+
+        # but only if the limits are enabled
+        msg = load_message(GetPublicKey, packed + b"\x08\x01")
+        self.assertEqual(len(msg.address_n), 1025)
+
+    def test_alloc_limit(self):
+        # 40 KiB message does not fit into the allocation limit
+        msg_encoded = dump_message(SignMessage(message=bytes(40 * 1024)))
+        with self.assertRaises(ValueError):
+            protobuf.decode(msg_encoded, SignMessage, False, limits=True)
+
+        msg = load_message(SignMessage, msg_encoded)
+        self.assertEqual(len(msg.message), 40 * 1024)
+
+    def test_error_path(self):
+        msg_encoded = dump_message(Failure(code=1000))
+        try:
+            load_message(Failure, msg_encoded)
+        except ValueError as e:
+            self.assertEqual(e.args[-1], "Failure.code")
+        else:
+            self.fail("ValueError not raised")
+
+        msg_encoded = dump_message(SignMessage(message=None))
+        try:
+            load_message(SignMessage, msg_encoded)
+        except ValueError as e:
+            # the field name is kept, the path comes last
+            self.assertEqual(e.args[1:], ("message", "SignMessage.message"))
+        else:
+            self.fail("ValueError not raised")
+
+        # index of the offending element is included
+        try:
+            load_message(GetPublicKey, b"\x08\x01\x08\x02\x08\x80")
+        except ValueError as e:
+            self.assertEqual(e.args[-1], "GetPublicKey.address_n[2]")
+        else:
+            self.fail("ValueError not raised")
+
+    def test_preserve_unknown(self):
+        # field number 15 is unknown to WebAuthnCredential
+        unknown_varint = b"\x78\x2a"
+        unknown_bytes = b"\x7a\x03abc"
+        index = b"\x08\x05"
+        buffer = unknown_varint + index + unknown_bytes
+
+        # unknown fields are dropped by default
+        msg = protobuf.decode(buffer, WebAuthnCredential, False)
+        self.assertEqual(dump_message(msg), index)
+
+        # known fields first, then unknown fields in their original order
+        msg = protobuf.decode(buffer, WebAuthnCredential, False, preserve_unknown=True)
+        self.assertEqual(msg.index, 5)
+        self.assertEqual(dump_message(msg), index + unknown_varint + unknown_bytes)
+
+        # modified message keeps the unknown fields
+        msg.index = 6
+        self.assertEqual(dump_message(msg), b"\x08\x06" + unknown_varint + unknown_bytes)
+
+    def test_strict(self):
+        index = b"\x08\x05"
+        non_minimal = b"\x08\x85\x00"
+        duplicate = b"\x08\x05\x08\x06"
+        truncated_key = b"\x08\x05\x80"
+
+        # lenient by default
+        for buffer in (index, non_minimal, duplicate, truncated_key):
+            msg = protobuf.decode(buffer, WebAuthnCredential, False)
+            self.assertEqual(msg.index, 6 if buffer is duplicate else 5)
+
+        msg = protobuf.decode(index, WebAuthnCredential, False, strict=True)
+        self.assertEqual(msg.index, 5)
+
+        with self.assertRaises(ValueError):
+            protobuf.decode(non_minimal, WebAuthnCredential, False, strict=True)
+        with self.assertRaises(ValueError):
+            protobuf.decode(b"\x88\x00\x05", WebAuthnCredential, False, strict=True)
+        with self.assertRaises(ValueError):
+            protobuf.decode(duplicate, WebAuthnCredential, False, strict=True)
+        with self.assertRaises(ValueError):
+            protobuf.decode(truncated_key, WebAuthnCredential, False, strict=True)
+
+        # repeated fields may occur more than once
+        msg = protobuf.decode(b"\x08\x01\x08\x02", GetPublicKey, False, strict=True)
+        self.assertEqual(msg.address_n, [1, 2])
+
+    def test_zero_copy(self):
+        buffer = b"\x12\x03abc\x08\x05"
+
+        msg = protobuf.decode(buffer, WebAuthnCredential, False)
+        self.assertEqual(msg.id, b"abc")
+        self.assertIsInstance(msg.id, bytes)
+
+        msg = protobuf.decode(buffer, WebAuthnCredential, False, zero_copy=True)
+        self.assertIsInstance(msg.id, memoryview)
+        self.assertEqual(bytes(msg.id), b"abc")
+        self.assertEqual(msg.index, 5)
+        with self.assertRaises(TypeError):
+            msg.id[0] = 0
+
+        # re-encoding reads through the view
+        self.assertEqual(dump_message(msg), b"\x08\x05\x12\x03abc")
+
+    def test_message_fields(self):
+        fields = SignMessage.MESSAGE_FIELDS
+        self.assertEqual(
+            [f["name"] for f in fields],
+            ["address_n", "message", "coin_name", "script_type", "no_script_type"],
+        )
+        address_n, message, coin_name, script_type, no_script_type = fields
+
+        self.assertEqual(address_n["tag"], 1)
+        self.assertEqual(address_n["type"], "uvarint")
+        self.assertTrue(address_n["repeated"])
+        self.assertFalse(address_n["required"])
+
+        self.assertEqual(message["type"], "bytes")
+        self.assertTrue(message["required"])
+        self.assertIsNone(message["default"])
+
+        self.assertEqual(coin_name["type"], "string")
+        self.assertEqual(coin_name["default"], "Bitcoin")
+
+        self.assertEqual(script_type["type"], "enum")
+        self.assertIn(0, script_type["enum_values"])
+        self.assertEqual(script_type["default"], 0)
+
+        self.assertEqual(no_script_type["type"], "bool")
+        self.assertIsNone(no_script_type["enum_values"])
+        self.assertFalse(no_script_type["experimental"])
+
+        (credentials,) = WebAuthnCredentials.MESSAGE_FIELDS
+        self.assertEqual(credentials["type"], "message")
+        self.assertEqual(credentials["msg_type"].MESSAGE_NAME, "WebAuthnCredential")
 
 
 if __name__ == "__main__":
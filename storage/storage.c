@@ -1381,6 +1381,38 @@ secbool storage_next_counter(const uint16_t key, uint32_t *count) {
   }
 }
 
+/*
+ * Finds the next value in the app namespace app_id, in the order in which the
+ * values are stored in flash. Set *offset to zero to find the first value. The
+ * private values are skipped while the storage is locked, and their length is
+ * reported without the encryption overhead. Nothing is decrypted. The storage
+ * must not be modified between the calls.
+ */
+secbool storage_next_entry(const uint8_t app_id, uint32_t *offset,
+                           uint16_t *key, uint16_t *len) {
+  // APP == 0 is reserved for PIN related values
+  if (sectrue != initialized || app_id == APP_STORAGE || app_id > MAX_APPID) {
+    return secfalse;
+  }
+
+  const void *val = NULL;
+  while (sectrue == norcow_get_next(offset, key, &val, len)) {
+    const uint8_t app = *key >> 8;
+    if ((app & MAX_APPID) != app_id) {
+      continue;
+    }
+    if ((app & FLAG_PUBLIC) == 0) {
+      if (sectrue != unlocked ||
+          *len < CHACHA20_IV_SIZE + POLY1305_TAG_SIZE) {
+        continue;
+      }
+      *len -= CHACHA20_IV_SIZE + POLY1305_TAG_SIZE;
+    }
+    return sectrue;
+  }
+  return secfalse;
+}
+
 secbool storage_has_pin(void) {
   if (sectrue != initialized) {
     return secfalse;
@@ -71,5 +71,7 @@ secbool storage_set(const uint16_t key, const void *val, const uint16_t len);
 secbool storage_delete(const uint16_t key);
 secbool storage_set_counter(const uint16_t key, const uint32_t count);
 secbool storage_next_counter(const uint16_t key, uint32_t *count);
+secbool storage_next_entry(const uint8_t app_id, uint32_t *offset,
+                           uint16_t *key, uint16_t *len);
 
 #endif